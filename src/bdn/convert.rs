@@ -0,0 +1,600 @@
+//! Conversion between PGS display sets and BDN events.
+//!
+//! Every composition showing objects becomes an event lasting until the next display set,
+//! with one PNG per composition object. Every event becomes an epoch of its own,
+//! with a palette of at most 255 colors and a transparent entry 0.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::Path;
+
+use image::RgbaImage;
+use rayon::prelude::*;
+
+use super::{bdn_error, Bdn, Event, FrameRate, Graphic, VideoFormat};
+use crate::color::Matrix;
+use crate::pgs::{
+    CompositionObject, CompositionState, Crop, DisplaySet, Object, PaletteDefinition, PaletteEntry,
+    PgsStream, PresentationComposition, Segment, SegmentData, Window, WindowDefinition,
+};
+use crate::Result;
+
+/// Duration of a last composition that is never cleared
+const LAST_EVENT_DURATION: u32 = 5 * 90_000;
+/// PCS frame rate written by the Blu-ray authoring tools, whatever the video frame rate
+const PCS_FRAME_RATE: u8 = 0x10;
+/// PGS compositions show at most two objects, each in its own window
+const MAX_GRAPHICS: usize = 2;
+const TRANSPARENT: u8 = 0;
+const MAX_COLORS: usize = 255;
+
+/// Object displayed by a composition, with the palette active at the time
+struct Shown {
+    object: Object,
+    crop: Option<Crop>,
+    palette: [[u8; 4]; 256],
+    x: u16,
+    y: u16,
+}
+
+impl Bdn {
+    /// Renders the compositions of a stream to PNGs next to the XML `path`,
+    /// named `{stem}_0001.png` and so on.
+    /// The XML itself is not written.
+    pub fn from_stream<P: AsRef<Path>>(
+        stream: &PgsStream,
+        path: P,
+        frame_rate: FrameRate,
+        matrix: Matrix,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let stem = path
+            .file_stem()
+            .map(OsStr::to_string_lossy)
+            .unwrap_or_default();
+
+        let video_format = match stream.display_sets.first() {
+            Some(ds) => {
+                let pcs = ds.composition();
+                VideoFormat::from_size(pcs.width, pcs.height).ok_or_else(|| {
+                    bdn_error(format!(
+                        "Unsupported video size {}x{}",
+                        pcs.width, pcs.height
+                    ))
+                })?
+            }
+            None => VideoFormat::default(),
+        };
+
+        // Objects and palettes of the current epoch
+        let mut objects: HashMap<u16, Object> = HashMap::new();
+        let mut palettes: HashMap<u8, [[u8; 4]; 256]> = HashMap::new();
+        let mut shown: Vec<(u32, u32, bool, Vec<Shown>)> = Vec::new();
+
+        for (i, ds) in stream.display_sets.iter().enumerate() {
+            if ds.composition().composition_state == CompositionState::EpochStart {
+                objects.clear();
+                palettes.clear();
+            }
+
+            for object in ds.complete_objects()? {
+                objects.insert(object.id, object);
+            }
+            for pds in ds.palettes() {
+                let palette = palettes.entry(pds.id).or_insert([[0; 4]; 256]);
+                for entry in &pds.entries {
+                    let [r, g, b] = entry.to_rgb(matrix).map(|c| c.round() as u8);
+                    palette[entry.id as usize] = [r, g, b, entry.alpha];
+                }
+            }
+
+            let pcs = ds.composition();
+            let in_time = ds.pts();
+            let out_time = stream
+                .display_sets
+                .get(i + 1)
+                .map_or(in_time.saturating_add(LAST_EVENT_DURATION), DisplaySet::pts);
+
+            // Frames are the smallest unit of BDN
+            if pcs.objects.is_empty()
+                || frame_rate.to_frames(in_time) >= frame_rate.to_frames(out_time)
+            {
+                continue;
+            }
+
+            let palette = palettes
+                .get(&pcs.palette_id)
+                .copied()
+                .unwrap_or([[0; 4]; 256]);
+            let graphics = pcs
+                .objects
+                .iter()
+                .map(|obj| {
+                    let object = objects.get(&obj.object_id).ok_or_else(|| {
+                        bdn_error(format!(
+                            "Object {} is not defined in its epoch",
+                            obj.object_id
+                        ))
+                    })?;
+
+                    Ok(Shown {
+                        object: object.clone(),
+                        crop: obj.crop,
+                        palette,
+                        x: obj.x,
+                        y: obj.y,
+                    })
+                })
+                .collect::<Result<_>>()?;
+
+            shown.push((in_time, out_time, ds.is_forced(), graphics));
+        }
+
+        let mut count = 0;
+        let mut events = Vec::with_capacity(shown.len());
+        let mut images = Vec::new();
+
+        for (in_time, out_time, forced, graphics) in shown {
+            let mut event = Event {
+                in_time,
+                out_time,
+                forced,
+                graphics: Vec::with_capacity(graphics.len()),
+            };
+
+            for shown in graphics {
+                count += 1;
+                let file = format!("{stem}_{count:04}.png");
+
+                event.graphics.push(Graphic {
+                    x: shown.x,
+                    y: shown.y,
+                    width: shown.crop.map_or(shown.object.width, |c| c.width),
+                    height: shown.crop.map_or(shown.object.height, |c| c.height),
+                    file: file.clone(),
+                });
+                images.push((shown, path.with_file_name(file)));
+            }
+
+            events.push(event);
+        }
+
+        images
+            .par_iter()
+            .try_for_each(|(shown, file)| -> Result<()> {
+                render(shown)?.save(file)?;
+                Ok(())
+            })?;
+
+        Ok(Self {
+            title: stem.into_owned(),
+            language: "und".to_owned(),
+            video_format,
+            frame_rate,
+            drop_frame: false,
+            events,
+        })
+    }
+
+    /// Builds a stream from the events and their PNGs, relative to `dir`.
+    /// Events without graphics are ignored, and the DTS are 0.
+    pub fn to_stream<P: AsRef<Path>>(&self, dir: P, matrix: Matrix) -> Result<PgsStream> {
+        let dir = dir.as_ref();
+        let (width, height) = self.video_format.size();
+
+        let events: Vec<&Event> = self
+            .events
+            .iter()
+            .filter(|e| !e.graphics.is_empty())
+            .collect();
+
+        for (event, next) in events.iter().zip(events.iter().skip(1)) {
+            if next.in_time < event.in_time {
+                return Err(bdn_error(format!(
+                    "Event `{}` starts before the previous one",
+                    next.graphics[0].file
+                )));
+            }
+        }
+
+        let epochs = events
+            .par_iter()
+            .map(|event| epoch_start(event, dir, width, height, matrix))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut display_sets = Vec::with_capacity(epochs.len() * 2);
+        for (i, (event, mut ds)) in events.iter().zip(epochs).enumerate() {
+            if event.out_time <= event.in_time {
+                return Err(bdn_error(format!(
+                    "Event `{}` ends before it starts",
+                    event.graphics[0].file
+                )));
+            }
+
+            ds.composition_mut().composition_number = display_sets.len() as u16;
+            let windows = ds.windows().next().cloned();
+            display_sets.push(ds);
+
+            // The next epoch start replaces the composition anyway
+            if events
+                .get(i + 1)
+                .is_some_and(|next| next.in_time <= event.out_time)
+            {
+                continue;
+            }
+
+            let pcs = PresentationComposition {
+                width,
+                height,
+                frame_rate: PCS_FRAME_RATE,
+                composition_number: display_sets.len() as u16,
+                composition_state: CompositionState::Normal,
+                palette_update: false,
+                palette_id: 0,
+                objects: Vec::new(),
+            };
+
+            let mut segments = vec![SegmentData::Pcs(pcs)];
+            segments.extend(windows.map(SegmentData::Wds));
+            segments.push(SegmentData::End);
+
+            display_sets.push(display_set(event.out_time, segments)?);
+        }
+
+        Ok(PgsStream { display_sets })
+    }
+}
+
+/// Display set showing the graphics of an event, starting an epoch
+fn epoch_start(
+    event: &Event,
+    dir: &Path,
+    width: u16,
+    height: u16,
+    matrix: Matrix,
+) -> Result<DisplaySet> {
+    if event.graphics.len() > MAX_GRAPHICS {
+        return Err(bdn_error(format!(
+            "Event `{}` has {} graphics, at most {MAX_GRAPHICS} are supported",
+            event.graphics[0].file,
+            event.graphics.len()
+        )));
+    }
+
+    let images = event
+        .graphics
+        .iter()
+        .map(|graphic| {
+            let image = image::open(dir.join(&graphic.file))?.to_rgba8();
+
+            if image.width() > u16::MAX as u32 || image.height() > u16::MAX as u32 {
+                return Err(bdn_error(format!(
+                    "Graphic `{}` is too large",
+                    graphic.file
+                )));
+            }
+
+            Ok(image)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let (colors, indices) = quantize(&images);
+
+    let mut windows = Vec::with_capacity(images.len());
+    let mut composition_objects = Vec::with_capacity(images.len());
+    let mut objects = Vec::with_capacity(images.len());
+
+    for (i, (graphic, image)) in event.graphics.iter().zip(&images).enumerate() {
+        let (w, h) = (image.width() as u16, image.height() as u16);
+        let bitmap: Vec<u8> = image.pixels().map(|p| indices[&p.0]).collect();
+
+        windows.push(Window {
+            id: i as u8,
+            x: graphic.x,
+            y: graphic.y,
+            width: w,
+            height: h,
+        });
+        composition_objects.push(CompositionObject {
+            object_id: i as u16,
+            window_id: i as u8,
+            forced: event.forced,
+            x: graphic.x,
+            y: graphic.y,
+            crop: None,
+        });
+        objects.push(Object::from_bitmap(i as u16, 0, w, h, &bitmap));
+    }
+
+    let mut entries = vec![PaletteEntry {
+        id: TRANSPARENT,
+        y: 16,
+        cr: 128,
+        cb: 128,
+        alpha: 0,
+    }];
+    entries.extend(colors.iter().enumerate().map(|(i, &[r, g, b, alpha])| {
+        let mut entry = PaletteEntry {
+            id: i as u8 + 1,
+            y: 16,
+            cr: 128,
+            cb: 128,
+            alpha,
+        };
+        entry.set_rgb([r, g, b].map(f32::from), matrix);
+        entry
+    }));
+
+    let pcs = PresentationComposition {
+        width,
+        height,
+        frame_rate: PCS_FRAME_RATE,
+        composition_number: 0,
+        composition_state: CompositionState::EpochStart,
+        palette_update: false,
+        palette_id: 0,
+        objects: composition_objects,
+    };
+
+    let mut segments = vec![
+        SegmentData::Pcs(pcs),
+        SegmentData::Wds(WindowDefinition { windows }),
+        SegmentData::Pds(PaletteDefinition {
+            id: 0,
+            version: 0,
+            entries,
+        }),
+    ];
+    segments.extend(
+        objects
+            .iter()
+            .flat_map(Object::fragments)
+            .map(SegmentData::Ods),
+    );
+    segments.push(SegmentData::End);
+
+    display_set(event.in_time, segments)
+}
+
+fn display_set(pts: u32, segments: Vec<SegmentData>) -> Result<DisplaySet> {
+    DisplaySet::new(
+        segments
+            .into_iter()
+            .map(|data| Segment { pts, dts: 0, data })
+            .collect(),
+    )
+}
+
+/// Palette of the most used visible colors, and the palette index of every color.
+/// The other colors use the closest palette color, transparent pixels use entry 0.
+fn quantize(images: &[RgbaImage]) -> (Vec<[u8; 4]>, HashMap<[u8; 4], u8>) {
+    let mut counts: HashMap<[u8; 4], u32> = HashMap::new();
+    for image::Rgba(data) in images.iter().flat_map(|image| image.pixels()) {
+        *counts.entry(*data).or_default() += 1;
+    }
+
+    let mut colors: Vec<([u8; 4], u32)> = counts.into_iter().collect();
+    colors.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let palette: Vec<[u8; 4]> = colors
+        .iter()
+        .map(|(color, _)| *color)
+        .filter(|color| color[3] > 0)
+        .take(MAX_COLORS)
+        .collect();
+
+    let indices = colors
+        .iter()
+        .map(|&(color, _)| {
+            if color[3] == 0 {
+                return (color, TRANSPARENT);
+            }
+
+            let closest = palette
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, p)| {
+                    p.iter()
+                        .zip(color)
+                        .map(|(&a, b)| (a as i32 - b as i32).pow(2))
+                        .sum::<i32>()
+                })
+                .map_or(0, |(i, _)| i);
+
+            (color, closest as u8 + 1)
+        })
+        .collect();
+
+    (palette, indices)
+}
+
+/// Decodes a displayed object to RGBA, cropped
+fn render(shown: &Shown) -> Result<RgbaImage> {
+    let object = &shown.object;
+    let bitmap = object.decode()?;
+
+    let crop = shown.crop.unwrap_or(Crop {
+        x: 0,
+        y: 0,
+        width: object.width,
+        height: object.height,
+    });
+
+    if crop.x as u32 + crop.width as u32 > object.width as u32
+        || crop.y as u32 + crop.height as u32 > object.height as u32
+    {
+        return Err(bdn_error(format!(
+            "Crop of object {} is outside of the object",
+            object.id
+        )));
+    }
+
+    Ok(RgbaImage::from_fn(
+        crop.width as u32,
+        crop.height as u32,
+        |x, y| {
+            let index = (crop.y as usize + y as usize) * object.width as usize
+                + crop.x as usize
+                + x as usize;
+            image::Rgba(shown.palette[bitmap[index] as usize])
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+    use crate::bdn::FrameRate;
+    use crate::pgs::samples::{sample_stream, small_stream};
+    use crate::workspace::Workspace;
+
+    /// Colors of the pixels of the object shown by a display set, transparent pixels are 0
+    fn pixels(ds: &DisplaySet) -> Vec<[u8; 4]> {
+        let entries = &ds.palettes().next().unwrap().entries;
+        let object = &ds.complete_objects().unwrap()[0];
+
+        object
+            .decode()
+            .unwrap()
+            .into_iter()
+            .map(|index| {
+                let entry = entries.iter().find(|e| e.id == index).unwrap();
+                if entry.alpha == 0 {
+                    [0; 4]
+                } else {
+                    [entry.y, entry.cr, entry.cb, entry.alpha]
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        let workspace = Workspace::new(None, false).unwrap();
+        let path = workspace.path().join("sub.xml");
+
+        // Colors outside of the RGB gamut are clipped
+        let mut stream = small_stream();
+        for ds in &mut stream.display_sets {
+            for pds in ds.palettes_mut() {
+                pds.entries[2] = PaletteEntry {
+                    y: 104,
+                    cr: 150,
+                    cb: 110,
+                    ..pds.entries[2]
+                };
+            }
+        }
+
+        let bdn = Bdn::from_stream(&stream, &path, FrameRate::Fps25, Matrix::Bt709).unwrap();
+        assert_eq!(bdn.video_format, VideoFormat::P1080);
+        assert_eq!(bdn.events.len(), 2);
+        assert!(bdn.events[0].forced && !bdn.events[1].forced);
+        assert_eq!(
+            (bdn.events[1].in_time, bdn.events[1].out_time),
+            (270_000, 360_000)
+        );
+        assert_eq!(bdn.events[1].graphics[0].file, "sub_0002.png");
+        assert!(workspace.path().join("sub_0002.png").is_file());
+
+        bdn.write_to_path(&path).unwrap();
+        let bdn = Bdn::from_path(&path).unwrap();
+        let result = bdn.to_stream(workspace.path(), Matrix::Bt709).unwrap();
+
+        assert_eq!(result.display_sets.len(), stream.display_sets.len());
+        for (ds, expected) in result.display_sets.iter().zip(&stream.display_sets) {
+            assert_eq!(ds.pts(), expected.pts());
+            assert!(ds.segments.iter().all(|s| s.dts == 0));
+            assert_eq!(ds.is_forced(), expected.is_forced());
+            assert_eq!(
+                ds.composition().composition_state,
+                expected.composition().composition_state
+            );
+
+            if expected.composition().objects.is_empty() {
+                assert!(ds.composition().objects.is_empty());
+                continue;
+            }
+
+            // The colors only go through 8 bits RGB
+            for (color, expected) in pixels(ds).into_iter().zip(pixels(expected)) {
+                let close = color.iter().zip(expected).all(|(&a, b)| a.abs_diff(b) <= 1);
+                assert!(close, "{color:?} instead of {expected:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn cropped_objects_are_rendered_cropped() {
+        let workspace = Workspace::new(None, false).unwrap();
+        let path = workspace.path().join("sub.xml");
+
+        let bdn =
+            Bdn::from_stream(&sample_stream(), &path, FrameRate::Fps24, Matrix::Bt709).unwrap();
+        let graphic = &bdn.events[0].graphics[0];
+        assert_eq!(
+            (graphic.x, graphic.y, graphic.width, graphic.height),
+            (460, 800, 1000, 100)
+        );
+
+        let image = image::open(workspace.path().join(&graphic.file))
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(image.dimensions(), (1000, 100));
+        // Entry `id` has an alpha of `id`
+        assert_eq!(image.get_pixel(1, 0)[3], 7);
+    }
+
+    #[test]
+    fn colors_are_quantized_to_255_entries() {
+        let workspace = Workspace::new(None, false).unwrap();
+
+        // Every red level twice, then rarer colors next to them, then transparent pixels
+        let mut colors: Vec<[u8; 4]> = (0..=254).flat_map(|r| [[r, 0, 0, 255]; 2]).collect();
+        colors.extend((0..45).map(|r| [r * 5, 1, 0, 255]));
+        colors.extend([[0, 0, 0, 0], [90, 90, 90, 0]]);
+
+        let image = RgbaImage::from_fn(colors.len() as u32, 1, |x, _| Rgba(colors[x as usize]));
+        image.save(workspace.path().join("colors.png")).unwrap();
+
+        let bdn = Bdn {
+            title: String::new(),
+            language: "und".to_owned(),
+            video_format: VideoFormat::P1080,
+            frame_rate: FrameRate::Fps24,
+            drop_frame: false,
+            events: vec![Event {
+                in_time: 90_000,
+                out_time: 180_000,
+                forced: false,
+                graphics: vec![Graphic {
+                    x: 0,
+                    y: 0,
+                    width: image.width() as u16,
+                    height: 1,
+                    file: "colors.png".to_owned(),
+                }],
+            }],
+        };
+        let stream = bdn.to_stream(workspace.path(), Matrix::Bt709).unwrap();
+        let ds = &stream.display_sets[0];
+
+        let entries = &ds.palettes().next().unwrap().entries;
+        assert_eq!(entries.len(), 256);
+        assert_eq!(entries[0].alpha, 0);
+        assert!(entries[1..].iter().all(|e| e.alpha == 255));
+
+        let bitmap = ds.complete_objects().unwrap()[0].decode().unwrap();
+        for (&index, color) in bitmap.iter().zip(&colors) {
+            let expected = match color {
+                [_, _, _, 0] => TRANSPARENT,
+                // The most used colors come first, then the lowest,
+                // and the others use the closest red level
+                [r, ..] => r + 1,
+            };
+            assert_eq!(index, expected, "{color:?}");
+        }
+    }
+}
@@ -0,0 +1,603 @@
+//! BDN XML (`.xml` + `.png`), the subtitle interchange format of Blu-ray authoring tools.
+//!
+//! Every event is a timecode range with one or two PNG graphics at a position on the video.
+//! The timecodes count frames at the nominal rate of the frame rate, 24 for 23.976,
+//! and use drop frame counting for 29.97 and 59.94 when `DropFrame` is set.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::Result;
+
+mod convert;
+
+pub const EXTENSION: &str = "xml";
+
+const BDN_VERSION: &str = "0.93";
+const SCHEMA_LOCATION: &str = "BD-03-006-0093b BDN File Format.xsd";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VideoFormat {
+    P2160,
+    #[default]
+    P1080,
+    I1080,
+    P720,
+    I576,
+    I480,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FrameRate {
+    #[default]
+    Fps23976,
+    Fps24,
+    Fps25,
+    Fps2997,
+    Fps50,
+    Fps5994,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bdn {
+    pub title: String,
+    /// ISO 639-2 code
+    pub language: String,
+    pub video_format: VideoFormat,
+    pub frame_rate: FrameRate,
+    /// Timecodes use drop frame counting, only for 29.97 and 59.94
+    pub drop_frame: bool,
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Start, 90 kHz
+    pub in_time: u32,
+    /// End, 90 kHz
+    pub out_time: u32,
+    pub forced: bool,
+    pub graphics: Vec<Graphic>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Graphic {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    /// PNG file, relative to the XML file
+    pub file: String,
+}
+
+impl VideoFormat {
+    pub fn size(&self) -> (u16, u16) {
+        match self {
+            Self::P2160 => (3840, 2160),
+            Self::P1080 | Self::I1080 => (1920, 1080),
+            Self::P720 => (1280, 720),
+            Self::I576 => (720, 576),
+            Self::I480 => (720, 480),
+        }
+    }
+
+    /// Progressive format of a video size, interlaced for SD
+    pub fn from_size(width: u16, height: u16) -> Option<Self> {
+        [Self::P2160, Self::P1080, Self::P720, Self::I576, Self::I480]
+            .into_iter()
+            .find(|format| format.size() == (width, height))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::P2160 => "2160p",
+            Self::P1080 => "1080p",
+            Self::I1080 => "1080i",
+            Self::P720 => "720p",
+            Self::I576 => "576i",
+            Self::I480 => "480i",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [
+            Self::P2160,
+            Self::P1080,
+            Self::I1080,
+            Self::P720,
+            Self::I576,
+            Self::I480,
+        ]
+        .into_iter()
+        .find(|format| format.as_str().eq_ignore_ascii_case(value))
+    }
+}
+
+impl FrameRate {
+    /// Frames per second, as a fraction
+    pub fn ratio(&self) -> (u64, u64) {
+        match self {
+            Self::Fps23976 => (24000, 1001),
+            Self::Fps24 => (24, 1),
+            Self::Fps25 => (25, 1),
+            Self::Fps2997 => (30000, 1001),
+            Self::Fps50 => (50, 1),
+            Self::Fps5994 => (60000, 1001),
+        }
+    }
+
+    /// Frames per timecode second
+    pub fn nominal(&self) -> u64 {
+        let (num, den) = self.ratio();
+        num.div_ceil(den)
+    }
+
+    pub fn supports_drop_frame(&self) -> bool {
+        matches!(self, Self::Fps2997 | Self::Fps5994)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fps23976 => "23.976",
+            Self::Fps24 => "24",
+            Self::Fps25 => "25",
+            Self::Fps2997 => "29.97",
+            Self::Fps50 => "50",
+            Self::Fps5994 => "59.94",
+        }
+    }
+
+    /// Parses a frame rate like `23.976`, trailing zeros are ignored
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let value = if value.contains('.') {
+            value.trim_end_matches('0').trim_end_matches('.')
+        } else {
+            value
+        };
+
+        [
+            Self::Fps23976,
+            Self::Fps24,
+            Self::Fps25,
+            Self::Fps2997,
+            Self::Fps50,
+            Self::Fps5994,
+        ]
+        .into_iter()
+        .find(|rate| rate.as_str() == value)
+    }
+
+    /// Rounds a 90 kHz timestamp to the nearest frame
+    pub fn to_frames(&self, time: u32) -> u64 {
+        let (num, den) = self.ratio();
+        (time as u64 * num + 45_000 * den) / (90_000 * den)
+    }
+
+    /// 90 kHz timestamp of a frame, rounded
+    pub fn to_time(&self, frames: u64) -> Result<u32> {
+        let (num, den) = self.ratio();
+        let time = (frames * 90_000 * den + num / 2) / num;
+
+        u32::try_from(time).map_err(|_| bdn_error(format!("Frame {frames} is out of range")))
+    }
+
+    /// Number of frames dropped from the count every minute, except every tenth minute
+    fn dropped_frames(&self, drop_frame: bool) -> u64 {
+        if drop_frame && self.supports_drop_frame() {
+            self.nominal() / 15
+        } else {
+            0
+        }
+    }
+
+    /// Parses a `HH:MM:SS:FF` timecode to a frame count
+    pub fn parse_timecode(&self, timecode: &str, drop_frame: bool) -> Result<u64> {
+        let invalid = || bdn_error(format!("Invalid timecode `{timecode}`"));
+
+        let parts = timecode
+            .split([':', ';'])
+            .map(|part| part.parse::<u64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>>>()?;
+        let &[hours, minutes, seconds, frames] = parts.as_slice() else {
+            return Err(invalid());
+        };
+
+        let nominal = self.nominal();
+        if minutes >= 60 || seconds >= 60 || frames >= nominal {
+            return Err(invalid());
+        }
+
+        let total_minutes = hours * 60 + minutes;
+        let dropped = self.dropped_frames(drop_frame) * (total_minutes - total_minutes / 10);
+
+        Ok((total_minutes * 60 + seconds) * nominal + frames - dropped)
+    }
+
+    /// Formats a frame count as a `HH:MM:SS:FF` timecode
+    pub fn format_timecode(&self, mut frames: u64, drop_frame: bool) -> String {
+        let nominal = self.nominal();
+        let dropped = self.dropped_frames(drop_frame);
+
+        if dropped > 0 {
+            let per_ten_minutes = nominal * 600 - dropped * 9;
+            let per_minute = nominal * 60 - dropped;
+
+            let tens = frames / per_ten_minutes;
+            let rest = frames % per_ten_minutes;
+
+            frames += dropped * 9 * tens;
+            if rest > dropped {
+                frames += dropped * ((rest - dropped) / per_minute);
+            }
+        }
+
+        let seconds = frames / nominal;
+        format!(
+            "{:02}:{:02}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            frames % nominal
+        )
+    }
+}
+
+impl Bdn {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(xml: &str) -> Result<Self> {
+        let document = roxmltree::Document::parse(xml).map_err(|e| bdn_error(e.to_string()))?;
+        let root = document.root_element();
+
+        if !root.has_tag_name("BDN") {
+            return Err(bdn_error(format!(
+                "Root element is `{}` instead of `BDN`",
+                root.tag_name().name()
+            )));
+        }
+
+        let description = child(root, "Description")?;
+        let format = child(description, "Format")?;
+
+        let video_format = attribute(format, "VideoFormat")?;
+        let video_format = VideoFormat::parse(video_format)
+            .ok_or_else(|| bdn_error(format!("Unsupported video format `{video_format}`")))?;
+        let frame_rate = attribute(format, "FrameRate")?;
+        let frame_rate = FrameRate::parse(frame_rate)
+            .ok_or_else(|| bdn_error(format!("Unsupported frame rate `{frame_rate}`")))?;
+        let drop_frame = format
+            .attribute("DropFrame")
+            .is_some_and(|value| value.eq_ignore_ascii_case("true"));
+
+        let title = description
+            .children()
+            .find(|n| n.has_tag_name("Name"))
+            .and_then(|n| n.attribute("Title"))
+            .unwrap_or_default()
+            .to_owned();
+        let language = description
+            .children()
+            .find(|n| n.has_tag_name("Language"))
+            .and_then(|n| n.attribute("Code"))
+            .unwrap_or("und")
+            .to_owned();
+
+        let time = |node: roxmltree::Node, name: &str| -> Result<u32> {
+            frame_rate.to_time(frame_rate.parse_timecode(attribute(node, name)?, drop_frame)?)
+        };
+
+        let events = child(root, "Events")?
+            .children()
+            .filter(|n| n.has_tag_name("Event"))
+            .map(|event| {
+                let graphics = event
+                    .children()
+                    .filter(|n| n.has_tag_name("Graphic"))
+                    .map(|graphic| {
+                        let file = graphic.text().map(str::trim).unwrap_or_default();
+                        if file.is_empty() {
+                            return Err(bdn_error("Graphic without a file name"));
+                        }
+
+                        Ok(Graphic {
+                            x: number(graphic, "X")?,
+                            y: number(graphic, "Y")?,
+                            width: number(graphic, "Width")?,
+                            height: number(graphic, "Height")?,
+                            file: file.to_owned(),
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+
+                Ok(Event {
+                    in_time: time(event, "InTC")?,
+                    out_time: time(event, "OutTC")?,
+                    forced: event
+                        .attribute("Forced")
+                        .is_some_and(|value| value.eq_ignore_ascii_case("true")),
+                    graphics,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            title,
+            language,
+            video_format,
+            frame_rate,
+            drop_frame,
+            events,
+        })
+    }
+
+    pub fn write_to_path<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Ok(fs::write(path, self.to_xml())?)
+    }
+
+    pub fn to_xml(&self) -> String {
+        let drop_frame = self.drop_frame && self.frame_rate.supports_drop_frame();
+        let timecode = |time: u32| {
+            self.frame_rate
+                .format_timecode(self.frame_rate.to_frames(time), drop_frame)
+        };
+        let flag = |value: bool| if value { "True" } else { "False" };
+
+        let first_in = self.events.first().map_or(0, |e| e.in_time);
+        let last_out = self.events.last().map_or(0, |e| e.out_time);
+
+        let mut xml = String::new();
+        let _ = writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        let _ = writeln!(
+            xml,
+            r#"<BDN Version="{BDN_VERSION}" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:noNamespaceSchemaLocation="{SCHEMA_LOCATION}">"#
+        );
+        let _ = writeln!(xml, "  <Description>");
+        let _ = writeln!(
+            xml,
+            r#"    <Name Title="{}" Content=""/>"#,
+            escape(&self.title)
+        );
+        let _ = writeln!(xml, r#"    <Language Code="{}"/>"#, escape(&self.language));
+        let _ = writeln!(
+            xml,
+            r#"    <Format VideoFormat="{}" FrameRate="{}" DropFrame="{}"/>"#,
+            self.video_format.as_str(),
+            self.frame_rate.as_str(),
+            flag(drop_frame)
+        );
+        let _ = writeln!(
+            xml,
+            r#"    <Events Type="Graphic" FirstEventInTC="{}" LastEventOutTC="{}" NumberofEvents="{}"/>"#,
+            timecode(first_in),
+            timecode(last_out),
+            self.events.len()
+        );
+        let _ = writeln!(xml, "  </Description>");
+        let _ = writeln!(xml, "  <Events>");
+
+        for event in &self.events {
+            let _ = writeln!(
+                xml,
+                r#"    <Event InTC="{}" OutTC="{}" Forced="{}">"#,
+                timecode(event.in_time),
+                timecode(event.out_time),
+                flag(event.forced)
+            );
+
+            for graphic in &event.graphics {
+                let _ = writeln!(
+                    xml,
+                    r#"      <Graphic Width="{}" Height="{}" X="{}" Y="{}">{}</Graphic>"#,
+                    graphic.width,
+                    graphic.height,
+                    graphic.x,
+                    graphic.y,
+                    escape(&graphic.file)
+                );
+            }
+
+            let _ = writeln!(xml, "    </Event>");
+        }
+
+        let _ = writeln!(xml, "  </Events>");
+        let _ = writeln!(xml, "</BDN>");
+
+        xml
+    }
+}
+
+fn child<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &str,
+) -> Result<roxmltree::Node<'a, 'input>> {
+    node.children()
+        .find(|n| n.has_tag_name(name))
+        .ok_or_else(|| bdn_error(format!("Missing `{name}` element")))
+}
+
+fn attribute<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Result<&'a str> {
+    node.attribute(name).ok_or_else(|| {
+        bdn_error(format!(
+            "`{}` is missing its `{name}` attribute",
+            node.tag_name().name()
+        ))
+    })
+}
+
+fn number(node: roxmltree::Node, name: &str) -> Result<u16> {
+    let value = attribute(node, name)?;
+
+    value.trim().parse().map_err(|_| {
+        bdn_error(format!(
+            "Invalid `{name}` of `{}`: `{value}`",
+            node.tag_name().name()
+        ))
+    })
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Whether the path has the BDN XML extension
+pub fn is_bdn(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(EXTENSION))
+}
+
+/// Whether the file is an XML document with a `BDN` root element
+pub fn is_bdn_document(path: &Path) -> bool {
+    fs::read_to_string(path).is_ok_and(|xml| {
+        roxmltree::Document::parse(&xml).is_ok_and(|doc| doc.root_element().has_tag_name("BDN"))
+    })
+}
+
+pub(crate) fn bdn_error<S: Into<String>>(message: S) -> Error {
+    Error::Bdn(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<BDN Version="0.93">
+  <Description>
+    <Name Title="Movie &amp; Co" Content=""/>
+    <Language Code="fra"/>
+    <Format VideoFormat="1080i" FrameRate="29.970" DropFrame="True"/>
+    <Events Type="Graphic" FirstEventInTC="00:00:59;29" LastEventOutTC="00:10:00;00" NumberofEvents="2"/>
+  </Description>
+  <Events>
+    <Event InTC="00:00:59;29" OutTC="00:01:00;02" Forced="True">
+      <Graphic Width="100" Height="20" X="910" Y="900">sub_0001.png</Graphic>
+      <Graphic Width="50" Height="20" X="935" Y="950"> sub_0002.png </Graphic>
+    </Event>
+    <Event InTC="00:09:59;29" OutTC="00:10:00;00">
+      <Graphic Width="100" Height="20" X="910" Y="900">sub_0003.png</Graphic>
+    </Event>
+  </Events>
+</BDN>
+"#;
+
+    #[test]
+    fn timecodes() {
+        let rate = FrameRate::Fps25;
+        assert_eq!(rate.parse_timecode("01:02:03:04", false).unwrap(), 93_079);
+        assert_eq!(rate.format_timecode(93_079, false), "01:02:03:04");
+        assert_eq!(rate.to_time(93_079).unwrap(), 335_084_400);
+
+        // 23.976 counts 24 frames per timecode second
+        let rate = FrameRate::Fps23976;
+        assert_eq!(rate.parse_timecode("00:00:01:00", false).unwrap(), 24);
+        assert_eq!(rate.to_time(24).unwrap(), 90_090);
+        assert_eq!(rate.to_frames(90_090), 24);
+        assert_eq!(rate.format_timecode(24, false), "00:00:01:00");
+
+        // Drop frame counting is ignored without drop frame rates
+        assert_eq!(rate.parse_timecode("00:01:00:00", true).unwrap(), 1440);
+
+        for timecode in ["00:00:01", "00:60:00:00", "00:00:00:24", "00:00:aa:00", ""] {
+            assert!(rate.parse_timecode(timecode, false).is_err(), "{timecode}");
+        }
+    }
+
+    #[test]
+    fn drop_frame_timecodes() {
+        let rate = FrameRate::Fps2997;
+
+        for (timecode, frames) in [
+            ("00:00:59;29", 1799),
+            ("00:01:00;02", 1800),
+            ("00:01:59;29", 3597),
+            ("00:02:00;02", 3598),
+            // Every tenth minute keeps its first frames
+            ("00:09:59;29", 17_981),
+            ("00:10:00;00", 17_982),
+            ("00:11:00;02", 19_782),
+            ("01:00:00;00", 107_892),
+        ] {
+            assert_eq!(rate.parse_timecode(timecode, true).unwrap(), frames);
+            assert_eq!(
+                rate.format_timecode(frames, true),
+                timecode.replace(';', ":")
+            );
+        }
+
+        // Without drop frame, every timecode second has 30 frames
+        assert_eq!(rate.parse_timecode("00:01:00:02", false).unwrap(), 1802);
+        assert_eq!(rate.format_timecode(1800, false), "00:01:00:00");
+
+        let rate = FrameRate::Fps5994;
+        assert_eq!(rate.parse_timecode("00:00:59;59", true).unwrap(), 3599);
+        assert_eq!(rate.format_timecode(3600, true), "00:01:00:04");
+        assert_eq!(rate.format_timecode(35_964, true), "00:10:00:00");
+    }
+
+    #[test]
+    fn parse_document() {
+        let bdn = Bdn::parse(SAMPLE).unwrap();
+        let rate = FrameRate::Fps2997;
+
+        assert_eq!(bdn.title, "Movie & Co");
+        assert_eq!(bdn.language, "fra");
+        assert_eq!(bdn.video_format, VideoFormat::I1080);
+        assert_eq!(bdn.frame_rate, rate);
+        assert!(bdn.drop_frame);
+
+        assert_eq!(bdn.events.len(), 2);
+        let event = &bdn.events[0];
+        assert_eq!(event.in_time, rate.to_time(1799).unwrap());
+        assert_eq!(event.out_time, rate.to_time(1800).unwrap());
+        assert!(event.forced);
+        assert_eq!(
+            event.graphics[1],
+            Graphic {
+                x: 935,
+                y: 950,
+                width: 50,
+                height: 20,
+                file: "sub_0002.png".to_owned(),
+            }
+        );
+        assert!(!bdn.events[1].forced);
+        assert_eq!(bdn.events[1].out_time, rate.to_time(17_982).unwrap());
+
+        // The written document is read back the same
+        assert_eq!(Bdn::parse(&bdn.to_xml()).unwrap(), bdn);
+    }
+
+    #[test]
+    fn invalid_documents() {
+        for (from, to) in [
+            ("<BDN Version=\"0.93\">", "<BDMV>"),
+            ("FrameRate=\"29.970\"", "FrameRate=\"30\""),
+            ("VideoFormat=\"1080i\"", "VideoFormat=\"1440p\""),
+            ("InTC=\"00:09:59;29\"", "InTC=\"00:09:59\""),
+            ("X=\"910\"", "X=\"-1\""),
+            (">sub_0003.png<", "> <"),
+            ("<Events>", "<Subtitles>"),
+        ] {
+            let xml = SAMPLE.replacen(from, to, 1);
+            let xml = if to == "<BDMV>" {
+                xml.replace("</BDN>", "</BDMV>")
+            } else if to == "<Subtitles>" {
+                xml.replace("  </Events>", "  </Subtitles>")
+            } else {
+                xml
+            };
+
+            assert!(matches!(Bdn::parse(&xml), Err(Error::Bdn(_))), "{to}");
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Legacy processing through BDSup2Sub, extracting the subtitles to PNG images.
+
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use image::RgbaImage;
+use rayon::prelude::*;
+
+use crate::stats::Samples;
+use crate::workspace::Workspace;
+use crate::{Error, Result, Scope, TonemapOptions};
+
+pub const JAR_NAME: &str = "BDSup2Sub512.jar";
+
+#[derive(Debug, Clone)]
+pub struct BdSup2Sub {
+    pub java_jar: PathBuf,
+    /// Directory for the temporary workspaces, defaults to the system temporary directory
+    pub temp_dir: Option<PathBuf>,
+    /// Leave the extracted images behind, for debugging
+    pub keep_temp: bool,
+}
+
+impl BdSup2Sub {
+    /// Expects the jar file to be in the same directory as the current executable
+    pub fn from_current_exe() -> Result<Self> {
+        let mut java_jar = env::current_exe()?;
+        java_jar.pop();
+        java_jar.push(JAR_NAME);
+
+        Ok(Self {
+            java_jar,
+            temp_dir: None,
+            keep_temp: false,
+        })
+    }
+
+    pub fn exists(&self) -> bool {
+        self.java_jar.exists()
+    }
+
+    /// Tonemaps `file` to `output`, extracting the images to a temporary workspace.
+    pub fn tonemap_file(&self, file: &Path, output: &Path, opts: &TonemapOptions) -> Result<()> {
+        let workspace = Workspace::new(self.temp_dir.as_deref(), self.keep_temp)?;
+        let timestamps = workspace.path().join("sub.xml");
+
+        self.run("Image extraction", file, &timestamps)?;
+        process_images(workspace.path(), opts)?;
+        self.run("Image merging", &timestamps, output)
+    }
+
+    fn run(&self, step: &'static str, input: &Path, output: &Path) -> Result<()> {
+        let output = Command::new("java")
+            .arg("-jar")
+            .arg(&self.java_jar)
+            .args(["-T", "keep", "-o"])
+            .arg(output)
+            .arg(input)
+            .output()
+            .map_err(|e| Error::ExternalTool {
+                step,
+                message: format!("Failed to execute java: {e}"),
+            })?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stderr = stderr.trim();
+
+            let message = if stderr.is_empty() {
+                output.status.to_string()
+            } else {
+                format!("{}\n{stderr}", output.status)
+            };
+
+            Err(Error::ExternalTool { step, message })
+        }
+    }
+}
+
+fn process_images(in_dir: &Path, opts: &TonemapOptions) -> Result<()> {
+    let images: Vec<PathBuf> = in_dir
+        .read_dir()?
+        .filter_map(std::result::Result::ok)
+        .map(|e| e.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "png"))
+        .collect();
+
+    // The images have no epochs, only one reference for the whole file
+    let file_reference = if opts.scope == Scope::Event {
+        None
+    } else {
+        let samples = images
+            .par_iter()
+            .map(|i| -> Result<Samples> { Ok(image_samples(&image::open(i)?.to_rgba8(), opts)) })
+            .try_reduce(Samples::default, |mut a, mut b| {
+                a.append(&mut b);
+                Ok(a)
+            })?;
+
+        Some(samples.reference(opts.statistic))
+    };
+
+    images.par_iter().try_for_each(|i| -> Result<()> {
+        let mut img = image::open(i)?.to_rgba8();
+        let old_max =
+            file_reference.unwrap_or_else(|| image_samples(&img, opts).reference(opts.statistic));
+
+        img.pixels_mut().for_each(|p| {
+            let image::Rgba(mut data) = *p;
+            let rgb = [data[0] as f32, data[1] as f32, data[2] as f32];
+
+            if opts.should_edit(rgb, data[3]) {
+                let [r, g, b] = opts.tonemap_rgba(rgb, data[3], old_max);
+
+                data[0] = r.round() as u8;
+                data[1] = g.round() as u8;
+                data[2] = b.round() as u8;
+            }
+
+            data[3] = opts.scale_opacity(data[3]);
+            *p = image::Rgba(data);
+        });
+
+        img.save(i)?;
+
+        Ok(())
+    })
+}
+
+/// Measures every color of the image, weighted by its number of pixels
+fn image_samples(img: &RgbaImage, opts: &TonemapOptions) -> Samples {
+    let mut counts: HashMap<[u8; 4], u32> = HashMap::new();
+    for image::Rgba(data) in img.pixels() {
+        *counts.entry(*data).or_default() += 1;
+    }
+
+    let mut samples = Samples::default();
+    for (data, count) in counts.into_iter().filter(|(d, _)| opts.is_visible(d[3])) {
+        let rgb = [data[0] as f32, data[1] as f32, data[2] as f32];
+        samples.push(opts.measure_rgba(rgb, data[3]), count as f32, data[3]);
+    }
+
+    samples
+}
@@ -0,0 +1,152 @@
+//! Classification of the subtitle colors into fill, edge ramps and outline.
+//!
+//! Subtitles are usually a bright fill surrounded by a dark outline, with anti-aliased ramps between them.
+//! The classes are decided from the lightness of a color relative to the reference brightness.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorClass {
+    Fill,
+    Edge,
+    Outline,
+}
+
+/// Overrides for the colors of one class
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClassOptions {
+    /// Replaces the ratio. With `target_nits`, scales the target luminance instead.
+    pub ratio: Option<f32>,
+    /// Base color replacing the subtitle's colors, like `fixed`
+    pub color: Option<[f32; 3]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Classification {
+    /// Colors darker than this fraction of the reference brightness are outline
+    pub outline_below: f32,
+    /// Colors at least as light as this fraction of the reference brightness are fill
+    pub fill_above: f32,
+    pub fill: ClassOptions,
+    pub edge: ClassOptions,
+    pub outline: ClassOptions,
+}
+
+impl Default for Classification {
+    fn default() -> Self {
+        Self {
+            outline_below: 0.25,
+            fill_above: 0.75,
+            fill: ClassOptions::default(),
+            edge: ClassOptions::default(),
+            outline: ClassOptions::default(),
+        }
+    }
+}
+
+impl Classification {
+    pub fn classify(&self, lightness: f32, reference: f32) -> ColorClass {
+        let relative = if reference > 0.0 {
+            lightness / reference
+        } else {
+            1.0
+        };
+
+        if relative >= self.fill_above {
+            ColorClass::Fill
+        } else if relative < self.outline_below {
+            ColorClass::Outline
+        } else {
+            ColorClass::Edge
+        }
+    }
+
+    pub fn options(&self, class: ColorClass) -> &ClassOptions {
+        match class {
+            ColorClass::Fill => &self.fill,
+            ColorClass::Edge => &self.edge,
+            ColorClass::Outline => &self.outline,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::get_lightness;
+    use crate::pgs::samples::{caption, entry};
+    use crate::pgs::{Object, PgsStream};
+    use crate::{tonemap, TonemapOptions};
+
+    /// White fill surrounded by a grey ramp and a dark outline, in the corners of a transparent box
+    const BITMAP: [u8; 24] = [
+        0, 3, 3, 3, 3, 0, //
+        3, 2, 1, 1, 2, 3, //
+        3, 2, 1, 1, 2, 3, //
+        0, 3, 3, 3, 3, 0, //
+    ];
+    const GREYS: [f32; 4] = [0.0, 255.0, 140.0, 40.0];
+
+    #[test]
+    fn fill_surrounded_by_outline() {
+        let classification = Classification::default();
+        let lightness = |index: u8| {
+            let grey = GREYS[index as usize];
+            get_lightness(grey, grey, grey)
+        };
+        let reference = BITMAP
+            .iter()
+            .filter(|&&index| index > 0)
+            .map(|&index| lightness(index))
+            .fold(0.0, f32::max);
+
+        let classes: String = BITMAP
+            .iter()
+            .map(|&index| match index {
+                0 => '.',
+                index => match classification.classify(lightness(index), reference) {
+                    ColorClass::Fill => 'F',
+                    ColorClass::Edge => 'E',
+                    ColorClass::Outline => 'O',
+                },
+            })
+            .collect();
+        assert_eq!(classes, ".OOOO.OEFFEOOEFFEO.OOOO.");
+
+        // Without a reference, everything is fill
+        assert_eq!(classification.classify(0.1, 0.0), ColorClass::Fill);
+    }
+
+    #[test]
+    fn classes_use_their_options() {
+        let object = Object::from_bitmap(0, 0, 6, 4, &BITMAP);
+        let luma = |grey: f32| (16.0 + 219.0 * grey / 255.0).round() as u8;
+        let alpha = |i: u8| if i == 0 { 0 } else { 255 };
+        let entries = (0..4)
+            .map(|i| entry(i, luma(GREYS[i as usize]), 128, 128, alpha(i)))
+            .collect();
+        let mut stream = PgsStream {
+            display_sets: vec![caption(90_000, 0, false, entries, &object)],
+        };
+
+        let opts = TonemapOptions {
+            ratio: 1.0,
+            classification: Some(Classification {
+                fill: ClassOptions {
+                    ratio: Some(0.5),
+                    color: None,
+                },
+                outline: ClassOptions {
+                    ratio: None,
+                    color: Some([0.0; 3]),
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        tonemap(&mut stream, &opts).unwrap();
+
+        let entries = &stream.display_sets[0].palettes().next().unwrap().entries;
+        assert!(entries[1].y.abs_diff(luma(127.5)) <= 1, "{:?}", entries[1]);
+        assert!(entries[2].y.abs_diff(luma(140.0)) <= 1, "{:?}", entries[2]);
+        assert_eq!(entries[3].y, 16);
+    }
+}
@@ -0,0 +1,477 @@
+//! Color math shared by the processing modes, on RGB values in the 0-255 range.
+
+#[inline(always)]
+pub fn get_lightness(r: f32, g: f32, b: f32) -> f32 {
+    let rp = r / 255.0;
+    let gp = g / 255.0;
+    let bp = b / 255.0;
+    let cmax = rp.max(gp).max(bp);
+    let cmin = rp.min(gp).min(bp);
+
+    (cmax + cmin) / 2.0
+}
+
+/// Parses a `RRGGBB` hexadecimal color
+pub fn parse_hex(value: &str) -> Option<[u8; 3]> {
+    if value.len() != 6 || !value.is_ascii() {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&value[i..i + 2], 16).ok();
+
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Skips black and fully transparent colors
+#[inline(always)]
+pub fn should_edit(r: f32, g: f32, b: f32, alpha: u8) -> bool {
+    r.max(g).max(b) > 1.0 && alpha > 0
+}
+
+/// Peak luminance of the SMPTE ST 2084 (PQ) curve, in nits
+pub const PQ_MAX_NITS: f32 = 10000.0;
+
+const PQ_M1: f32 = 2610.0 / 16384.0;
+const PQ_M2: f32 = 2523.0 / 4096.0 * 128.0;
+const PQ_C1: f32 = 3424.0 / 4096.0;
+const PQ_C2: f32 = 2413.0 / 4096.0 * 32.0;
+const PQ_C3: f32 = 2392.0 / 4096.0 * 32.0;
+
+/// PQ EOTF, from a 0-1 signal to luminance in nits
+#[inline(always)]
+pub fn pq_eotf(signal: f32) -> f32 {
+    let e = signal.clamp(0.0, 1.0).powf(1.0 / PQ_M2);
+    let y = ((e - PQ_C1).max(0.0) / (PQ_C2 - PQ_C3 * e)).powf(1.0 / PQ_M1);
+
+    y * PQ_MAX_NITS
+}
+
+/// Inverse PQ EOTF, from luminance in nits to a 0-1 signal
+#[inline(always)]
+pub fn pq_inverse_eotf(nits: f32) -> f32 {
+    let y = (nits / PQ_MAX_NITS).clamp(0.0, 1.0).powf(PQ_M1);
+
+    ((PQ_C1 + PQ_C2 * y) / (1.0 + PQ_C3 * y)).powf(PQ_M2)
+}
+
+/// Relative luminance of linear BT.2020 RGB
+#[inline(always)]
+pub fn bt2020_luminance(rgb: [f32; 3]) -> f32 {
+    0.2627 * rgb[0] + 0.6780 * rgb[1] + 0.0593 * rgb[2]
+}
+
+const HLG_A: f32 = 0.178_832_77;
+const HLG_B: f32 = 1.0 - 4.0 * HLG_A;
+const HLG_C: f32 = 0.559_910_7;
+
+/// HLG inverse OETF, from a 0-1 signal to 0-1 scene linear light
+#[inline(always)]
+pub fn hlg_inverse_oetf(signal: f32) -> f32 {
+    let signal = signal.clamp(0.0, 1.0);
+
+    if signal <= 0.5 {
+        signal * signal / 3.0
+    } else {
+        (((signal - HLG_C) / HLG_A).exp() + HLG_B) / 12.0
+    }
+}
+
+/// HLG OETF, from 0-1 scene linear light to a 0-1 signal
+#[inline(always)]
+pub fn hlg_oetf(linear: f32) -> f32 {
+    let linear = linear.clamp(0.0, 1.0);
+
+    if linear <= 1.0 / 12.0 {
+        (3.0 * linear).sqrt()
+    } else {
+        HLG_A * (12.0 * linear - HLG_B).ln() + HLG_C
+    }
+}
+
+/// HLG system gamma for a display peak luminance, in nits
+#[inline(always)]
+pub fn hlg_system_gamma(peak_nits: f32) -> f32 {
+    1.2 + 0.42 * (peak_nits / 1000.0).log10()
+}
+
+/// Display luminance in nits of an achromatic HLG signal
+pub fn hlg_signal_to_nits(signal: f32, peak_nits: f32) -> f32 {
+    peak_nits * hlg_inverse_oetf(signal).powf(hlg_system_gamma(peak_nits))
+}
+
+/// Transfer function of the subtitle colors, for processing in absolute luminance
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Transfer {
+    /// SMPTE ST 2084
+    #[default]
+    Pq,
+    /// ARIB STD-B67, with the OOTF for a display of `peak_nits`
+    Hlg { peak_nits: f32 },
+}
+
+impl Transfer {
+    /// Decodes RGB code values in the 0-255 range to linear display light in nits
+    pub fn to_linear(&self, rgb: [f32; 3]) -> [f32; 3] {
+        match *self {
+            Self::Pq => rgb.map(|c| pq_eotf(c / 255.0)),
+            Self::Hlg { peak_nits } => {
+                let scene = rgb.map(|c| hlg_inverse_oetf(c / 255.0));
+                let ys = bt2020_luminance(scene);
+
+                if ys <= 0.0 {
+                    return [0.0; 3];
+                }
+
+                let gain = peak_nits * ys.powf(hlg_system_gamma(peak_nits) - 1.0);
+                scene.map(|c| c * gain)
+            }
+        }
+    }
+
+    /// Encodes linear display light in nits to RGB code values in the 0-255 range
+    pub fn from_linear(&self, rgb: [f32; 3]) -> [f32; 3] {
+        match *self {
+            Self::Pq => rgb.map(|c| pq_inverse_eotf(c) * 255.0),
+            Self::Hlg { peak_nits } => {
+                let rgb = rgb.map(|c| c.clamp(0.0, peak_nits));
+                let yd = bt2020_luminance(rgb);
+
+                if yd <= 0.0 {
+                    return [0.0; 3];
+                }
+
+                let gamma = hlg_system_gamma(peak_nits);
+                let ys = (yd / peak_nits).powf(1.0 / gamma);
+                let gain = peak_nits * ys.powf(gamma - 1.0);
+
+                rgb.map(|c| hlg_oetf(c / gain) * 255.0)
+            }
+        }
+    }
+}
+
+/// YCbCr matrix of the palette entries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Matrix {
+    #[default]
+    Bt709,
+    /// BT.2020 non-constant luminance, for UHD subtitles
+    Bt2020,
+}
+
+impl Matrix {
+    /// Red and blue luma coefficients
+    pub fn coefficients(&self) -> (f32, f32) {
+        match self {
+            Self::Bt709 => (0.2126, 0.0722),
+            Self::Bt2020 => (0.2627, 0.0593),
+        }
+    }
+}
+
+/// Luminance of SDR white, in nits
+pub const SDR_WHITE_NITS: f32 = 100.0;
+/// Luminance of HDR reference white from BT.2408, in nits
+pub const HDR_WHITE_NITS: f32 = 203.0;
+
+/// BT.1886 EOTF with a zero black level, from a 0-1 signal to 0-1 linear light
+#[inline(always)]
+pub fn bt1886_eotf(signal: f32) -> f32 {
+    signal.clamp(0.0, 1.0).powf(2.4)
+}
+
+/// Inverse BT.1886 EOTF, from 0-1 linear light to a 0-1 signal
+#[inline(always)]
+pub fn bt1886_inverse_eotf(linear: f32) -> f32 {
+    linear.clamp(0.0, 1.0).powf(1.0 / 2.4)
+}
+
+/// Model used to measure how light a color looks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightnessModel {
+    /// HSL lightness `(max + min) / 2`, on the code values
+    Hsl,
+    /// BT.709 relative luminance
+    Bt709,
+    /// BT.2020 relative luminance
+    Bt2020,
+    /// CIELAB L*
+    Cielab,
+    /// ICtCp intensity I
+    Ictcp,
+    /// Jzazbz lightness Jz
+    Jzazbz,
+}
+
+impl LightnessModel {
+    /// Lightness of linear RGB in nits, for a reference white of `white_nits`.
+    /// HSL is not defined on linear light, use [`get_lightness`] on the code values instead.
+    pub fn lightness(&self, linear: [f32; 3], white_nits: f32) -> f32 {
+        match self {
+            Self::Hsl => unreachable!("HSL lightness uses the code values"),
+            Self::Bt709 => bt709_luminance(linear),
+            Self::Bt2020 => bt2020_luminance(linear),
+            Self::Cielab => cielab_lightness(bt2020_luminance(linear) / white_nits),
+            Self::Ictcp => ictcp_intensity(linear),
+            Self::Jzazbz => jzazbz_lightness(linear),
+        }
+    }
+}
+
+/// Relative luminance of linear BT.709 RGB
+#[inline(always)]
+pub fn bt709_luminance(rgb: [f32; 3]) -> f32 {
+    0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2]
+}
+
+/// CIELAB L* of a luminance relative to the reference white
+#[inline(always)]
+pub fn cielab_lightness(relative_y: f32) -> f32 {
+    const EPSILON: f32 = 216.0 / 24389.0;
+    const KAPPA: f32 = 24389.0 / 27.0;
+
+    let relative_y = relative_y.max(0.0);
+
+    if relative_y > EPSILON {
+        116.0 * relative_y.cbrt() - 16.0
+    } else {
+        KAPPA * relative_y
+    }
+}
+
+/// ICtCp intensity of linear BT.2020 RGB in nits
+#[inline(always)]
+pub fn ictcp_intensity(rgb: [f32; 3]) -> f32 {
+    let l = (1688.0 * rgb[0] + 2146.0 * rgb[1] + 262.0 * rgb[2]) / 4096.0;
+    let m = (683.0 * rgb[0] + 2951.0 * rgb[1] + 462.0 * rgb[2]) / 4096.0;
+
+    0.5 * pq_inverse_eotf(l) + 0.5 * pq_inverse_eotf(m)
+}
+
+/// Jzazbz lightness of linear BT.2020 RGB in nits
+pub fn jzazbz_lightness(rgb: [f32; 3]) -> f32 {
+    const B: f32 = 1.15;
+    const G: f32 = 0.66;
+    const D: f32 = -0.56;
+    const D0: f32 = 1.629_55e-11;
+    const P: f32 = 1.7 * 2523.0 / 32.0;
+
+    let x = 0.636_958 * rgb[0] + 0.144_617 * rgb[1] + 0.168_881 * rgb[2];
+    let y = 0.262_700 * rgb[0] + 0.677_998 * rgb[1] + 0.059_302 * rgb[2];
+    let z = 0.028_073 * rgb[1] + 1.060_985 * rgb[2];
+
+    let xp = B * x - (B - 1.0) * z;
+    let yp = G * y - (G - 1.0) * x;
+
+    let l = 0.414_789_7 * xp + 0.579_999 * yp + 0.014_648 * z;
+    let m = -0.201_51 * xp + 1.120_649 * yp + 0.053_100_8 * z;
+
+    let perceptual = |c: f32| {
+        let c = (c / PQ_MAX_NITS).max(0.0).powf(PQ_M1);
+        ((PQ_C1 + PQ_C2 * c) / (1.0 + PQ_C3 * c)).powf(P)
+    };
+
+    let iz = 0.5 * (perceptual(l) + perceptual(m));
+
+    ((1.0 + D) * iz) / (1.0 + D * iz) - D0
+}
+
+/// CIELAB of BT.709 RGB code values, relative to a D65 white
+pub fn rgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    const WHITE: [f32; 3] = [0.950_47, 1.0, 1.088_83];
+
+    let [r, g, b] = rgb.map(|c| bt1886_eotf(c / 255.0));
+
+    let x = 0.412_390_8 * r + 0.357_584_3 * g + 0.180_480_8 * b;
+    let y = 0.212_639 * r + 0.715_168_7 * g + 0.072_192_3 * b;
+    let z = 0.019_330_8 * r + 0.119_194_8 * g + 0.950_532_2 * b;
+
+    let f = |t: f32| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x / WHITE[0]), f(y / WHITE[1]), f(z / WHITE[2]));
+
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// CIE76 color difference, the distance between two CIELAB colors
+#[inline(always)]
+pub fn delta_e(a: [f32; 3], b: [f32; 3]) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgs::PaletteEntry;
+
+    fn assert_close(value: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (value - expected).abs() <= tolerance,
+            "{value} instead of {expected}"
+        );
+    }
+
+    fn entry(y: u8, cr: u8, cb: u8) -> PaletteEntry {
+        PaletteEntry {
+            id: 0,
+            y,
+            cr,
+            cb,
+            alpha: 255,
+        }
+    }
+
+    #[test]
+    fn pq_known_values() {
+        assert_close(pq_inverse_eotf(100.0), 0.508_08, 1e-4);
+        assert_close(pq_inverse_eotf(203.0), 0.580_69, 1e-4);
+        assert_close(pq_inverse_eotf(1000.0), 0.751_83, 1e-4);
+        assert_close(pq_inverse_eotf(PQ_MAX_NITS), 1.0, 1e-6);
+
+        assert_close(pq_eotf(0.0), 0.0, 1e-6);
+        assert_close(pq_eotf(1.0), PQ_MAX_NITS, 1e-2);
+        // 10 bits code values
+        assert_close(pq_eotf(520.0 / 1023.0), 101.0, 1.0);
+        assert_close(pq_eotf(0.580_69), 203.0, 0.05);
+    }
+
+    #[test]
+    fn pq_round_trip() {
+        for nits in [0.01, 0.5, 48.0, 100.0, 203.0, 1000.0, 4000.0] {
+            assert_close(pq_eotf(pq_inverse_eotf(nits)), nits, nits * 1e-3);
+        }
+        for i in 0..=100 {
+            let signal = i as f32 / 100.0;
+            assert_close(pq_inverse_eotf(pq_eotf(signal)), signal, 1e-4);
+        }
+    }
+
+    #[test]
+    fn hlg_known_values() {
+        // 75% HLG is the reference white of BT.2408, 203 nits on a 1000 nits display
+        assert_close(hlg_inverse_oetf(0.75), 0.265, 1e-3);
+        assert_close(hlg_oetf(0.265), 0.75, 1e-3);
+        assert_close(hlg_signal_to_nits(0.75, 1000.0), 203.0, 0.5);
+        assert_close(hlg_signal_to_nits(1.0, 1000.0), 1000.0, 0.1);
+
+        // The two segments of the OETF meet at 1/12
+        assert_close(hlg_oetf(1.0 / 12.0), 0.5, 1e-6);
+        assert_close(hlg_inverse_oetf(0.5), 1.0 / 12.0, 1e-6);
+        assert_close(hlg_oetf(1.0), 1.0, 1e-5);
+
+        assert_close(hlg_system_gamma(1000.0), 1.2, 1e-6);
+        assert_close(hlg_system_gamma(2000.0), 1.326, 1e-3);
+        assert_close(hlg_system_gamma(400.0), 1.033, 1e-3);
+
+        for i in 0..=100 {
+            let signal = i as f32 / 100.0;
+            assert_close(hlg_oetf(hlg_inverse_oetf(signal)), signal, 1e-5);
+        }
+    }
+
+    #[test]
+    fn hlg_ootf() {
+        let hlg = Transfer::Hlg { peak_nits: 1000.0 };
+
+        // Achromatic colors follow the system gamma
+        let white = hlg.to_linear([0.75 * 255.0; 3]);
+        for c in white {
+            assert_close(c, 203.0, 0.5);
+        }
+
+        // The OOTF scales the channels by the same gain, keeping the hue
+        let scene = [0.6, 0.4, 0.1].map(hlg_inverse_oetf);
+        let linear = hlg.to_linear([0.6, 0.4, 0.1].map(|c| c * 255.0));
+        assert_close(linear[0] / linear[1], scene[0] / scene[1], 1e-4);
+        assert_close(linear[2] / linear[1], scene[2] / scene[1], 1e-4);
+
+        for rgb in [[255.0; 3], [200.0, 120.0, 30.0], [10.0, 90.0, 250.0]] {
+            let back = hlg.from_linear(hlg.to_linear(rgb));
+            for (c, expected) in back.into_iter().zip(rgb) {
+                assert_close(c, expected, 0.05);
+            }
+        }
+        assert_eq!(hlg.to_linear([0.0; 3]), [0.0; 3]);
+        assert_eq!(hlg.from_linear([0.0; 3]), [0.0; 3]);
+    }
+
+    #[test]
+    fn primaries_to_ycbcr() {
+        let ycbcr = |rgb: [f32; 3], matrix| {
+            let mut entry = entry(16, 128, 128);
+            entry.set_rgb(rgb, matrix);
+            [entry.y, entry.cb, entry.cr]
+        };
+
+        for (matrix, white, red, green, blue) in [
+            (
+                Matrix::Bt709,
+                [235, 128, 128],
+                [63, 102, 240],
+                [173, 42, 26],
+                [32, 240, 118],
+            ),
+            (
+                Matrix::Bt2020,
+                [235, 128, 128],
+                [74, 97, 240],
+                [164, 47, 25],
+                [29, 240, 119],
+            ),
+        ] {
+            assert_eq!(ycbcr([255.0; 3], matrix), white);
+            assert_eq!(ycbcr([0.0; 3], matrix), [16, 128, 128]);
+            assert_eq!(ycbcr([255.0, 0.0, 0.0], matrix), red);
+            assert_eq!(ycbcr([0.0, 255.0, 0.0], matrix), green);
+            assert_eq!(ycbcr([0.0, 0.0, 255.0], matrix), blue);
+
+            let [y, cb, cr] = red;
+            let [r, g, b] = entry(y, cr, cb).to_rgb(matrix);
+            assert_close(r, 255.0, 1.5);
+            assert_close(g, 0.0, 1.5);
+            assert_close(b, 0.0, 1.5);
+        }
+
+        // Decoding with the wrong matrix shifts the colors
+        let [_, g, _] = entry(74, 240, 97).to_rgb(Matrix::Bt709);
+        assert!(g > 10.0, "{g}");
+    }
+
+    #[test]
+    fn limited_range_round_trip() {
+        for matrix in [Matrix::Bt709, Matrix::Bt2020] {
+            for y in (16..=235).step_by(3) {
+                for cb in (16..=240).step_by(7) {
+                    for cr in (16..=240).step_by(7) {
+                        let original = entry(y, cr, cb);
+                        let rgb = original.to_rgb(matrix);
+
+                        // Clipped out of the RGB gamut
+                        if rgb.iter().any(|&c| c <= 0.0 || c >= 255.0) {
+                            continue;
+                        }
+
+                        let mut entry = entry(16, 128, 128);
+                        entry.set_rgb(rgb, matrix);
+                        assert_eq!(entry, original, "{matrix:?}");
+                    }
+                }
+            }
+
+            // 8 bits RGB loses less than a code value
+            for rgb in [[255.0, 128.0, 0.0], [12.0, 200.0, 99.0], [77.0; 3]] {
+                let mut entry = entry(16, 128, 128);
+                entry.set_rgb(rgb, matrix);
+
+                for (c, expected) in entry.to_rgb(matrix).into_iter().zip(rgb) {
+                    assert_close(c, expected, 1.5);
+                }
+            }
+        }
+    }
+}
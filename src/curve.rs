@@ -0,0 +1,201 @@
+//! Tone curves, mapping the lightness of the subtitle colors from a reference peak to a target peak.
+
+use crate::color::{pq_eotf, pq_inverse_eotf};
+
+/// Curve applied to the lightness of the colors.
+///
+/// Every curve maps `0` to `0` and the reference `peak_in` to the target `peak_out`,
+/// except that `Clip`, `SoftKnee` and `Bt2390` only compress:
+/// with `peak_in` below `peak_out`, they keep every color, and the reference, untouched.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ToneCurve {
+    /// Multiplies by `peak_out / peak_in`
+    #[default]
+    Linear,
+    /// Power curve on the lightness relative to the reference,
+    /// a `gamma` above 1 darkens the dim colors more than the bright ones
+    Gamma { gamma: f32 },
+    /// Keeps the colors below `peak_out` untouched and clips the brighter ones
+    Clip,
+    /// Keeps the colors below `knee * peak_out` untouched and smoothly compresses the brighter ones
+    SoftKnee { knee: f32 },
+    /// Extended Reinhard, with the reference as white point
+    Reinhard,
+    /// BT.2390 EETF, a Hermite spline roll-off in the PQ domain. The values are luminance in nits.
+    /// `knee` is the start of the roll-off relative to the PQ encoded reference,
+    /// defaults to `1.5 * max_lum - 0.5` from the recommendation.
+    Bt2390 { knee: Option<f32> },
+}
+
+impl ToneCurve {
+    pub fn apply(&self, value: f32, peak_in: f32, peak_out: f32) -> f32 {
+        if peak_in <= 0.0 {
+            return value;
+        }
+        if peak_out <= 0.0 {
+            return 0.0;
+        }
+
+        match *self {
+            Self::Linear => value * peak_out / peak_in,
+            Self::Gamma { gamma } => peak_out * (value / peak_in).max(0.0).powf(gamma),
+            Self::Clip => value.min(peak_out),
+            Self::SoftKnee { knee } => soft_knee(value, peak_in, peak_out, knee),
+            Self::Reinhard => {
+                let white = peak_in / peak_out;
+                let x = value / peak_out;
+
+                peak_out * x * (1.0 + x / (white * white)) / (1.0 + x)
+            }
+            Self::Bt2390 { knee } => bt2390_eetf(value, peak_in, peak_out, knee),
+        }
+    }
+}
+
+/// Identity up to the knee, then `e / (1 + e * (1 / out - 1 / in))` on the excess
+/// so that the slope stays continuous and the reference reaches `peak_out`.
+fn soft_knee(value: f32, peak_in: f32, peak_out: f32, knee: f32) -> f32 {
+    let knee = knee.clamp(0.0, 1.0) * peak_out;
+
+    if value <= knee {
+        return value;
+    }
+
+    let range_in = peak_in - knee;
+    let range_out = peak_out - knee;
+
+    // Nothing to compress
+    if range_in <= range_out || range_out <= 0.0 {
+        return value.min(peak_out.max(knee));
+    }
+
+    let excess = value - knee;
+
+    knee + excess / (1.0 + excess * (1.0 / range_out - 1.0 / range_in))
+}
+
+fn bt2390_eetf(nits: f32, peak_in: f32, peak_out: f32, knee: Option<f32>) -> f32 {
+    let source_max = pq_inverse_eotf(peak_in);
+    let max_lum = pq_inverse_eotf(peak_out) / source_max;
+
+    // The EETF only compresses
+    if max_lum >= 1.0 {
+        return nits;
+    }
+
+    let e1 = (pq_inverse_eotf(nits) / source_max).min(1.0);
+    let ks = knee.unwrap_or(1.5 * max_lum - 0.5).clamp(0.0, max_lum);
+
+    let e2 = if e1 < ks {
+        e1
+    } else {
+        let t = (e1 - ks) / (1.0 - ks);
+        let (t2, t3) = (t * t, t * t * t);
+
+        (2.0 * t3 - 3.0 * t2 + 1.0) * ks
+            + (t3 - 2.0 * t2 + t) * (1.0 - ks)
+            + (-2.0 * t3 + 3.0 * t2) * max_lum
+    };
+
+    pq_eotf(e2 * source_max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [ToneCurve; 7] = [
+        ToneCurve::Linear,
+        ToneCurve::Gamma { gamma: 2.2 },
+        ToneCurve::Clip,
+        ToneCurve::SoftKnee { knee: 0.75 },
+        ToneCurve::Reinhard,
+        ToneCurve::Bt2390 { knee: None },
+        ToneCurve::Bt2390 { knee: Some(0.5) },
+    ];
+
+    fn assert_close(value: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (value - expected).abs() <= tolerance,
+            "{value} instead of {expected}"
+        );
+    }
+
+    #[test]
+    fn endpoints() {
+        for curve in CURVES {
+            for (peak_in, peak_out) in [(1000.0, 203.0), (255.0, 153.0), (100.0, 203.0)] {
+                let compresses_only = matches!(
+                    curve,
+                    ToneCurve::Clip | ToneCurve::SoftKnee { .. } | ToneCurve::Bt2390 { .. }
+                );
+                let expected = if compresses_only && peak_in < peak_out {
+                    peak_in
+                } else {
+                    peak_out
+                };
+
+                assert_close(curve.apply(0.0, peak_in, peak_out), 0.0, 1e-3);
+                assert_close(curve.apply(peak_in, peak_in, peak_out), expected, 0.05);
+            }
+
+            // Without a reference or a target
+            assert_eq!(curve.apply(50.0, 0.0, 203.0), 50.0);
+            assert_eq!(curve.apply(50.0, 100.0, 0.0), 0.0);
+        }
+    }
+
+    #[test]
+    fn curves_are_monotonic() {
+        for curve in CURVES {
+            for (peak_in, peak_out) in [(1000.0, 203.0), (255.0, 153.0), (100.0, 203.0)] {
+                let mut previous = 0.0;
+
+                for i in 0..=1000 {
+                    let value = curve.apply(peak_in * i as f32 / 1000.0, peak_in, peak_out);
+                    assert!(value >= previous - 1e-3, "{curve:?} at {i}");
+                    previous = value;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn soft_knee_keeps_the_colors_below_the_knee() {
+        let curve = ToneCurve::SoftKnee { knee: 0.75 };
+        let knee = 0.75 * 153.0;
+
+        assert_eq!(curve.apply(100.0, 255.0, 153.0), 100.0);
+        assert_eq!(curve.apply(knee, 255.0, 153.0), knee);
+
+        // The slope stays 1 right above the knee
+        let slope = (curve.apply(knee + 0.1, 255.0, 153.0) - knee) / 0.1;
+        assert_close(slope, 1.0, 0.01);
+    }
+
+    #[test]
+    fn bt2390_knee_is_continuous() {
+        let (peak_in, peak_out) = (1000.0, 203.0);
+        let source_max = pq_inverse_eotf(peak_in);
+        let max_lum = pq_inverse_eotf(peak_out) / source_max;
+
+        for (knee, ks) in [(None, 1.5 * max_lum - 0.5), (Some(0.4), 0.4)] {
+            let curve = ToneCurve::Bt2390 { knee };
+            let at = |e: f32| curve.apply(pq_eotf(e * source_max), peak_in, peak_out);
+            let knee_nits = pq_eotf(ks * source_max);
+
+            // Identity below the knee
+            assert_close(at(ks * 0.5), pq_eotf(ks * 0.5 * source_max), 1e-2);
+            assert_close(at(ks), knee_nits, 1e-2);
+
+            // The value and the slope in the PQ domain are continuous at the knee
+            let pq = |e: f32| pq_inverse_eotf(at(e)) / source_max;
+            let h = 1e-3;
+            assert_close(pq(ks + h), ks + h, 1e-4);
+            let below = (pq(ks) - pq(ks - h)) / h;
+            let above = (pq(ks + h) - pq(ks)) / h;
+            assert_close(below, 1.0, 0.01);
+            assert_close(above, 1.0, 0.01);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::io;
+
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("{step} failed: {message}")]
+    ExternalTool { step: &'static str, message: String },
+
+    #[error("Invalid PGS data: {0}")]
+    Parse(String),
+
+    #[error("Cannot write PGS data: {0}")]
+    Encode(String),
+
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+
+    #[error(transparent)]
+    Glob(#[from] globset::Error),
+
+    #[error("Timing verification failed: {0}")]
+    Timing(String),
+
+    #[error("Invalid Matroska data: {0}")]
+    Matroska(String),
+
+    #[error("Invalid transport stream: {0}")]
+    TransportStream(String),
+
+    #[error("Invalid BDN data: {0}")]
+    Bdn(String),
+
+    #[error("Invalid color rule `{rule}`: {message}")]
+    Rule { rule: String, message: String },
+}
+
+pub(crate) fn parse_error<S: Into<String>>(message: S) -> Error {
+    Error::Parse(message.into())
+}
+
+pub(crate) fn encode_error<S: Into<String>>(message: S) -> Error {
+    Error::Encode(message.into())
+}
@@ -0,0 +1,269 @@
+//! Discovery of the subtitle files to process.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use walkdir::WalkDir;
+
+use crate::bdn;
+use crate::m2ts;
+use crate::matroska::PgsTrack;
+use crate::Result;
+
+/// Extensions of the supported input files, compared case-insensitively
+pub const SUPPORTED_EXTENSIONS: &[&str] = &["sup", "xml", "mkv", "mks", "m2ts", "mts", "ts"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputFile {
+    pub path: PathBuf,
+    /// Path relative to the input directory it was found in, or the file name for file inputs
+    pub relative: PathBuf,
+    /// PGS tracks to process, for Matroska inputs
+    pub tracks: Vec<PgsTrack>,
+    /// PID of the PGS stream to process, for transport stream inputs
+    pub pid: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InputOptions {
+    /// Also look for files in the subdirectories of directory inputs
+    pub recursive: bool,
+    /// When not empty, only files matching one of these patterns are kept
+    pub include: Vec<Glob>,
+    /// Files matching any of these patterns are skipped
+    pub exclude: Vec<Glob>,
+}
+
+pub fn is_supported(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            SUPPORTED_EXTENSIONS
+                .iter()
+                .any(|supported| ext.eq_ignore_ascii_case(supported))
+        })
+}
+
+/// Finds the supported files from a list of file or directory inputs.
+///
+/// The patterns are matched against the path relative to the directory input,
+/// or against the path as given for file inputs.
+/// XML files found in directories are only inputs when they are BDN documents,
+/// and transport streams when they start with sync bytes.
+pub fn find_inputs(inputs: &[PathBuf], opts: &InputOptions) -> Result<Vec<InputFile>> {
+    let include = build_glob_set(&opts.include)?;
+    let exclude = build_glob_set(&opts.exclude)?;
+
+    let is_selected = |path: &Path| {
+        (opts.include.is_empty() || include.is_match(path)) && !exclude.is_match(path)
+    };
+
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+
+    for input in inputs {
+        if input.is_dir() {
+            let walker = WalkDir::new(input)
+                .follow_links(true)
+                .max_depth(if opts.recursive { usize::MAX } else { 1 })
+                .sort_by_file_name();
+
+            for entry in walker {
+                let entry = entry.map_err(std::io::Error::from)?;
+                let path = entry.path();
+                let relative = path.strip_prefix(input).unwrap_or(path);
+
+                // Other XML files, like the BDMV metadata, are not BDN subtitles,
+                // and `.ts` files may be TypeScript sources
+                if entry.file_type().is_file()
+                    && is_supported(path)
+                    && is_selected(relative)
+                    && (!bdn::is_bdn(path) || bdn::is_bdn_document(path))
+                    && (!m2ts::is_transport_stream(path) || m2ts::has_sync_bytes(path))
+                {
+                    files.push(InputFile {
+                        path: path.to_path_buf(),
+                        relative: relative.to_path_buf(),
+                        tracks: Vec::new(),
+                        pid: None,
+                    });
+                }
+            }
+        } else if input.is_file() {
+            if is_supported(input) && is_selected(input) {
+                files.push(InputFile {
+                    path: input.clone(),
+                    relative: input.file_name().map(PathBuf::from).unwrap_or_default(),
+                    tracks: Vec::new(),
+                    pid: None,
+                });
+            }
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Input {} does not exist", input.display()),
+            )
+            .into());
+        }
+    }
+
+    files.retain(|file| seen.insert(file.path.clone()));
+
+    Ok(files)
+}
+
+/// Reads a list of inputs, one path per line.
+/// Empty lines and lines starting with `#` are ignored.
+pub fn read_list_file<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+    let content = fs::read_to_string(path)?;
+
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+fn build_glob_set(globs: &[Glob]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    globs.iter().cloned().for_each(|glob| {
+        builder.add(glob);
+    });
+
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    /// Directory of subtitles, with files that are not inputs
+    fn sample_dir() -> Workspace {
+        let workspace = Workspace::new(None, false).unwrap();
+        let dir = workspace.path();
+
+        fs::create_dir_all(dir.join("extras/deep")).unwrap();
+        for file in [
+            "a.sup",
+            "B.SUP",
+            "extras/b.sup",
+            "extras/deep/c.sup",
+            "extras/skip.sup",
+        ] {
+            fs::write(dir.join(file), b"PG").unwrap();
+        }
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        fs::write(dir.join("meta.xml"), "<DiscInfo/>").unwrap();
+        fs::write(dir.join("subs.xml"), "<BDN Version=\"0.93\"/>").unwrap();
+        fs::write(dir.join("index.ts"), "export {};\n").unwrap();
+
+        let mut packets = vec![0; 2 * 188];
+        packets[0] = 0x47;
+        packets[188] = 0x47;
+        fs::write(dir.join("movie.m2ts"), packets).unwrap();
+
+        workspace
+    }
+
+    fn relatives(files: &[InputFile]) -> Vec<&str> {
+        files
+            .iter()
+            .map(|file| file.relative.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn directory_inputs() {
+        let workspace = sample_dir();
+        let inputs = [workspace.path().to_path_buf()];
+        let dir = &inputs[0];
+
+        let files = find_inputs(&inputs, &InputOptions::default()).unwrap();
+        assert_eq!(
+            relatives(&files),
+            ["B.SUP", "a.sup", "movie.m2ts", "subs.xml"]
+        );
+        assert_eq!(files[1].path, dir.join("a.sup"));
+        assert!(files.iter().all(|f| f.tracks.is_empty() && f.pid.is_none()));
+
+        let opts = InputOptions {
+            recursive: true,
+            ..Default::default()
+        };
+        let files = find_inputs(&inputs, &opts).unwrap();
+        assert_eq!(
+            relatives(&files),
+            [
+                "B.SUP",
+                "a.sup",
+                "extras/b.sup",
+                "extras/deep/c.sup",
+                "extras/skip.sup",
+                "movie.m2ts",
+                "subs.xml",
+            ]
+        );
+        assert_eq!(files[3].path, dir.join("extras/deep/c.sup"));
+    }
+
+    #[test]
+    fn include_and_exclude_patterns() {
+        let workspace = sample_dir();
+        let inputs = [workspace.path().to_path_buf()];
+        let dir = &inputs[0];
+
+        let opts = InputOptions {
+            recursive: true,
+            include: vec![Glob::new("extras/**").unwrap()],
+            exclude: vec![Glob::new("**/skip.sup").unwrap()],
+        };
+        let files = find_inputs(&inputs, &opts).unwrap();
+        assert_eq!(relatives(&files), ["extras/b.sup", "extras/deep/c.sup"]);
+
+        // Relative to the directory input, not to the working directory
+        let opts = InputOptions {
+            recursive: true,
+            include: vec![Glob::new("*.sup").unwrap()],
+            exclude: vec![Glob::new("deep/*").unwrap()],
+        };
+        let files = find_inputs(&[dir.join("extras")], &opts).unwrap();
+        assert_eq!(relatives(&files), ["b.sup", "skip.sup"]);
+
+        let opts = InputOptions {
+            recursive: true,
+            include: vec![Glob::new("extras/**").unwrap()],
+            ..Default::default()
+        };
+        assert!(find_inputs(&[dir.join("extras")], &opts)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn file_inputs() {
+        let workspace = sample_dir();
+        let dir = workspace.path().to_path_buf();
+
+        // Given files are kept whatever their content, and found once
+        let inputs = [dir.join("index.ts"), dir.join("notes.txt"), dir.clone()];
+        let files = find_inputs(&inputs, &InputOptions::default()).unwrap();
+        assert_eq!(
+            relatives(&files),
+            ["index.ts", "B.SUP", "a.sup", "movie.m2ts", "subs.xml"]
+        );
+
+        let inputs = [dir.join("a.sup"), dir.join("a.sup")];
+        assert_eq!(
+            find_inputs(&inputs, &InputOptions::default())
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert!(find_inputs(&[dir.join("missing.sup")], &InputOptions::default()).is_err());
+    }
+}
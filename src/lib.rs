@@ -0,0 +1,22 @@
+//! Maps PGS subtitles to a different color/brightness.
+//!
+//! The usual pipeline is loading a [`pgs::PgsStream`], running [`tonemap`] on it and writing it back.
+
+pub mod bdn;
+pub mod bdsup2sub;
+pub mod classify;
+pub mod color;
+pub mod curve;
+mod error;
+pub mod input;
+pub mod m2ts;
+pub mod matroska;
+pub mod output;
+pub mod pgs;
+pub mod rules;
+pub mod stats;
+mod tonemap;
+pub mod workspace;
+
+pub use error::{Error, Result};
+pub use tonemap::*;
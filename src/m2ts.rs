@@ -0,0 +1,582 @@
+//! MPEG-2 transport stream (`.m2ts`, `.ts`) input, reassembling the PES packets of the PGS streams.
+//!
+//! Blu-ray streams carry every segment in its own PES packet, without the `PG` magic.
+//! The PTS and DTS come from the PES header.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::pgs::{add_headers, PgsStream};
+use crate::Result;
+
+pub const EXTENSIONS: &[&str] = &["m2ts", "mts", "ts"];
+
+/// PIDs of the presentation graphics streams on Blu-ray
+pub const PGS_PIDS: std::ops::RangeInclusive<u16> = 0x1200..=0x121F;
+/// Stream type of presentation graphics in the program map table
+pub const PGS_STREAM_TYPE: u8 = 0x90;
+
+const SYNC_BYTE: u8 = 0x47;
+const PACKET_SIZE: usize = 188;
+/// Blu-ray packets are prefixed by a 4 bytes arrival timestamp
+const M2TS_PACKET_SIZE: usize = 192;
+
+const PAT_PID: u16 = 0;
+const PAT_TABLE_ID: u8 = 0x00;
+const PMT_TABLE_ID: u8 = 0x02;
+
+#[derive(Debug, Clone)]
+pub struct TransportStream {
+    pub path: PathBuf,
+    packet_size: usize,
+    /// PIDs of the PGS streams listed in the program map tables
+    pub pids: Vec<u16>,
+}
+
+/// Transport packet, without its header and adaptation field
+struct Packet<'a> {
+    pid: u16,
+    /// Start of a PES packet or PSI section
+    unit_start: bool,
+    payload: &'a [u8],
+}
+
+/// Section of a PSI table being reassembled
+#[derive(Default)]
+struct Section {
+    data: Vec<u8>,
+}
+
+impl Section {
+    /// Adds a packet, returning the section once complete
+    fn push(&mut self, packet: &Packet) -> Option<Vec<u8>> {
+        if packet.unit_start {
+            let pointer = *packet.payload.first()? as usize;
+            self.data = packet.payload.get(1 + pointer..)?.to_vec();
+        } else if !self.data.is_empty() {
+            self.data.extend_from_slice(packet.payload);
+        }
+
+        let length =
+            3 + (u16::from_be_bytes([*self.data.get(1)?, *self.data.get(2)?]) & 0x0FFF) as usize;
+        if self.data.len() < length {
+            return None;
+        }
+
+        let mut section = std::mem::take(&mut self.data);
+        section.truncate(length);
+
+        Some(section)
+    }
+}
+
+impl TransportStream {
+    /// Detects the packet size and finds the PGS streams from the program map tables
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+
+        let packet_size = detect_packet_size(path)?
+            .ok_or_else(|| ts_error("Missing sync byte, not a transport stream"))?;
+
+        let mut stream = Self {
+            path: path.to_path_buf(),
+            packet_size,
+            pids: Vec::new(),
+        };
+        stream.pids = stream.find_pgs_pids()?;
+
+        Ok(stream)
+    }
+
+    /// Reads the PAT, then every PMT it lists
+    fn find_pgs_pids(&self) -> Result<Vec<u16>> {
+        let mut pat = Section::default();
+        let mut pmts: Vec<(u16, Section, bool)> = Vec::new();
+        let mut pids = Vec::new();
+
+        self.for_each_packet(|packet| {
+            if packet.pid == PAT_PID && pmts.is_empty() {
+                if let Some(section) = pat.push(&packet) {
+                    pmts = parse_pat(&section)?
+                        .into_iter()
+                        .map(|pid| (pid, Section::default(), false))
+                        .collect();
+                }
+            } else if let Some((_, section, done)) =
+                pmts.iter_mut().find(|(pid, _, _)| *pid == packet.pid)
+            {
+                if let Some(section) = section.push(&packet) {
+                    pids.extend(parse_pmt(&section)?);
+                    *done = true;
+                }
+            }
+
+            let complete = !pmts.is_empty() && pmts.iter().all(|(_, _, done)| *done);
+            Ok(if complete {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            })
+        })?;
+
+        if pmts.is_empty() {
+            return Err(ts_error("Missing program association table"));
+        }
+
+        pids.sort_unstable();
+        pids.dedup();
+
+        Ok(pids)
+    }
+
+    /// Reassembles the PES packets of the PIDs, returning one stream per PID in the same order
+    pub fn demux(&self, pids: &[u16]) -> Result<Vec<PgsStream>> {
+        let mut pes: Vec<Vec<u8>> = vec![Vec::new(); pids.len()];
+        let mut buffers = vec![Vec::new(); pids.len()];
+
+        self.for_each_packet(|packet| {
+            let Some(index) = pids.iter().position(|&pid| pid == packet.pid) else {
+                return Ok(ControlFlow::Continue(()));
+            };
+
+            if packet.unit_start {
+                let previous = std::mem::take(&mut pes[index]);
+                add_pes(&mut buffers[index], &previous, packet.pid)?;
+                pes[index] = packet.payload.to_vec();
+            } else if !pes[index].is_empty() {
+                pes[index].extend_from_slice(packet.payload);
+            }
+
+            Ok(ControlFlow::Continue(()))
+        })?;
+
+        for ((buffer, pes), &pid) in buffers.iter_mut().zip(&pes).zip(pids) {
+            add_pes(buffer, pes, pid)?;
+        }
+
+        buffers.iter().map(|data| PgsStream::parse(data)).collect()
+    }
+
+    fn for_each_packet<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(Packet) -> Result<ControlFlow<()>>,
+    {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut buf = vec![0; self.packet_size];
+        let header_offset = self.packet_size - PACKET_SIZE;
+        let mut offset = 0u64;
+
+        // A truncated last packet is ignored
+        while read_full(&mut reader, &mut buf)? == self.packet_size {
+            let packet = &buf[header_offset..];
+
+            if packet[0] != SYNC_BYTE {
+                return Err(ts_error(format!("Lost sync at offset {offset}")));
+            }
+
+            offset += self.packet_size as u64;
+
+            let transport_error = packet[1] & 0x80 != 0;
+            let unit_start = packet[1] & 0x40 != 0;
+            let pid = u16::from_be_bytes([packet[1], packet[2]]) & 0x1FFF;
+            let adaptation = packet[3] & 0x20 != 0;
+            let has_payload = packet[3] & 0x10 != 0;
+
+            if transport_error || !has_payload {
+                continue;
+            }
+
+            let payload_start = if adaptation {
+                5 + packet[4] as usize
+            } else {
+                4
+            };
+            let Some(payload) = packet.get(payload_start..) else {
+                continue;
+            };
+
+            let packet = Packet {
+                pid,
+                unit_start,
+                payload,
+            };
+            if f(packet)?.is_break() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// PMT PIDs of the programs of a PAT section
+fn parse_pat(section: &[u8]) -> Result<Vec<u16>> {
+    if section.first() != Some(&PAT_TABLE_ID) || section.len() < 12 {
+        return Err(ts_error("Invalid program association table"));
+    }
+
+    // Entries are between the 8 bytes header and the CRC
+    Ok(section[8..section.len() - 4]
+        .chunks_exact(4)
+        .filter(|entry| u16::from_be_bytes([entry[0], entry[1]]) != 0)
+        .map(|entry| u16::from_be_bytes([entry[2], entry[3]]) & 0x1FFF)
+        .collect())
+}
+
+/// PIDs of the PGS streams of a PMT section, by stream type or Blu-ray PID
+fn parse_pmt(section: &[u8]) -> Result<Vec<u16>> {
+    if section.first() != Some(&PMT_TABLE_ID) || section.len() < 16 {
+        return Err(ts_error("Invalid program map table"));
+    }
+
+    let program_info_length = (u16::from_be_bytes([section[10], section[11]]) & 0x0FFF) as usize;
+    let mut entries = section
+        .get(12 + program_info_length..section.len() - 4)
+        .ok_or_else(|| ts_error("Invalid program map table"))?;
+
+    let mut pids = Vec::new();
+    while entries.len() >= 5 {
+        let stream_type = entries[0];
+        let pid = u16::from_be_bytes([entries[1], entries[2]]) & 0x1FFF;
+        let info_length = (u16::from_be_bytes([entries[3], entries[4]]) & 0x0FFF) as usize;
+
+        if stream_type == PGS_STREAM_TYPE || PGS_PIDS.contains(&pid) {
+            pids.push(pid);
+        }
+
+        entries = entries.get(5 + info_length..).unwrap_or_default();
+    }
+
+    Ok(pids)
+}
+
+/// Adds the segments of a complete PES packet to a stream
+fn add_pes(buffer: &mut Vec<u8>, pes: &[u8], pid: u16) -> Result<()> {
+    if pes.is_empty() {
+        return Ok(());
+    }
+
+    if pes.len() < 9 || pes[..3] != [0, 0, 1] {
+        return Err(ts_error(format!("Invalid PES packet in PID {pid:#06X}")));
+    }
+
+    let length = u16::from_be_bytes([pes[4], pes[5]]) as usize;
+    let end = if length == 0 {
+        pes.len()
+    } else {
+        (6 + length).min(pes.len())
+    };
+
+    let flags = pes[7] >> 6;
+    let data_start = 9 + pes[8] as usize;
+    if data_start > end {
+        return Err(ts_error(format!("Truncated PES packet in PID {pid:#06X}")));
+    }
+
+    let pts = if flags & 0b10 != 0 {
+        read_timestamp(&pes[9..])
+    } else {
+        0
+    };
+    let dts = if flags == 0b11 {
+        read_timestamp(&pes[14..])
+    } else {
+        0
+    };
+
+    add_headers(buffer, &pes[data_start..end], pts, dts)
+        .map_err(|e| ts_error(format!("PID {pid:#06X}: {e}")))
+}
+
+/// Reads a 33 bits PES timestamp, truncated to the 32 bits of PGS
+fn read_timestamp(data: &[u8]) -> u32 {
+    if data.len() < 5 {
+        return 0;
+    }
+
+    let timestamp = ((data[0] as u64 >> 1) & 0x07) << 30
+        | (data[1] as u64) << 22
+        | (data[2] as u64 >> 1) << 15
+        | (data[3] as u64) << 7
+        | data[4] as u64 >> 1;
+
+    timestamp as u32
+}
+
+/// Size of the packets from the sync bytes at the start of the file, `None` if it has none
+fn detect_packet_size(path: &Path) -> io::Result<Option<usize>> {
+    let mut start = [0; M2TS_PACKET_SIZE + 5];
+    let read = read_full(&mut File::open(path)?, &mut start)?;
+    let start = &start[..read];
+
+    let is_sync = |offset: usize, size: usize| {
+        start.get(offset) == Some(&SYNC_BYTE)
+            && start.get(offset + size).map_or(true, |&b| b == SYNC_BYTE)
+    };
+
+    Ok(if is_sync(0, PACKET_SIZE) {
+        Some(PACKET_SIZE)
+    } else if is_sync(4, M2TS_PACKET_SIZE) {
+        Some(M2TS_PACKET_SIZE)
+    } else {
+        None
+    })
+}
+
+/// Fills `buf` as much as possible, returning the number of bytes read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(read)
+}
+
+/// Whether the path has a transport stream extension
+pub fn is_transport_stream(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+/// Whether the file starts with the sync bytes of transport stream packets
+pub fn has_sync_bytes(path: &Path) -> bool {
+    detect_packet_size(path).is_ok_and(|size| size.is_some())
+}
+
+fn ts_error<S: Into<String>>(message: S) -> Error {
+    Error::TransportStream(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::pgs::samples::{sample_stream, small_stream};
+    use crate::pgs::{Segment, SEGMENT_HEADER_SIZE};
+    use crate::workspace::Workspace;
+
+    const PMT_PID: u16 = 0x100;
+
+    /// Packets of a payload, the last one filled by the stuffing of an adaptation field
+    fn packets(pid: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packets = Vec::new();
+
+        for (i, chunk) in payload.chunks(PACKET_SIZE - 4).enumerate() {
+            let unit_start = if i == 0 { 0x40 } else { 0 };
+            packets.push(SYNC_BYTE);
+            packets.extend_from_slice(&(pid | (unit_start << 8)).to_be_bytes());
+
+            if chunk.len() == PACKET_SIZE - 4 {
+                packets.push(0x10);
+            } else {
+                let stuffing = PACKET_SIZE - 5 - chunk.len();
+                packets.push(0x30);
+                packets.push(stuffing as u8);
+                if stuffing > 0 {
+                    packets.push(0);
+                    packets.extend(std::iter::repeat(0xFF).take(stuffing - 1));
+                }
+            }
+
+            packets.extend_from_slice(chunk);
+        }
+
+        packets
+    }
+
+    /// PSI section with a pointer field, the CRC is not checked
+    fn section(table_id: u8, id: u16, body: &[u8]) -> Vec<u8> {
+        let length = (5 + body.len() + 4) as u16;
+
+        [
+            &[0, table_id][..],
+            &(0xB000 | length).to_be_bytes(),
+            &id.to_be_bytes(),
+            &[0xC1, 0, 0],
+            body,
+            &[0; 4],
+        ]
+        .concat()
+    }
+
+    fn pat() -> Vec<u8> {
+        // The network PID of program 0 is not a PMT
+        let body = [(0, 0x10), (1, PMT_PID)]
+            .iter()
+            .flat_map(|&(program, pid): &(u16, u16)| {
+                [program.to_be_bytes(), (0xE000 | pid).to_be_bytes()].concat()
+            })
+            .collect::<Vec<_>>();
+
+        section(PAT_TABLE_ID, 1, &body)
+    }
+
+    fn pmt() -> Vec<u8> {
+        let stream = |stream_type: u8, pid: u16, info: &[u8]| {
+            [
+                &[stream_type][..],
+                &(0xE000 | pid).to_be_bytes(),
+                &(0xF000 | info.len() as u16).to_be_bytes(),
+                info,
+            ]
+            .concat()
+        };
+
+        // PCR PID, then a program descriptor
+        let mut body = vec![0xF0, 0x11, 0xF0, 0x03, 0x05, 0x01, 0xAA];
+        body.extend(stream(0x1B, 0x1011, &[0x0A, 0x01, 0x00]));
+        body.extend(stream(0x81, 0x1100, &[]));
+        body.extend(stream(PGS_STREAM_TYPE, 0x1201, &[]));
+        // A Blu-ray PGS PID with another stream type
+        body.extend(stream(0x06, 0x1200, &[]));
+        body.extend(stream(PGS_STREAM_TYPE, 0x1300, &[0x0A, 0x01, 0x00]));
+
+        section(PMT_TABLE_ID, 1, &body)
+    }
+
+    /// 33 bits timestamp with its marker bits
+    fn timestamp(prefix: u8, value: u64) -> [u8; 5] {
+        [
+            prefix << 4 | ((value >> 29) & 0x0E) as u8 | 1,
+            (value >> 22) as u8,
+            ((value >> 14) & 0xFE) as u8 | 1,
+            (value >> 7) as u8,
+            ((value << 1) & 0xFE) as u8 | 1,
+        ]
+    }
+
+    /// PES packet of a segment, with `high` added to its timestamps
+    fn pes(segment: &Segment, high: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        segment.write(&mut bytes).unwrap();
+
+        let data = &bytes[SEGMENT_HEADER_SIZE - 3..];
+        let header = [
+            &[0x81, 0xC0, 10][..],
+            &timestamp(3, high + segment.pts as u64),
+            &timestamp(1, high + segment.dts as u64),
+        ]
+        .concat();
+
+        // Longer packets have an unbounded length
+        let length = header.len() + data.len();
+        let length = if length > u16::MAX as usize {
+            0
+        } else {
+            length
+        };
+
+        [
+            &[0, 0, 1, 0xBD][..],
+            &(length as u16).to_be_bytes(),
+            &header,
+            data,
+        ]
+        .concat()
+    }
+
+    /// Transport stream of two PGS streams, interleaved
+    fn sample_file(high: u64) -> Vec<u8> {
+        let mut file = [packets(PAT_PID, &pat()), packets(PMT_PID, &pmt())].concat();
+
+        let first = sample_stream();
+        let second = small_stream();
+        let mut first = first.segments();
+        let mut second = second.segments();
+
+        loop {
+            let (a, b) = (first.next(), second.next());
+            if a.is_none() && b.is_none() {
+                break;
+            }
+
+            if let Some(segment) = a {
+                file.extend(packets(0x1201, &pes(segment, high)));
+            }
+            if let Some(segment) = b {
+                file.extend(packets(0x1200, &pes(segment, high)));
+            }
+        }
+
+        file
+    }
+
+    #[test]
+    fn program_tables() {
+        let pat = pat();
+        assert_eq!(parse_pat(&pat[1..]).unwrap(), [PMT_PID]);
+
+        let pmt = pmt();
+        assert_eq!(parse_pmt(&pmt[1..]).unwrap(), [0x1201, 0x1200, 0x1300]);
+
+        assert!(parse_pat(&pmt[1..]).is_err());
+        assert!(parse_pmt(&pat[1..]).is_err());
+    }
+
+    #[test]
+    fn timestamps_are_truncated_to_32_bits() {
+        assert_eq!(read_timestamp(&timestamp(2, 90_000)), 90_000);
+        assert_eq!(read_timestamp(&timestamp(2, (1 << 33) - 1)), u32::MAX);
+        assert_eq!(read_timestamp(&timestamp(2, (1 << 32) + 5)), 5);
+        assert_eq!(read_timestamp(&[0x21, 0]), 0);
+    }
+
+    #[test]
+    fn demux_interleaved_streams() {
+        let workspace = Workspace::new(None, false).unwrap();
+
+        for (name, high, arrival) in [("a.ts", 0, false), ("b.m2ts", 1 << 32, true)] {
+            let mut file = sample_file(high);
+
+            // Packets without payload and with transport errors are skipped
+            let mut skipped = packets(0x1200, &[0; 10]);
+            skipped[3] = 0x20;
+            file.extend(skipped);
+            let mut skipped = packets(0x1200, &pes(&small_stream().display_sets[0].segments[0], 0));
+            skipped[1] |= 0x80;
+            file.extend(skipped);
+
+            if arrival {
+                file = file
+                    .chunks(PACKET_SIZE)
+                    .flat_map(|packet| [&[0; 4][..], packet].concat())
+                    .collect();
+            }
+
+            let path = workspace.path().join(name);
+            fs::write(&path, &file).unwrap();
+            assert!(has_sync_bytes(&path));
+
+            let ts = TransportStream::open(&path).unwrap();
+            assert_eq!(ts.packet_size, PACKET_SIZE + 4 * arrival as usize);
+            assert_eq!(ts.pids, [0x1200, 0x1201, 0x1300]);
+
+            let streams = ts.demux(&[0x1201, 0x1200, 0x1300]).unwrap();
+            assert_eq!(streams[0], sample_stream());
+            assert_eq!(streams[1], small_stream());
+            assert!(streams[2].display_sets.is_empty());
+        }
+    }
+
+    #[test]
+    fn files_without_sync_bytes() {
+        let workspace = Workspace::new(None, false).unwrap();
+        let path = workspace.path().join("index.ts");
+        fs::write(&path, "export const answer = 42;\n").unwrap();
+
+        assert!(!has_sync_bytes(&path));
+        assert!(TransportStream::open(&path).is_err());
+    }
+}
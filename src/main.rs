@@ -1,30 +1,96 @@
 use std::env;
 use std::fs;
-use std::path::Path;
-use std::path::PathBuf;
-use std::process::Command;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
 
-use clap::{Parser, ValueHint};
+use clap::{Parser, ValueEnum, ValueHint};
+use globset::Glob;
 use rayon::prelude::*;
+use regex::Regex;
+
+use subtitle_tonemap::bdn::{is_bdn, Bdn, FrameRate};
+use subtitle_tonemap::bdsup2sub::BdSup2Sub;
+use subtitle_tonemap::classify::{ClassOptions, Classification};
+use subtitle_tonemap::color::{hlg_signal_to_nits, parse_hex, LightnessModel, Matrix, Transfer};
+use subtitle_tonemap::curve::ToneCurve;
+use subtitle_tonemap::input::{find_inputs, read_list_file, InputFile, InputOptions};
+use subtitle_tonemap::m2ts::{is_transport_stream, TransportStream};
+use subtitle_tonemap::matroska::{is_matroska, Matroska, PgsTrack, RemuxMode, TrackSelection};
+use subtitle_tonemap::output::{OutputFormat, OutputOptions, OverwritePolicy};
+use subtitle_tonemap::pgs::PgsStream;
+use subtitle_tonemap::rules::{read_rules_file, ColorRule};
+use subtitle_tonemap::stats::Statistic;
+use subtitle_tonemap::workspace::{self, Workspace};
+use subtitle_tonemap::{
+    tonemap, tonemap_file, ForcedFilter, GamutMapping, Result, Scope, TonemapOptions,
+};
 
 #[derive(Parser, Debug)]
 #[command(name = env!("CARGO_PKG_NAME"), about = "Maps PGS subtitles to a different color/brightness", author = "quietvoid", version = env!("CARGO_PKG_VERSION"))]
 struct Opt {
     #[arg(
         id = "input",
-        help = "Input subtitle file or directory containing PGS subtitles",
+        help = "Input subtitle, BDN XML, Matroska or transport stream files, or directories containing them",
+        required_unless_present = "list",
+        value_hint = ValueHint::AnyPath
+    )]
+    inputs: Vec<PathBuf>,
+
+    #[arg(
+        short = 'l',
+        long,
+        help = "File containing a list of inputs, one per line",
         value_hint = ValueHint::FilePath
     )]
-    input: PathBuf,
+    list: Option<PathBuf>,
+
+    #[arg(
+        short = 'r',
+        long,
+        help = "Look for subtitles in the subdirectories of the input directories"
+    )]
+    recursive: bool,
+
+    #[arg(
+        long,
+        value_parser = Glob::new,
+        help = "Only process files matching the glob pattern, relative to the input directory. Can be repeated"
+    )]
+    include: Vec<Glob>,
+
+    #[arg(
+        long,
+        value_parser = Glob::new,
+        help = "Skip files matching the glob pattern, relative to the input directory. Can be repeated"
+    )]
+    exclude: Vec<Glob>,
 
     #[arg(
         short = 'o',
         long,
-        help = "Output directory",
-        value_hint = ValueHint::FilePath
+        help = "Output directory, mirroring the structure of the input directories. Defaults to next to the inputs",
+        value_hint = ValueHint::DirPath
+    )]
+    output: Option<PathBuf>,
+
+    #[arg(
+        short = 'n',
+        long,
+        help = "Output file name template, using the input's {stem}, {ext} and {name}. Defaults to the input file name, or {stem}.tonemapped.{ext} without --output"
+    )]
+    name: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OverwriteMode::Overwrite,
+        help = "What to do when the output file already exists"
     )]
-    output: PathBuf,
+    overwrite: OverwriteMode,
 
     #[arg(
         short = 'p',
@@ -44,242 +110,958 @@ struct Opt {
     #[arg(
         short = 'c',
         long,
+        value_parser = parse_hex_color,
         help = "Hexadecimal color value to use as base color for --fixed. RRGGBB"
     )]
-    color: Option<String>,
+    color: Option<[u8; 3]>,
+
+    #[arg(
+        long,
+        help = "With --fixed, keep the hue and saturation of the subtitle and only use the lightness of the base color. Implies --fixed"
+    )]
+    preserve_hue: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Gamut::Clip,
+        help = "What to do with colors that end up out of range after scaling"
+    )]
+    gamut: Gamut,
+
+    #[arg(
+        short = 't',
+        long,
+        conflicts_with = "percentage",
+        help = "Scale the PQ encoded subtitles so that the brightest color reaches this luminance in nits, instead of --percentage"
+    )]
+    target_nits: Option<f32>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = TransferFunction::Pq,
+        help = "Transfer function of the subtitles, for --target-nits"
+    )]
+    transfer: TransferFunction,
+
+    #[arg(
+        long,
+        default_value = "1000",
+        help = "Display peak luminance in nits, for the HLG system gamma"
+    )]
+    hlg_peak: f32,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["percentage", "target_nits"],
+        help = "Target level as a percentage of the HLG signal, implies --transfer hlg. 75 is the HLG reference white"
+    )]
+    hlg_level: Option<f32>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "YCbCr matrix of the subtitle palettes. Defaults to bt709, or bt2020 with --target-nits or --hlg-level"
+    )]
+    matrix: Option<ColorMatrix>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Model used to measure and scale the lightness of the colors. Defaults to hsl, or bt2020 with --target-nits"
+    )]
+    lightness: Option<Lightness>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Curve::Linear,
+        help = "Tone curve mapping the brightest color to --percentage or --target-nits"
+    )]
+    curve: Curve,
+
+    #[arg(long, default_value = "2.2", help = "Exponent of --curve gamma")]
+    gamma: f32,
+
+    #[arg(
+        long,
+        help = "Start of the roll-off of --curve soft-knee (default 0.75) and bt2390 (default from BT.2390), from 0 to 1"
+    )]
+    knee: Option<f32>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = NormalizeScope::Event,
+        help = "Subtitles sharing the same reference brightness"
+    )]
+    scope: NormalizeScope,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ReferenceStatistic::Max,
+        help = "Statistic of the subtitle colors used as reference brightness"
+    )]
+    statistic: ReferenceStatistic,
+
+    #[arg(
+        long,
+        default_value = "99.9",
+        help = "Percentile for --statistic percentile"
+    )]
+    percentile: f32,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ForcedMode::All,
+        help = "Subtitles to process according to their forced flag, the others are left untouched"
+    )]
+    forced: ForcedMode,
+
+    #[arg(
+        long,
+        help = "Check that the output keeps the exact timing of the input, failing the file otherwise"
+    )]
+    verify: bool,
+
+    #[arg(
+        long,
+        help = "Also write the forced subtitles to a separate {stem}.forced.sup file"
+    )]
+    split_forced: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Write Matroska inputs back as Matroska files, with the tonemapped track instead of a .sup"
+    )]
+    remux: Option<Remux>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Numbers of the Matroska tracks to process, every PGS track by default. Can be repeated"
+    )]
+    track: Vec<u64>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Only process the Matroska tracks in these languages, like eng,jpn"
+    )]
+    lang: Vec<String>,
+
+    #[arg(long, help = "Only process the Matroska tracks flagged as forced")]
+    forced_only: bool,
+
+    #[arg(
+        long,
+        value_parser = Regex::new,
+        help = "Only process the Matroska tracks whose name matches the regular expression"
+    )]
+    name_regex: Option<Regex>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_pid,
+        help = "PIDs of the PGS streams to process in transport streams, like 0x1200. Every PGS stream by default"
+    )]
+    pid: Vec<u16>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Format::Sup,
+        help = "Format of the outputs, a .sup file or a BDN XML with its PNG images"
+    )]
+    format: Format,
+
+    #[arg(
+        long,
+        value_parser = parse_frame_rate,
+        help = "Frame rate of the BDN outputs, like 23.976. Defaults to the frame rate of BDN inputs, or 23.976"
+    )]
+    fps: Option<FrameRate>,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Colors with an alpha at or below this value are considered transparent: ignored by the analysis and left untouched"
+    )]
+    alpha_threshold: u8,
+
+    #[arg(
+        long,
+        help = "Treat semi-transparent colors as premultiplied by their alpha, like anti-aliased edges often look"
+    )]
+    premultiplied: bool,
+
+    #[arg(
+        long,
+        default_value = "100",
+        help = "Percentage to multiply the opacity of the subtitle, independently from the color"
+    )]
+    opacity: f32,
+
+    #[arg(
+        long,
+        value_parser = ColorRule::from_str,
+        help = "Source to target color rule, SOURCE[:TOLERANCE]=TARGET[@PERCENTAGE]. The SOURCE color ends up at PERCENTAGE of TARGET. Can be repeated, the first matching rule is used"
+    )]
+    rule: Vec<ColorRule>,
+
+    #[arg(
+        long,
+        help = "File containing color rules, one per line",
+        value_hint = ValueHint::FilePath
+    )]
+    rules: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Percentage to multiply the fill of the subtitle, instead of --percentage"
+    )]
+    fill_percentage: Option<f32>,
+
+    #[arg(
+        long,
+        value_parser = parse_hex_color,
+        help = "Hexadecimal color value to use as base color for the fill. RRGGBB"
+    )]
+    fill_color: Option<[u8; 3]>,
+
+    #[arg(
+        long,
+        help = "Percentage to multiply the anti-aliased edges between the fill and the outline, instead of --percentage"
+    )]
+    edge_percentage: Option<f32>,
+
+    #[arg(
+        long,
+        value_parser = parse_hex_color,
+        help = "Hexadecimal color value to use as base color for the edges. RRGGBB"
+    )]
+    edge_color: Option<[u8; 3]>,
+
+    #[arg(
+        long,
+        help = "Percentage to multiply the outline of the subtitle, instead of --percentage"
+    )]
+    outline_percentage: Option<f32>,
+
+    #[arg(
+        long,
+        value_parser = parse_hex_color,
+        help = "Hexadecimal color value to use as base color for the outline. RRGGBB"
+    )]
+    outline_color: Option<[u8; 3]>,
+
+    #[arg(
+        long,
+        default_value = "75",
+        help = "Colors at least as light as this percentage of the brightest color are fill"
+    )]
+    fill_above: f32,
+
+    #[arg(
+        long,
+        default_value = "25",
+        help = "Colors darker than this percentage of the brightest color are outline"
+    )]
+    outline_below: f32,
+
+    #[arg(
+        short = 'm',
+        long,
+        value_enum,
+        default_value_t = Mode::Image,
+        help = "Processing mode"
+    )]
+    mode: Mode,
+
+    #[arg(
+        long,
+        help = "Directory for the temporary files of --mode bdsup2sub. Defaults to the system temporary directory",
+        value_hint = ValueHint::DirPath
+    )]
+    temp_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Keep the temporary files of --mode bdsup2sub, for debugging"
+    )]
+    keep_temp: bool,
 }
 
-fn main() -> std::io::Result<()> {
-    let now = Instant::now();
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /// Decodes the subtitle bitmaps and measures the pixels that are actually displayed
+    Image,
+    /// Edits the palette entries directly, leaving the bitmaps untouched
+    Palette,
+    /// Extracts the subtitles to PNG images with BDSup2Sub and edits every pixel
+    Bdsup2sub,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TransferFunction {
+    /// SMPTE ST 2084
+    Pq,
+    /// Hybrid log-gamma
+    Hlg,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorMatrix {
+    /// BT.709, for HD subtitles
+    Bt709,
+    /// BT.2020 non-constant luminance, for UHD subtitles
+    Bt2020,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Lightness {
+    /// HSL lightness, (max + min) / 2
+    Hsl,
+    /// BT.709 relative luminance
+    Bt709,
+    /// BT.2020 relative luminance
+    Bt2020,
+    /// CIELAB L*
+    Cielab,
+    /// ICtCp intensity
+    Ictcp,
+    /// Jzazbz lightness
+    Jzazbz,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Gamut {
+    /// Clips every channel separately, which can shift the hue
+    Clip,
+    /// Scales all the channels down by the same factor, reducing the lightness instead
+    Scale,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Curve {
+    /// Multiplies the lightness
+    Linear,
+    /// Power curve, set with --gamma
+    Gamma,
+    /// Keeps the colors below the target and clips the brighter ones
+    Clip,
+    /// Keeps the colors below --knee of the target and compresses the brighter ones
+    SoftKnee,
+    /// Extended Reinhard
+    Reinhard,
+    /// BT.2390 EETF, with the roll-off starting at --knee
+    Bt2390,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum NormalizeScope {
+    /// Every subtitle separately
+    Event,
+    /// Subtitles of the same epoch
+    Epoch,
+    /// The whole file
+    File,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ReferenceStatistic {
+    /// Brightest color
+    Max,
+    /// Percentile of the colors by pixel count and alpha, set with --percentile
+    Percentile,
+    /// Mean of the colors, weighted by pixel count and alpha
+    Mean,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ForcedMode {
+    /// Every subtitle
+    All,
+    /// Only the forced subtitles
+    Forced,
+    /// Only the subtitles that are not forced
+    NonForced,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Remux {
+    /// Replaces the original track
+    Replace,
+    /// Adds the tonemapped track after the original one
+    Add,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// PGS .sup file
+    Sup,
+    /// BDN XML with a PNG image per subtitle, named after the XML file
+    Bdn,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OverwriteMode {
+    /// Keep the existing file and skip the input
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Append a number to the output file name
+    Rename,
+}
+
+fn main() -> ExitCode {
     let opt = Opt::parse();
 
-    let mut working_dir = env::current_dir()?;
+    match run(opt) {
+        Ok(0) => ExitCode::SUCCESS,
+        Ok(failed) => {
+            eprintln!("{failed} subtitle(s) failed to tonemap");
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns the number of files that failed
+fn run(opt: Opt) -> Result<usize> {
+    let now = Instant::now();
 
-    // Make sure jar file exists in the same directory
-    let mut java_jar = env::current_exe()?;
-    java_jar.pop();
-    java_jar.push("BDSup2Sub512.jar");
-    assert!(
-        java_jar.exists(),
-        "BDSup2Sub should be in the same directory as this executable."
-    );
+    // Only the BDSup2Sub mode needs Java, and the jar file in the same directory
+    let bdsup2sub = if opt.mode == Mode::Bdsup2sub {
+        let mut bdsup2sub = BdSup2Sub::from_current_exe()?;
+        if !bdsup2sub.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "BDSup2Sub should be in the same directory as this executable.",
+            )
+            .into());
+        }
 
-    let input = opt.input;
-    let output = opt.output;
+        bdsup2sub.temp_dir = opt.temp_dir.clone();
+        bdsup2sub.keep_temp = opt.keep_temp;
+        Some(bdsup2sub)
+    } else {
+        None
+    };
+
+    if opt.mode == Mode::Bdsup2sub && opt.forced != ForcedMode::All {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--forced is not supported with --mode bdsup2sub",
+        )
+        .into());
+    }
+
+    if opt.mode == Mode::Bdsup2sub && opt.scope == NormalizeScope::Epoch {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--scope epoch is not supported with --mode bdsup2sub, the extracted images have no epochs",
+        )
+        .into());
+    }
+
+    if opt.format == Format::Bdn && opt.remux.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--remux is not supported with --format bdn",
+        )
+        .into());
+    }
+
+    if opt.format == Format::Bdn && opt.verify {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--verify is not supported with --format bdn, the timecodes are rounded to frames",
+        )
+        .into());
+    }
+
+    if opt.keep_temp {
+        let temp_dir = opt.temp_dir.clone().unwrap_or_else(env::temp_dir);
+        println!("Keeping temporary files in {}", temp_dir.display());
+    }
+
+    // Remove the temporary files when interrupted
+    ctrlc::set_handler(|| {
+        workspace::cleanup_all();
+        process::exit(130);
+    })
+    .map_err(io::Error::other)?;
 
     let ratio: f32 = opt.percentage / 100.0;
-    let mut fixed: bool = opt.fixed;
+    let mut fixed: bool = opt.fixed || opt.preserve_hue;
 
-    let color = if let Some(c) = opt.color {
+    let color = if let Some([rr, gg, bb]) = opt.color {
         fixed = true;
 
-        assert_eq!(c.len(), 6);
+        [rr as f32, gg as f32, bb as f32]
+    } else {
+        [255.0, 255.0, 255.0]
+    };
+
+    let transfer = match opt.transfer {
+        TransferFunction::Pq if opt.hlg_level.is_none() => Transfer::Pq,
+        _ => Transfer::Hlg {
+            peak_nits: opt.hlg_peak,
+        },
+    };
+    let target_nits = opt
+        .hlg_level
+        .map(|level| hlg_signal_to_nits(level / 100.0, opt.hlg_peak))
+        .or(opt.target_nits);
 
-        let rr = u8::from_str_radix(&c[0..2], 16).unwrap_or(255);
-        let gg = u8::from_str_radix(&c[2..4], 16).unwrap_or(255);
-        let bb = u8::from_str_radix(&c[4..6], 16).unwrap_or(255);
+    let mut rules = opt.rule;
+    if let Some(path) = &opt.rules {
+        rules.extend(read_rules_file(path)?);
+    }
 
-        vec![rr as f32, gg as f32, bb as f32]
-    } else {
-        vec![255.0, 255.0, 255.0]
+    let class_options = |percentage: Option<f32>, color: Option<[u8; 3]>| ClassOptions {
+        ratio: percentage.map(|p| p / 100.0),
+        color: color.map(|c| c.map(f32::from)),
     };
+    let fill = class_options(opt.fill_percentage, opt.fill_color);
+    let edge = class_options(opt.edge_percentage, opt.edge_color);
+    let outline = class_options(opt.outline_percentage, opt.outline_color);
 
-    working_dir.push(output.as_path());
+    let classification = [fill, edge, outline]
+        .iter()
+        .any(|class| *class != ClassOptions::default())
+        .then_some(Classification {
+            outline_below: opt.outline_below / 100.0,
+            fill_above: opt.fill_above / 100.0,
+            fill,
+            edge,
+            outline,
+        });
 
-    // Create output dir if it doesn't exist
-    if !output.exists() {
-        fs::create_dir(output)?;
+    let opts = TonemapOptions {
+        ratio,
+        fixed,
+        color,
+        preserve_hue: opt.preserve_hue,
+        gamut: match opt.gamut {
+            Gamut::Clip => GamutMapping::Clip,
+            Gamut::Scale => GamutMapping::Scale,
+        },
+        target_nits,
+        transfer,
+        matrix: opt.matrix.map(|matrix| match matrix {
+            ColorMatrix::Bt709 => Matrix::Bt709,
+            ColorMatrix::Bt2020 => Matrix::Bt2020,
+        }),
+        lightness: opt.lightness.map(|model| match model {
+            Lightness::Hsl => LightnessModel::Hsl,
+            Lightness::Bt709 => LightnessModel::Bt709,
+            Lightness::Bt2020 => LightnessModel::Bt2020,
+            Lightness::Cielab => LightnessModel::Cielab,
+            Lightness::Ictcp => LightnessModel::Ictcp,
+            Lightness::Jzazbz => LightnessModel::Jzazbz,
+        }),
+        curve: match opt.curve {
+            Curve::Linear => ToneCurve::Linear,
+            Curve::Gamma => ToneCurve::Gamma { gamma: opt.gamma },
+            Curve::Clip => ToneCurve::Clip,
+            Curve::SoftKnee => ToneCurve::SoftKnee {
+                knee: opt.knee.unwrap_or(0.75),
+            },
+            Curve::Reinhard => ToneCurve::Reinhard,
+            Curve::Bt2390 => ToneCurve::Bt2390 { knee: opt.knee },
+        },
+        scope: match opt.scope {
+            NormalizeScope::Event => Scope::Event,
+            NormalizeScope::Epoch => Scope::Epoch,
+            NormalizeScope::File => Scope::File,
+        },
+        statistic: match opt.statistic {
+            ReferenceStatistic::Max => Statistic::Max,
+            ReferenceStatistic::Percentile => Statistic::Percentile(opt.percentile),
+            ReferenceStatistic::Mean => Statistic::Mean,
+        },
+        alpha_threshold: opt.alpha_threshold,
+        premultiplied: opt.premultiplied,
+        opacity: opt.opacity / 100.0,
+        classification,
+        rules,
+        forced: match opt.forced {
+            ForcedMode::All => ForcedFilter::All,
+            ForcedMode::Forced => ForcedFilter::Forced,
+            ForcedMode::NonForced => ForcedFilter::NonForced,
+        },
+        mode: match opt.mode {
+            Mode::Palette => subtitle_tonemap::Mode::Palette,
+            Mode::Image | Mode::Bdsup2sub => subtitle_tonemap::Mode::Image,
+        },
+    };
+
+    let mut inputs = opt.inputs;
+    if let Some(list) = &opt.list {
+        inputs.extend(read_list_file(list)?);
     }
 
-    let mut files: Vec<PathBuf> = Vec::new();
-    if input.exists() {
-        if input.is_dir() {
-            files.extend(
-                input
-                    .read_dir()
-                    .expect("Couldn't read directory content")
-                    .filter_map(Result::ok)
-                    .filter(|e| e.metadata().expect("Couldn't get file metadata").is_file())
-                    .filter(|e| e.path().extension().expect("File has no extension") == "sup")
-                    .map(|e| {
-                        let mut path = PathBuf::from(&working_dir);
-                        path.pop();
-                        path.push(e.path());
-
-                        path
-                    }),
-            );
-        } else if input.extension().expect("File has no extension") == "sup" {
-            files.push(input);
+    let input_opts = InputOptions {
+        recursive: opt.recursive,
+        include: opt.include,
+        exclude: opt.exclude,
+    };
+    let files = find_inputs(&inputs, &input_opts)?;
+
+    let selection = TrackSelection {
+        numbers: opt.track,
+        languages: opt.lang,
+        forced_only: opt.forced_only,
+        name: opt.name_regex,
+    };
+    let remux = opt.remux.map(|remux| match remux {
+        Remux::Replace => RemuxMode::Replace,
+        Remux::Add => RemuxMode::Add,
+    });
+    let failed = AtomicUsize::new(0);
+
+    // Every selected track of a Matroska input is a separate input, unless remuxed together.
+    // Every selected stream of a transport stream is a separate input.
+    let mut inputs = Vec::new();
+    for file in files {
+        if is_transport_stream(&file.path) {
+            let pids: Vec<u16> = match TransportStream::open(&file.path) {
+                Ok(ts) => ts
+                    .pids
+                    .into_iter()
+                    .filter(|pid| opt.pid.is_empty() || opt.pid.contains(pid))
+                    .collect(),
+                Err(e) => {
+                    eprintln!("Failed to read {}: {e}", file.path.display());
+                    failed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+
+            if pids.is_empty() {
+                println!("Skipping {}, no PGS stream selected", file.path.display());
+            }
+
+            inputs.extend(pids.into_iter().map(|pid| InputFile {
+                pid: Some(pid),
+                ..file.clone()
+            }));
+            continue;
+        } else if !is_matroska(&file.path) {
+            inputs.push(file);
+            continue;
+        }
+
+        let tracks: Vec<PgsTrack> = match Matroska::open(&file.path) {
+            Ok(matroska) => matroska.select(&selection).into_iter().cloned().collect(),
+            Err(e) => {
+                eprintln!("Failed to read {}: {e}", file.path.display());
+                failed.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+
+        if tracks.is_empty() {
+            println!("Skipping {}, no PGS track selected", file.path.display());
+        } else if remux.is_some() {
+            inputs.push(InputFile { tracks, ..file });
+        } else {
+            inputs.extend(tracks.into_iter().map(|track| InputFile {
+                tracks: vec![track],
+                ..file.clone()
+            }));
         }
     }
+    let files = inputs;
+
+    let output_opts = OutputOptions {
+        dir: opt.output,
+        template: opt.name,
+        remux: remux.is_some(),
+        format: match opt.format {
+            Format::Sup => OutputFormat::Sup,
+            Format::Bdn => OutputFormat::Bdn,
+        },
+        overwrite: match opt.overwrite {
+            OverwriteMode::Skip => OverwritePolicy::Skip,
+            OverwriteMode::Overwrite => OverwritePolicy::Overwrite,
+            OverwriteMode::Rename => OverwritePolicy::Rename,
+        },
+        split_forced: opt.split_forced,
+    };
+    let out_files = output_opts.plan(&files);
 
     let total: u64 = files.len() as u64;
+    let split_forced = opt.split_forced;
+    let verify = opt.verify;
+    let format = output_opts.format;
+    let fps = opt.fps;
 
-    (0..files.len()).into_par_iter().for_each(|current| {
-        let file = &files[current];
+    let tonemap_stream = |input: &PgsStream| -> Result<PgsStream> {
+        match &bdsup2sub {
+            None => {
+                let mut stream = input.clone();
+                tonemap(&mut stream, &opts)?;
+                Ok(stream)
+            }
+            Some(bdsup2sub) => {
+                // BDSup2Sub only reads the track from a .sup file
+                let workspace = Workspace::new(bdsup2sub.temp_dir.as_deref(), bdsup2sub.keep_temp)?;
+                let sup = workspace.path().join("track.sup");
+                let tonemapped = workspace.path().join("tonemapped.sup");
 
-        println!("Tonemapping subtitle #{} of {}", current + 1, total);
-        extract_images(&java_jar, &working_dir, file, current)
-            .and_then(|out_file| process_images(out_file, ratio, fixed, &color))
-            .and_then(|timestamps| merge_images(&java_jar, &working_dir, file, timestamps))
-            .and_then(cleanup_images)
-            .ok();
-    });
+                input.write_to_path(&sup)?;
+                bdsup2sub.tonemap_file(&sup, &tonemapped, &opts)?;
+                PgsStream::from_path(&tonemapped)
+            }
+        }
+    };
 
-    println!("Done: {:#?} elapsed", now.elapsed());
+    // BDN outputs default to the frame rate of BDN inputs
+    let write_stream = |stream: &PgsStream, path: &Path, rate: Option<FrameRate>| match format {
+        OutputFormat::Sup => stream.write_to_path(path),
+        OutputFormat::Bdn => {
+            let frame_rate = fps.or(rate).unwrap_or_default();
+            Bdn::from_stream(stream, path, frame_rate, opts.matrix())?.write_to_path(path)
+        }
+    };
 
-    Ok(())
-}
+    // Verification reads the written file back, to check what actually ends up on disk
+    let written_stream = |stream: PgsStream, path: &Path| -> Result<PgsStream> {
+        if verify {
+            PgsStream::from_path(path)
+        } else {
+            Ok(stream)
+        }
+    };
 
-fn extract_images(
-    java_jar: &Path,
-    working_dir: &Path,
-    file: &Path,
-    index: usize,
-) -> Result<PathBuf, std::io::Error> {
-    let mut out_file = PathBuf::from(working_dir);
-    out_file.push(format!("sub{}", index));
-
-    if !out_file.exists() {
-        fs::create_dir(&out_file)?;
-    }
+    let process = |current: usize,
+                   input: &InputFile,
+                   out_file: Result<Option<PathBuf>>,
+                   demuxed: Option<PgsStream>| {
+        let file = &input.path;
 
-    out_file.push(format!("sub{}.xml", index));
-
-    let output = Command::new("java")
-        .args([
-            "-jar",
-            java_jar.to_str().unwrap(),
-            "-T",
-            "keep",
-            "-o",
-            out_file.to_str().unwrap(),
-            file.to_str().unwrap(),
-        ])
-        .output()
-        .expect("Failed to execute process");
-
-    if output.status.success() {
-        Ok(out_file)
-    } else {
-        panic!("Couldn't run imagex extraction");
-    }
-}
+        let res = out_file.and_then(|out_file| {
+            let Some(out_file) = out_file else {
+                println!("Skipping subtitle #{}, output already exists", current + 1);
+                return Ok(());
+            };
 
-fn process_images(
-    file: PathBuf,
-    ratio: f32,
-    fixed: bool,
-    color: &[f32],
-) -> Result<PathBuf, std::io::Error> {
-    let mut in_dir = PathBuf::from(&file);
-    in_dir.pop();
-
-    let images: Vec<PathBuf> = in_dir
-        .read_dir()
-        .expect("Couldn't read images directory")
-        .filter_map(Result::ok)
-        .filter(|e| e.path().extension().expect("File has no extension") == "png")
-        .map(|e| e.path())
-        .collect();
-
-    images
-        .par_iter()
-        .map(|i| {
-            let mut img = image::open(i).expect("Opening image failed").to_rgba8();
-            let old_max = img
-                .pixels()
-                .map(|p| {
-                    let image::Rgba(data) = *p;
-                    get_lightness(data[0] as f32, data[1] as f32, data[2] as f32)
-                })
-                .max_by(|x, y| x.abs().partial_cmp(&y.abs()).unwrap())
-                .unwrap();
-
-            img.pixels_mut()
-                .filter(|p| {
-                    let image::Rgba(data) = **p;
-
-                    data[0] > 1 && data[1] > 1 && data[2] > 1 && data[3] > 0
-                })
-                .for_each(|p| {
-                    let image::Rgba(mut data) = *p;
-
-                    if fixed {
-                        let src_lightness =
-                            get_lightness(data[0] as f32, data[1] as f32, data[2] as f32);
-
-                        let scale = (src_lightness * ratio) / old_max;
-
-                        let r = color[0] * scale;
-                        let g = color[1] * scale;
-                        let b = color[2] * scale;
-
-                        data[0] = r.round().clamp(0.0, color[0]) as u8;
-                        data[1] = g.round().clamp(0.0, color[1]) as u8;
-                        data[2] = b.round().clamp(0.0, color[2]) as u8;
-                    } else {
-                        data[0] = (data[0] as f32 * ratio).round().clamp(0.0, 255.0) as u8;
-                        data[1] = (data[1] as f32 * ratio).round().clamp(0.0, 255.0) as u8;
-                        data[2] = (data[2] as f32 * ratio).round().clamp(0.0, 255.0) as u8;
+            println!("Tonemapping subtitle #{} of {}", current + 1, total);
+
+            if let Some(parent) = out_file.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            // Input and output streams, with the path of their forced subtitles
+            let forced_paths = output_opts.forced_paths(input, &out_file);
+            let mut results = Vec::new();
+            let mut frame_rate = None;
+
+            if let Some(source) = demuxed {
+                match input.pid {
+                    Some(pid) => println!("Using PGS stream {pid:#06X} of {}", file.display()),
+                    None => println!("Using PGS {} of {}", input.tracks[0], file.display()),
+                }
+
+                let stream = tonemap_stream(&source)?;
+                write_stream(&stream, &out_file, frame_rate)?;
+                let stream = written_stream(stream, &out_file)?;
+
+                results.push((source, stream, forced_paths.clone()));
+            } else if is_bdn(file) {
+                let bdn = Bdn::from_path(file)?;
+                let source =
+                    bdn.to_stream(file.parent().unwrap_or(Path::new("")), opts.matrix())?;
+                frame_rate = Some(bdn.frame_rate);
+
+                let stream = tonemap_stream(&source)?;
+                write_stream(&stream, &out_file, frame_rate)?;
+                let stream = written_stream(stream, &out_file)?;
+
+                results.push((source, stream, forced_paths.clone()));
+            } else if input.tracks.is_empty() && format == OutputFormat::Bdn {
+                let source = PgsStream::from_path(file)?;
+
+                let stream = tonemap_stream(&source)?;
+                write_stream(&stream, &out_file, frame_rate)?;
+                let stream = written_stream(stream, &out_file)?;
+
+                results.push((source, stream, forced_paths.clone()));
+            } else if input.tracks.is_empty() {
+                match &bdsup2sub {
+                    None => tonemap_file(file, &out_file, &opts)?,
+                    Some(bdsup2sub) => bdsup2sub.tonemap_file(file, &out_file, &opts)?,
+                }
+
+                if verify || split_forced {
+                    results.push((
+                        PgsStream::from_path(file)?,
+                        PgsStream::from_path(&out_file)?,
+                        forced_paths.clone(),
+                    ));
+                }
+            } else {
+                let matroska = Matroska::open(file)?;
+                let numbers: Vec<u64> = input.tracks.iter().map(|t| t.number).collect();
+                let sources = matroska.demux(&numbers)?;
+
+                let streams = numbers
+                    .iter()
+                    .zip(&input.tracks)
+                    .zip(&sources)
+                    .map(|((&number, track), source)| {
+                        println!("Using PGS {track} of {}", file.display());
+                        Ok((number, tonemap_stream(source)?))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                let outputs = match remux {
+                    Some(remux) => {
+                        let numbers = matroska.remux(&out_file, &streams, remux)?;
+
+                        if verify || split_forced {
+                            Matroska::open(&out_file)?.demux(&numbers)?
+                        } else {
+                            Vec::new()
+                        }
+                    }
+                    None => {
+                        let (_, stream) = streams.into_iter().next().unwrap();
+                        write_stream(&stream, &out_file, frame_rate)?;
+                        vec![written_stream(stream, &out_file)?]
                     }
+                };
 
-                    *p = image::Rgba(data);
-                });
+                for (i, (source, output)) in sources.into_iter().zip(outputs).enumerate() {
+                    let forced = forced_paths.get(i).into_iter().cloned().collect();
+                    results.push((source, output, forced));
+                }
+            }
 
-            (i, img)
-        })
-        .for_each(|(path, img)| img.save(&path).unwrap());
+            for (source, output, forced) in results {
+                if verify {
+                    source.verify_timing(&output)?;
+                }
 
-    Ok(file)
-}
+                for forced in forced {
+                    write_stream(&output.forced_only(), &forced, frame_rate)?;
+                }
+            }
 
-fn merge_images(
-    java_jar: &Path,
-    working_dir: &Path,
-    file: &Path,
-    timestamps: PathBuf,
-) -> Result<PathBuf, std::io::Error> {
-    let mut out_file = PathBuf::from(&working_dir);
-    out_file.push(file.file_name().unwrap());
-
-    let output = Command::new("java")
-        .args([
-            "-jar",
-            java_jar.to_str().unwrap(),
-            "-T",
-            "keep",
-            "-o",
-            out_file.to_str().unwrap(),
-            timestamps.to_str().unwrap(),
-        ])
-        .output()
-        .expect("Failed to execute process");
-
-    if output.status.success() {
-        Ok(timestamps)
-    } else {
-        panic!("Couldn't run imagex extraction");
+            Ok(())
+        });
+
+        if let Err(e) = res {
+            eprintln!("Failed to tonemap {}: {e}", file.display());
+            failed.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+
+    // The selected streams of a transport stream are demuxed together, in one pass over the file,
+    // and so are the tracks of a Matroska file when they are not remuxed
+    let is_demuxed =
+        |input: &InputFile| input.pid.is_some() || (remux.is_none() && !input.tracks.is_empty());
+
+    let mut groups: Vec<Vec<(usize, &InputFile, _)>> = Vec::new();
+    for (current, (input, out_file)) in files.iter().zip(out_files).enumerate() {
+        match groups.last_mut() {
+            Some(group)
+                if is_demuxed(input) && is_demuxed(group[0].1) && group[0].1.path == input.path =>
+            {
+                group.push((current, input, out_file))
+            }
+            _ => groups.push(vec![(current, input, out_file)]),
+        }
     }
-}
 
-fn cleanup_images(dir: PathBuf) -> Result<PathBuf, std::io::Error> {
-    let mut dir_to_rm = PathBuf::from(&dir);
-    dir_to_rm.pop();
+    groups.into_par_iter().for_each(|group| {
+        let selected: Vec<&InputFile> = group
+            .iter()
+            .filter(|(_, input, out_file)| is_demuxed(input) && matches!(out_file, Ok(Some(_))))
+            .map(|(_, input, _)| *input)
+            .collect();
+
+        let mut streams = Vec::new().into_iter();
+        if let Some(first) = selected.first() {
+            let file = &first.path;
+
+            let demuxed = if first.pid.is_some() {
+                let pids: Vec<u16> = selected.iter().filter_map(|input| input.pid).collect();
+                TransportStream::open(file).and_then(|ts| ts.demux(&pids))
+            } else {
+                let numbers: Vec<u64> = selected
+                    .iter()
+                    .map(|input| input.tracks[0].number)
+                    .collect();
+                Matroska::open(file).and_then(|matroska| matroska.demux(&numbers))
+            };
+
+            match demuxed {
+                Ok(demuxed) => streams = demuxed.into_iter(),
+                Err(e) => {
+                    eprintln!("Failed to read {}: {e}", file.display());
+                    failed.fetch_add(selected.len(), Ordering::Relaxed);
+                }
+            }
+        }
+
+        let mut jobs = Vec::new();
+        for (current, input, out_file) in group {
+            let demuxed = if is_demuxed(input) && matches!(out_file, Ok(Some(_))) {
+                match streams.next() {
+                    Some(stream) => Some(stream),
+                    // Already counted as failed
+                    None => continue,
+                }
+            } else {
+                None
+            };
+
+            jobs.push((current, input, out_file, demuxed));
+        }
 
-    fs::remove_dir_all(dir_to_rm)?;
+        jobs.into_par_iter()
+            .for_each(|(current, input, out_file, demuxed)| {
+                process(current, input, out_file, demuxed)
+            });
+    });
 
-    Ok(dir)
+    println!("Done: {:#?} elapsed", now.elapsed());
+
+    Ok(failed.into_inner())
 }
 
-#[inline(always)]
-fn get_lightness(r: f32, g: f32, b: f32) -> f32 {
-    let rp = r / 255.0;
-    let gp = g / 255.0;
-    let bp = b / 255.0;
-    let cmax = rp.max(gp).max(bp);
-    let cmin = rp.min(gp).min(bp);
+fn parse_pid(value: &str) -> std::result::Result<u16, String> {
+    let pid = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+
+    pid.ok()
+        .filter(|&pid| pid <= 0x1FFF)
+        .ok_or_else(|| format!("invalid PID `{value}`, expected a number like 0x1200"))
+}
+
+fn parse_frame_rate(value: &str) -> std::result::Result<FrameRate, String> {
+    FrameRate::parse(value).ok_or_else(|| {
+        format!("invalid frame rate `{value}`, expected 23.976, 24, 25, 29.97, 50 or 59.94")
+    })
+}
 
-    (cmax + cmin) / 2.0
+fn parse_hex_color(value: &str) -> std::result::Result<[u8; 3], String> {
+    parse_hex(value).ok_or_else(|| format!("invalid color `{value}`, expected RRGGBB hexadecimal"))
 }
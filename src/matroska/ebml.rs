@@ -0,0 +1,305 @@
+//! Minimal EBML reading, enough to walk the Matroska elements.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::matroska_error;
+use crate::Result;
+
+/// Header of an element, the data starts right after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElementHeader {
+    /// ID with its length marker, as written in the specification
+    pub id: u32,
+    /// Data size, `None` when unknown
+    pub size: Option<u64>,
+    /// Position of the ID
+    pub start: u64,
+    /// Position of the data
+    pub data_start: u64,
+}
+
+impl ElementHeader {
+    /// Position right after the element, when its size is known
+    pub fn end(&self) -> Option<u64> {
+        self.size.map(|size| self.data_start + size)
+    }
+}
+
+pub struct EbmlReader<R> {
+    reader: R,
+}
+
+impl<R: Read + Seek> EbmlReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    pub fn position(&mut self) -> Result<u64> {
+        Ok(self.reader.stream_position()?)
+    }
+
+    pub fn seek(&mut self, position: u64) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(position))?;
+        Ok(())
+    }
+
+    /// Reads the next element header, `None` at the end of the file
+    pub fn read_header(&mut self) -> Result<Option<ElementHeader>> {
+        let start = self.position()?;
+
+        let mut first = [0; 1];
+        if self.reader.read(&mut first)? == 0 {
+            return Ok(None);
+        }
+
+        let id_len = first[0].leading_zeros() as usize + 1;
+        if id_len > 4 {
+            return Err(matroska_error(format!(
+                "Invalid element ID at offset {start}"
+            )));
+        }
+
+        let mut id = first[0] as u32;
+        for _ in 1..id_len {
+            id = (id << 8) | self.read_u8()? as u32;
+        }
+
+        let (size, size_len) = self.read_vint()?;
+        let unknown = size == (1 << (7 * size_len)) - 1;
+
+        Ok(Some(ElementHeader {
+            id,
+            size: (!unknown).then_some(size),
+            start,
+            data_start: self.position()?,
+        }))
+    }
+
+    /// Reads a variable size integer without its length marker, and its length in bytes
+    pub fn read_vint(&mut self) -> Result<(u64, usize)> {
+        let first = self.read_u8()?;
+        let len = first.leading_zeros() as usize + 1;
+
+        if len > 8 {
+            return Err(matroska_error("Invalid variable size integer"));
+        }
+
+        let mut value = (first as u64) & (0xFF >> len);
+        for _ in 1..len {
+            value = (value << 8) | self.read_u8()? as u64;
+        }
+
+        Ok((value, len))
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        let mut buf = [0; 1];
+        self.reader.read_exact(&mut buf)?;
+
+        Ok(buf[0])
+    }
+
+    pub fn read_uint(&mut self, header: &ElementHeader) -> Result<u64> {
+        let size = known_size(header)?;
+        if size > 8 {
+            return Err(matroska_error(format!(
+                "Integer element {:#X} is {size} bytes long",
+                header.id
+            )));
+        }
+
+        let mut value = 0;
+        for _ in 0..size {
+            value = (value << 8) | self.read_u8()? as u64;
+        }
+
+        Ok(value)
+    }
+
+    pub fn read_string(&mut self, header: &ElementHeader) -> Result<String> {
+        let bytes = self.read_bytes(header)?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+
+    pub fn read_bytes(&mut self, header: &ElementHeader) -> Result<Vec<u8>> {
+        let size = known_size(header)?;
+        let mut bytes = vec![0; size as usize];
+        self.reader.read_exact(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        Ok(self.reader.read_exact(buf)?)
+    }
+
+    /// Copies the next `len` bytes to `writer`
+    pub fn copy_to<W: Write>(&mut self, writer: &mut W, len: u64) -> Result<()> {
+        let copied = io::copy(&mut (&mut self.reader).take(len), writer)?;
+
+        if copied < len {
+            return Err(matroska_error("Unexpected end of file"));
+        }
+
+        Ok(())
+    }
+
+    pub fn skip(&mut self, header: &ElementHeader) -> Result<()> {
+        self.seek(known_size(header).map(|size| header.data_start + size)?)
+    }
+}
+
+fn known_size(header: &ElementHeader) -> Result<u64> {
+    header
+        .size
+        .ok_or_else(|| matroska_error(format!("Element {:#X} has an unknown size", header.id)))
+}
+
+/// Encodes a variable size integer on `len` bytes, or the fewest bytes that can hold it
+pub fn encode_vint(value: u64, len: Option<usize>) -> Result<Vec<u8>> {
+    // All ones is reserved for unknown sizes
+    let fits = |len: usize| value < (1 << (7 * len)) - 1;
+    let len = len.unwrap_or_else(|| (1..8).find(|&len| fits(len)).unwrap_or(8));
+
+    if len == 0 || len > 8 || !fits(len) {
+        return Err(matroska_error(format!(
+            "Value {value} does not fit in a {len} bytes integer"
+        )));
+    }
+
+    let marked = value | (1 << (7 * len));
+    Ok(marked.to_be_bytes()[8 - len..].to_vec())
+}
+
+/// Serializes an element ID as written in the specification
+pub fn encode_id(id: u32) -> Vec<u8> {
+    let bytes = id.to_be_bytes();
+    let start = bytes.iter().position(|&b| b != 0).unwrap_or(3);
+
+    bytes[start..].to_vec()
+}
+
+/// Element header, with the size on the fewest bytes
+pub fn encode_header(id: u32, size: u64) -> Result<Vec<u8>> {
+    Ok([encode_id(id), encode_vint(size, None)?].concat())
+}
+
+pub fn encode_element(id: u32, data: &[u8]) -> Result<Vec<u8>> {
+    Ok([encode_header(id, data.len() as u64)?, data.to_vec()].concat())
+}
+
+/// Unsigned integer element, on 8 bytes so that its size doesn't depend on the value
+pub fn encode_fixed_uint(id: u32, value: u64) -> Result<Vec<u8>> {
+    encode_element(id, &value.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn reader(bytes: &[u8]) -> EbmlReader<Cursor<&[u8]>> {
+        EbmlReader::new(Cursor::new(bytes))
+    }
+
+    #[test]
+    fn vints_of_every_length() {
+        assert_eq!(reader(&[0x81]).read_vint().unwrap(), (1, 1));
+        assert_eq!(reader(&[0x40, 0x7F]).read_vint().unwrap(), (127, 2));
+        assert_eq!(
+            reader(&[0x21, 0x00, 0x00]).read_vint().unwrap(),
+            (0x1_0000, 3)
+        );
+        assert_eq!(
+            reader(&[0x01, 0, 0, 0, 0, 0, 0x01, 0x02])
+                .read_vint()
+                .unwrap(),
+            (0x102, 8)
+        );
+
+        assert!(reader(&[0x00, 0x81]).read_vint().is_err());
+        assert!(reader(&[0x40]).read_vint().is_err());
+    }
+
+    #[test]
+    fn vints_are_encoded_on_the_fewest_bytes() {
+        assert_eq!(encode_vint(0, None).unwrap(), [0x80]);
+        assert_eq!(encode_vint(126, None).unwrap(), [0xFE]);
+        // All ones is the unknown size
+        assert_eq!(encode_vint(127, None).unwrap(), [0x40, 0x7F]);
+        assert_eq!(encode_vint(5, Some(8)).unwrap(), [1, 0, 0, 0, 0, 0, 0, 5]);
+        assert!(encode_vint(127, Some(1)).is_err());
+
+        for value in [0, 1, 126, 127, 16382, 16383, 1 << 40] {
+            let bytes = encode_vint(value, None).unwrap();
+            assert_eq!(
+                reader(&bytes).read_vint().unwrap(),
+                (value, bytes.len()),
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn element_headers() {
+        let bytes = [0x1A, 0x45, 0xDF, 0xA3, 0x84, 1, 2, 3, 4];
+        let header = reader(&bytes).read_header().unwrap().unwrap();
+
+        assert_eq!(
+            header,
+            ElementHeader {
+                id: 0x1A45_DFA3,
+                size: Some(4),
+                start: 0,
+                data_start: 5,
+            }
+        );
+        assert_eq!(header.end(), Some(9));
+        assert_eq!(encode_id(header.id), bytes[..4]);
+        assert_eq!(encode_header(0xA3, 4).unwrap(), [0xA3, 0x84]);
+
+        assert!(reader(&[]).read_header().unwrap().is_none());
+        assert!(reader(&[0x08, 0x81]).read_header().is_err());
+    }
+
+    #[test]
+    fn unknown_sizes() {
+        for bytes in [
+            &[0xA3, 0xFF][..],
+            &[0xA3, 0x7F, 0xFF],
+            &[0xA3, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+        ] {
+            let header = reader(bytes).read_header().unwrap().unwrap();
+
+            assert_eq!(header.size, None);
+            assert_eq!(header.data_start, bytes.len() as u64);
+            assert!(header.end().is_none());
+        }
+
+        let mut unknown = reader(&[0xE7, 0xFF, 1]);
+        let header = unknown.read_header().unwrap().unwrap();
+        assert!(unknown.read_uint(&header).is_err());
+    }
+
+    #[test]
+    fn element_values() {
+        let bytes = [encode_fixed_uint(0xD7, 300).unwrap(), vec![0x86, 0x84]].concat();
+        let bytes = [bytes, b"ab\0c".to_vec()].concat();
+        let mut reader = reader(&bytes);
+
+        let header = reader.read_header().unwrap().unwrap();
+        assert_eq!(header.size, Some(8));
+        assert_eq!(reader.read_uint(&header).unwrap(), 300);
+
+        let header = reader.read_header().unwrap().unwrap();
+        assert_eq!(reader.read_string(&header).unwrap(), "ab");
+
+        let long = [0xD7, 0x89, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let mut long_reader = EbmlReader::new(Cursor::new(&long[..]));
+        let header = long_reader.read_header().unwrap().unwrap();
+        assert!(long_reader.read_uint(&header).is_err());
+    }
+}
@@ -0,0 +1,706 @@
+//! Matroska (`.mkv`, `.mks`) input, demuxing the PGS subtitle tracks.
+//!
+//! The blocks of `S_HDMV/PGS` tracks contain the segments of a display set
+//! without their `PG` magic, PTS and DTS. The PTS comes from the block timestamp.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use regex::Regex;
+
+use crate::error::Error;
+use crate::pgs::{add_headers, PgsStream, Segment, SegmentType, SEGMENT_MAGIC};
+use crate::Result;
+
+mod ebml;
+mod remux;
+#[cfg(test)]
+mod samples;
+
+use ebml::{EbmlReader, ElementHeader};
+pub use remux::RemuxMode;
+
+pub const PGS_CODEC_ID: &str = "S_HDMV/PGS";
+pub const EXTENSIONS: &[&str] = &["mkv", "mks"];
+
+const EBML_HEADER: u32 = 0x1A45_DFA3;
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114D_9B74;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const CODEC_ID: u32 = 0x86;
+const LANGUAGE: u32 = 0x22_B59C;
+const LANGUAGE_BCP47: u32 = 0x22_B59D;
+const NAME: u32 = 0x536E;
+const FLAG_DEFAULT: u32 = 0x88;
+const FLAG_FORCED: u32 = 0x55AA;
+const CONTENT_ENCODINGS: u32 = 0x6D80;
+const CONTENT_ENCODING: u32 = 0x6240;
+const CONTENT_COMPRESSION: u32 = 0x5034;
+const CONTENT_COMP_ALGO: u32 = 0x4254;
+const CONTENT_COMP_SETTINGS: u32 = 0x4255;
+const CLUSTER: u32 = 0x1F43_B675;
+const CLUSTER_TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const BLOCK_GROUP: u32 = 0xA0;
+const BLOCK: u32 = 0xA1;
+const CUES: u32 = 0x1C53_BB6B;
+const CHAPTERS: u32 = 0x1043_A770;
+const TAGS: u32 = 0x1254_C367;
+const ATTACHMENTS: u32 = 0x1941_A469;
+
+/// Elements directly under the segment, which also end a cluster of unknown size
+const TOP_LEVEL: &[u32] = &[
+    SEEK_HEAD,
+    INFO,
+    TRACKS,
+    CLUSTER,
+    CUES,
+    CHAPTERS,
+    TAGS,
+    ATTACHMENTS,
+];
+
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zlib,
+    /// The settings bytes were removed from the start of every block
+    HeaderStripping,
+}
+
+/// A PGS subtitle track
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PgsTrack {
+    pub number: u64,
+    pub uid: u64,
+    /// Language code, the BCP 47 one when present
+    pub language: String,
+    pub name: Option<String>,
+    pub default: bool,
+    pub forced: bool,
+    pub compression: Option<(Compression, Vec<u8>)>,
+}
+
+impl fmt::Display for PgsTrack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "track {} ({}", self.number, self.language)?;
+
+        if let Some(name) = &self.name {
+            write!(f, ", {name:?}")?;
+        }
+        if self.default {
+            write!(f, ", default")?;
+        }
+        if self.forced {
+            write!(f, ", forced")?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+impl PgsTrack {
+    /// Undoes the content compression of a block
+    pub fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.compression {
+            None => Ok(data),
+            Some((Compression::HeaderStripping, stripped)) => {
+                Ok([stripped.as_slice(), &data].concat())
+            }
+            Some((Compression::Zlib, _)) => {
+                let mut decoded = Vec::new();
+                ZlibDecoder::new(data.as_slice()).read_to_end(&mut decoded)?;
+                Ok(decoded)
+            }
+        }
+    }
+
+    /// Applies the content compression of the track to a block
+    pub fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.compression {
+            None => Ok(data),
+            Some((Compression::HeaderStripping, stripped)) => data
+                .strip_prefix(stripped.as_slice())
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| {
+                    matroska_error(format!(
+                        "Block of track {} does not start with the stripped header",
+                        self.number
+                    ))
+                }),
+            Some((Compression::Zlib, _)) => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&data)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+}
+
+/// Criteria selecting PGS tracks, a track must match all of the ones that are set
+#[derive(Debug, Clone, Default)]
+pub struct TrackSelection {
+    /// Track numbers
+    pub numbers: Vec<u64>,
+    /// Language codes, `en` also matches the `en-US` tag
+    pub languages: Vec<String>,
+    /// Only the tracks flagged as forced
+    pub forced_only: bool,
+    /// Pattern searched in the track name
+    pub name: Option<Regex>,
+}
+
+impl TrackSelection {
+    pub fn matches(&self, track: &PgsTrack) -> bool {
+        let language = |code: &String| {
+            let primary = track.language.split('-').next().unwrap_or_default();
+
+            track.language.eq_ignore_ascii_case(code) || primary.eq_ignore_ascii_case(code)
+        };
+
+        (self.numbers.is_empty() || self.numbers.contains(&track.number))
+            && (self.languages.is_empty() || self.languages.iter().any(language))
+            && (!self.forced_only || track.forced)
+            && self.name.as_ref().map_or(true, |name| {
+                track.name.as_deref().is_some_and(|n| name.is_match(n))
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Matroska {
+    pub path: PathBuf,
+    /// Nanoseconds per timestamp tick
+    pub timestamp_scale: u64,
+    pub tracks: Vec<PgsTrack>,
+    /// Position of the first cluster
+    clusters_start: Option<u64>,
+}
+
+impl Matroska {
+    /// Reads the headers of the file, up to the first cluster
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut reader = EbmlReader::new(BufReader::new(File::open(path)?));
+
+        let segment = read_segment_header(&mut reader)?;
+
+        let mut matroska = Self {
+            path: path.to_path_buf(),
+            timestamp_scale: DEFAULT_TIMESTAMP_SCALE,
+            tracks: Vec::new(),
+            clusters_start: None,
+        };
+
+        while let Some(header) = next_child(&mut reader, &segment)? {
+            match header.id {
+                INFO => matroska.timestamp_scale = read_timestamp_scale(&mut reader, &header)?,
+                TRACKS => matroska.tracks = read_tracks(&mut reader, &header)?,
+                CLUSTER => {
+                    matroska.clusters_start = Some(header.start);
+                    break;
+                }
+                _ => reader.skip(&header)?,
+            }
+        }
+
+        Ok(matroska)
+    }
+
+    pub fn track(&self, number: u64) -> Option<&PgsTrack> {
+        self.tracks.iter().find(|t| t.number == number)
+    }
+
+    /// PGS tracks matching the selection, every track by default
+    pub fn select(&self, selection: &TrackSelection) -> Vec<&PgsTrack> {
+        self.tracks
+            .iter()
+            .filter(|t| selection.matches(t))
+            .collect()
+    }
+
+    /// Demuxes the PGS tracks, returning one stream per track number in the same order.
+    /// DTS are set to 0, like in the `.sup` files extracted by mkvextract.
+    pub fn demux(&self, numbers: &[u64]) -> Result<Vec<PgsStream>> {
+        let mut buffers = vec![Vec::new(); numbers.len()];
+
+        if let Some(clusters_start) = self.clusters_start {
+            let mut reader = EbmlReader::new(BufReader::new(File::open(&self.path)?));
+            let segment = read_segment_header(&mut reader)?;
+            reader.seek(clusters_start)?;
+
+            while let Some(header) = next_child(&mut reader, &segment)? {
+                if header.id == CLUSTER {
+                    self.read_cluster(&mut reader, &header, numbers, &mut buffers)?;
+                } else {
+                    reader.skip(&header)?;
+                }
+            }
+        }
+
+        buffers.iter().map(|data| PgsStream::parse(data)).collect()
+    }
+
+    fn read_cluster<R: Read + Seek>(
+        &self,
+        reader: &mut EbmlReader<R>,
+        cluster: &ElementHeader,
+        numbers: &[u64],
+        buffers: &mut [Vec<u8>],
+    ) -> Result<()> {
+        let mut cluster_timestamp = 0;
+
+        while let Some(header) = next_child(reader, cluster)? {
+            match header.id {
+                CLUSTER_TIMESTAMP => cluster_timestamp = reader.read_uint(&header)?,
+                SIMPLE_BLOCK => {
+                    self.read_block(reader, &header, cluster_timestamp, numbers, buffers)?
+                }
+                BLOCK_GROUP => {
+                    while let Some(child) = next_child(reader, &header)? {
+                        if child.id == BLOCK {
+                            self.read_block(reader, &child, cluster_timestamp, numbers, buffers)?;
+                        } else {
+                            reader.skip(&child)?;
+                        }
+                    }
+                }
+                _ => reader.skip(&header)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_block<R: Read + Seek>(
+        &self,
+        reader: &mut EbmlReader<R>,
+        block: &ElementHeader,
+        cluster_timestamp: u64,
+        numbers: &[u64],
+        buffers: &mut [Vec<u8>],
+    ) -> Result<()> {
+        let (number, _) = reader.read_vint()?;
+
+        let Some(index) = numbers.iter().position(|&n| n == number) else {
+            return reader.skip(block);
+        };
+        let track = self
+            .track(number)
+            .ok_or_else(|| matroska_error(format!("Track {number} is not a PGS track")))?;
+
+        let mut header = [0; 3];
+        reader.read_exact(&mut header)?;
+
+        let relative = i16::from_be_bytes([header[0], header[1]]);
+        let flags = header[2];
+        if flags & 0x06 != 0 {
+            return Err(matroska_error(format!(
+                "Laced blocks are not supported, in track {number}"
+            )));
+        }
+
+        let size = block
+            .end()
+            .and_then(|end| end.checked_sub(reader.position().ok()?))
+            .ok_or_else(|| matroska_error("Block is shorter than its header"))?;
+        let mut data = vec![0; size as usize];
+        reader.read_exact(&mut data)?;
+
+        let data = track.decode(data)?;
+
+        let timestamp = cluster_timestamp as i64 + relative as i64;
+        let pts = self.to_pts(timestamp);
+
+        add_headers(&mut buffers[index], &data, pts, 0)
+            .map_err(|e| matroska_error(format!("Track {number}: {e}")))
+    }
+
+    /// Converts a timestamp in ticks to a 90 kHz PTS
+    pub fn to_pts(&self, timestamp: i64) -> u32 {
+        let nanoseconds = timestamp.max(0) as u128 * self.timestamp_scale as u128;
+
+        ((nanoseconds * 9 + 50_000) / 100_000) as u32
+    }
+}
+
+/// Serializes segments without their `PG` magic, PTS and DTS, like in a block
+fn strip_headers<'a, I: IntoIterator<Item = &'a Segment>>(segments: I) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+
+    for segment in segments {
+        let mut bytes = Vec::new();
+        segment.write(&mut bytes)?;
+        data.extend_from_slice(&bytes[SEGMENT_MAGIC.len() + 8..]);
+    }
+
+    Ok(data)
+}
+
+/// Number of display sets a block completes, from its END segments
+fn count_display_sets(mut data: &[u8]) -> usize {
+    let mut count = 0;
+
+    while data.len() >= 3 {
+        if data[0] == SegmentType::End as u8 {
+            count += 1;
+        }
+
+        let size = u16::from_be_bytes([data[1], data[2]]) as usize;
+        data = &data[(3 + size).min(data.len())..];
+    }
+
+    count
+}
+
+fn read_segment_header<R: Read + Seek>(reader: &mut EbmlReader<R>) -> Result<ElementHeader> {
+    let header = reader
+        .read_header()?
+        .filter(|h| h.id == EBML_HEADER)
+        .ok_or_else(|| matroska_error("Missing EBML header"))?;
+    reader.skip(&header)?;
+
+    reader
+        .read_header()?
+        .filter(|h| h.id == SEGMENT)
+        .ok_or_else(|| matroska_error("Missing segment"))
+}
+
+/// Next child of `parent`, `None` at its end.
+/// Children of elements with an unknown size end at the next top level element.
+fn next_child<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    parent: &ElementHeader,
+) -> Result<Option<ElementHeader>> {
+    let position = reader.position()?;
+
+    if parent.end().is_some_and(|end| position >= end) {
+        return Ok(None);
+    }
+
+    let Some(header) = reader.read_header()? else {
+        return Ok(None);
+    };
+
+    if parent.size.is_none() && parent.id != SEGMENT && TOP_LEVEL.contains(&header.id) {
+        reader.seek(header.start)?;
+        return Ok(None);
+    }
+
+    Ok(Some(header))
+}
+
+fn read_timestamp_scale<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    info: &ElementHeader,
+) -> Result<u64> {
+    let mut scale = DEFAULT_TIMESTAMP_SCALE;
+
+    while let Some(header) = next_child(reader, info)? {
+        match header.id {
+            TIMESTAMP_SCALE => scale = reader.read_uint(&header)?,
+            _ => reader.skip(&header)?,
+        }
+    }
+
+    Ok(scale)
+}
+
+fn read_tracks<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    tracks: &ElementHeader,
+) -> Result<Vec<PgsTrack>> {
+    let mut pgs_tracks = Vec::new();
+
+    while let Some(header) = next_child(reader, tracks)? {
+        if header.id != TRACK_ENTRY {
+            reader.skip(&header)?;
+            continue;
+        }
+
+        let mut track = PgsTrack {
+            number: 0,
+            uid: 0,
+            language: "eng".to_owned(),
+            name: None,
+            default: true,
+            forced: false,
+            compression: None,
+        };
+        let mut codec = String::new();
+        let mut bcp47 = None;
+
+        while let Some(child) = next_child(reader, &header)? {
+            match child.id {
+                TRACK_NUMBER => track.number = reader.read_uint(&child)?,
+                TRACK_UID => track.uid = reader.read_uint(&child)?,
+                CODEC_ID => codec = reader.read_string(&child)?,
+                LANGUAGE => track.language = reader.read_string(&child)?,
+                LANGUAGE_BCP47 => bcp47 = Some(reader.read_string(&child)?),
+                NAME => track.name = Some(reader.read_string(&child)?),
+                FLAG_DEFAULT => track.default = reader.read_uint(&child)? != 0,
+                FLAG_FORCED => track.forced = reader.read_uint(&child)? != 0,
+                CONTENT_ENCODINGS => track.compression = read_compression(reader, &child)?,
+                _ => reader.skip(&child)?,
+            }
+        }
+
+        if codec == PGS_CODEC_ID {
+            if let Some(bcp47) = bcp47 {
+                track.language = bcp47;
+            }
+
+            pgs_tracks.push(track);
+        }
+    }
+
+    Ok(pgs_tracks)
+}
+
+fn read_compression<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    encodings: &ElementHeader,
+) -> Result<Option<(Compression, Vec<u8>)>> {
+    let mut compression = None;
+
+    while let Some(encoding) = next_child(reader, encodings)? {
+        if encoding.id != CONTENT_ENCODING {
+            reader.skip(&encoding)?;
+            continue;
+        }
+
+        while let Some(child) = next_child(reader, &encoding)? {
+            if child.id != CONTENT_COMPRESSION {
+                reader.skip(&child)?;
+                continue;
+            }
+
+            let mut algo = 0;
+            let mut settings = Vec::new();
+
+            while let Some(field) = next_child(reader, &child)? {
+                match field.id {
+                    CONTENT_COMP_ALGO => algo = reader.read_uint(&field)?,
+                    CONTENT_COMP_SETTINGS => settings = reader.read_bytes(&field)?,
+                    _ => reader.skip(&field)?,
+                }
+            }
+
+            compression = Some(match algo {
+                0 => (Compression::Zlib, settings),
+                3 => (Compression::HeaderStripping, settings),
+                algo => {
+                    return Err(matroska_error(format!(
+                        "Unsupported content compression algorithm {algo}"
+                    )))
+                }
+            });
+        }
+    }
+
+    Ok(compression)
+}
+
+/// Whether the path has a Matroska extension
+pub fn is_matroska(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+pub(crate) fn matroska_error<S: Into<String>>(message: S) -> Error {
+    Error::Matroska(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::samples::*;
+    use super::*;
+    use crate::pgs::samples::small_stream;
+    use crate::workspace::Workspace;
+
+    fn tracks() -> Vec<PgsTrack> {
+        let plain = pgs_track(2, "eng");
+        let zlib = PgsTrack {
+            name: Some("Signs".to_owned()),
+            default: false,
+            forced: true,
+            compression: Some((Compression::Zlib, Vec::new())),
+            ..pgs_track(3, "fr-CA")
+        };
+        // Every display set starts with its PCS
+        let stripped = PgsTrack {
+            compression: Some((Compression::HeaderStripping, vec![SegmentType::Pcs as u8])),
+            ..pgs_track(4, "jpn")
+        };
+
+        vec![plain, zlib, stripped]
+    }
+
+    /// The display sets of the small stream in every track, at 1 s intervals,
+    /// in a cluster of known size then in one of unknown size
+    fn sample_file(timestamp_scale: u64) -> SampleFile {
+        let stream = small_stream();
+        let ds = &stream.display_sets;
+        let tracks = tracks();
+        let ticks = |ms: u64| ms * 1_000_000 / timestamp_scale;
+
+        let cluster = |start: usize, known_size: bool| {
+            let mut blocks = vec![SampleBlock {
+                track: 1,
+                relative: 0,
+                payload: vec![0; 64],
+                group: false,
+            }];
+
+            for i in start..start + 2 {
+                let relative = ticks(1000 * (i - start) as u64) as i16;
+
+                for (n, track) in tracks.iter().enumerate() {
+                    // Some blocks of each track are in block groups
+                    let group = (i + n) % 2 == 1;
+                    blocks.push(pgs_block(track, relative, &ds[i..=i], group));
+                }
+            }
+
+            SampleCluster {
+                timestamp: ticks(1000 * (start as u64 + 1)),
+                blocks,
+                known_size,
+            }
+        };
+
+        let clusters = vec![cluster(0, true), cluster(2, false)];
+
+        let mut video = pgs_track(1, "und");
+        video.uid = 1;
+
+        let mut file_tracks = vec![(video, VIDEO_CODEC_ID)];
+        file_tracks.extend(tracks.into_iter().map(|t| (t, PGS_CODEC_ID)));
+
+        SampleFile {
+            timestamp_scale,
+            tracks: file_tracks,
+            clusters,
+            cues: vec![(0, 0), (1, 0)],
+        }
+    }
+
+    #[test]
+    fn demux_every_kind_of_block() {
+        let workspace = Workspace::new(None, false).unwrap();
+        let path = workspace.path().join("sample.mkv");
+
+        for scale in [1_000_000, 500_000] {
+            sample_file(scale).write(&path);
+
+            let matroska = Matroska::open(&path).unwrap();
+            assert_eq!(matroska.timestamp_scale, scale);
+            assert_eq!(matroska.tracks, tracks());
+
+            let streams = matroska.demux(&[4, 2, 3]).unwrap();
+            for stream in streams {
+                assert_eq!(stream, without_dts(small_stream()));
+            }
+        }
+    }
+
+    #[test]
+    fn timestamps_are_converted_to_90khz() {
+        let matroska = |timestamp_scale| Matroska {
+            path: PathBuf::new(),
+            timestamp_scale,
+            tracks: Vec::new(),
+            clusters_start: None,
+        };
+
+        assert_eq!(matroska(1_000_000).to_pts(1001), 90_090);
+        assert_eq!(matroska(100_000).to_pts(10), 90);
+        assert_eq!(matroska(1_000_000).to_pts(-5), 0);
+        // 11111 ns is 0.99999 ticks of 90 kHz
+        assert_eq!(matroska(1).to_pts(11_111), 1);
+        assert_eq!(matroska(1).to_pts(5_555), 0);
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        for track in tracks() {
+            let data = vec![SegmentType::Pcs as u8, 0, 2, 7, 7];
+            let encoded = track.encode(data.clone()).unwrap();
+
+            assert_eq!(track.decode(encoded).unwrap(), data, "{track}");
+        }
+
+        let stripped = &tracks()[2];
+        assert!(stripped.encode(vec![SegmentType::End as u8, 0, 0]).is_err());
+        assert_eq!(stripped.encode(vec![0x16, 1]).unwrap(), [1]);
+    }
+
+    #[test]
+    fn track_selection() {
+        let tracks = tracks();
+        let selected = |selection: TrackSelection| -> Vec<u64> {
+            tracks
+                .iter()
+                .filter(|t| selection.matches(t))
+                .map(|t| t.number)
+                .collect()
+        };
+
+        assert_eq!(selected(TrackSelection::default()), [2, 3, 4]);
+        assert_eq!(
+            selected(TrackSelection {
+                numbers: vec![4, 3, 9],
+                ..Default::default()
+            }),
+            [3, 4]
+        );
+        // The primary language matches the tag with a region, case-insensitively
+        assert_eq!(
+            selected(TrackSelection {
+                languages: vec!["FR".to_owned(), "jpn".to_owned()],
+                ..Default::default()
+            }),
+            [3, 4]
+        );
+        assert_eq!(
+            selected(TrackSelection {
+                languages: vec!["fr-ca".to_owned(), "en".to_owned()],
+                ..Default::default()
+            }),
+            [3]
+        );
+        assert_eq!(
+            selected(TrackSelection {
+                forced_only: true,
+                ..Default::default()
+            }),
+            [3]
+        );
+        // Tracks without a name never match a name pattern
+        assert_eq!(
+            selected(TrackSelection {
+                name: Some(Regex::new("(?i)sign").unwrap()),
+                ..Default::default()
+            }),
+            [3]
+        );
+        // Every criterion must match
+        assert!(selected(TrackSelection {
+            numbers: vec![3],
+            languages: vec!["jpn".to_owned()],
+            ..Default::default()
+        })
+        .is_empty());
+    }
+}
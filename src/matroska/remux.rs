@@ -0,0 +1,906 @@
+//! Copies a Matroska file with a PGS track replaced by a tonemapped stream, or added next to it.
+//!
+//! Only the blocks of the track, the track entries when adding and the positions
+//! in the seek heads and cues change, every other element is copied as is.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, Write};
+use std::path::Path;
+
+use super::ebml::{
+    encode_element, encode_fixed_uint, encode_header, encode_id, encode_vint, EbmlReader,
+    ElementHeader,
+};
+use super::*;
+use crate::pgs::DisplaySet;
+
+const CRC_32: u32 = 0xBF;
+pub(super) const SEEK: u32 = 0x4DBB;
+pub(super) const SEEK_POSITION: u32 = 0x53AC;
+pub(super) const CUE_POINT: u32 = 0xBB;
+pub(super) const CUE_TRACK_POSITIONS: u32 = 0xB7;
+pub(super) const CUE_CLUSTER_POSITION: u32 = 0xF1;
+pub(super) const CUE_RELATIVE_POSITION: u32 = 0xF0;
+pub(super) const CUE_BLOCK_NUMBER: u32 = 0x5378;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RemuxMode {
+    /// The tonemapped track replaces the original one
+    #[default]
+    Replace,
+    /// The tonemapped track is added after the original one
+    Add,
+}
+
+/// Element directly under the segment, with its actual end for unknown sizes
+struct TopLevel {
+    header: ElementHeader,
+    end: u64,
+    /// New data of the element, `None` when copied as is
+    data: Option<Content>,
+}
+
+enum Content {
+    Bytes(Vec<u8>),
+    Cluster(ClusterEdits),
+}
+
+/// Edited blocks of a cluster, in order
+struct ClusterEdits {
+    data_size: u64,
+    edits: Vec<BlockEdit>,
+}
+
+struct BlockEdit {
+    /// Position of the SimpleBlock or BlockGroup, relative to the cluster data
+    start: u64,
+    end: u64,
+    /// 1-based index of the block in the cluster
+    number: u64,
+    /// Element replacing the block, or added after it
+    element: Vec<u8>,
+}
+
+impl BlockEdit {
+    /// Size difference of the cluster data after this block
+    fn shift(&self, mode: RemuxMode) -> i64 {
+        match mode {
+            RemuxMode::Replace => self.element.len() as i64 - (self.end - self.start) as i64,
+            RemuxMode::Add => self.element.len() as i64,
+        }
+    }
+}
+
+impl ClusterEdits {
+    /// New position of an element of the cluster, relative to its data
+    fn position(&self, position: u64, mode: RemuxMode) -> u64 {
+        let shift: i64 = self
+            .edits
+            .iter()
+            .take_while(|edit| edit.start < position)
+            .map(|edit| edit.shift(mode))
+            .sum();
+
+        position.saturating_add_signed(shift)
+    }
+
+    /// New 1-based index of a block of the cluster
+    fn block_number(&self, number: u64, mode: RemuxMode) -> u64 {
+        match mode {
+            RemuxMode::Replace => number,
+            RemuxMode::Add => {
+                number
+                    + self
+                        .edits
+                        .iter()
+                        .filter(|edit| edit.number < number)
+                        .count() as u64
+            }
+        }
+    }
+}
+
+/// Track written from a tonemapped stream
+struct TrackPlan<'a> {
+    track: &'a PgsTrack,
+    /// Track number of the new blocks
+    number: u64,
+    display_sets: std::slice::Iter<'a, DisplaySet>,
+}
+
+/// State of the first pass, building the new blocks
+struct Planner<'a> {
+    tracks: Vec<TrackPlan<'a>>,
+    mode: RemuxMode,
+}
+
+impl Matroska {
+    /// Writes a copy of the file to `output`, with the blocks of every track number built from its stream.
+    /// A stream must have as many display sets as its track, which is the case for the native modes.
+    ///
+    /// Returns the number of the track holding each stream in the output.
+    pub fn remux<P: AsRef<Path>>(
+        &self,
+        output: P,
+        streams: &[(u64, PgsStream)],
+        mode: RemuxMode,
+    ) -> Result<Vec<u64>> {
+        let mut reader = EbmlReader::new(BufReader::new(File::open(&self.path)?));
+        let segment = read_segment_header(&mut reader)?;
+
+        let next_number = match mode {
+            RemuxMode::Replace => 0,
+            RemuxMode::Add => self.next_track_number(&mut reader, &segment)?,
+        };
+        reader.seek(segment.data_start)?;
+
+        let tracks = streams
+            .iter()
+            .zip(next_number..)
+            .map(|((number, stream), next_number)| {
+                let track = self
+                    .track(*number)
+                    .ok_or_else(|| matroska_error(format!("Track {number} is not a PGS track")))?;
+
+                Ok(TrackPlan {
+                    track,
+                    number: match mode {
+                        RemuxMode::Replace => track.number,
+                        RemuxMode::Add => next_number,
+                    },
+                    display_sets: stream.display_sets.iter(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        let mut planner = Planner { tracks, mode };
+
+        let mut elements = Vec::new();
+        while let Some(header) = next_child(&mut reader, &segment)? {
+            let data = match header.id {
+                TRACKS if mode == RemuxMode::Add => {
+                    Some(Content::Bytes(planner.add_tracks(&mut reader, &header)?))
+                }
+                CLUSTER => planner
+                    .plan_cluster(&mut reader, &header)?
+                    .map(Content::Cluster),
+                _ => {
+                    reader.skip(&header)?;
+                    None
+                }
+            };
+
+            elements.push(TopLevel {
+                header,
+                end: reader.position()?,
+                data,
+            });
+        }
+
+        for plan in &planner.tracks {
+            let remaining = plan.display_sets.len();
+            if remaining > 0 {
+                return Err(matroska_error(format!(
+                    "The tonemapped stream has {remaining} more display sets than track {}",
+                    plan.track.number
+                )));
+            }
+        }
+
+        let sizes: Vec<u64> = elements
+            .iter()
+            .map(|element| element_size(&mut reader, element, &HashMap::new(), mode))
+            .collect::<Result<_>>()?;
+
+        // Positions are relative to the segment data, the new segment size is on 8 bytes
+        let mut old_positions = HashMap::new();
+        let mut position = 0;
+        for (element, size) in elements.iter().zip(&sizes) {
+            old_positions.insert(
+                element.header.start - segment.data_start,
+                (position, element),
+            );
+            position += size;
+        }
+        let segment_size = position;
+
+        let mut writer = BufWriter::new(File::create(output)?);
+
+        reader.seek(0)?;
+        reader.copy_to(&mut writer, segment.start)?;
+        writer.write_all(&encode_id(SEGMENT))?;
+        writer.write_all(&encode_vint(segment_size, Some(8))?)?;
+
+        for element in &elements {
+            write_element(&mut reader, &mut writer, element, &old_positions, mode)?;
+        }
+
+        writer.flush()?;
+
+        Ok(planner.tracks.iter().map(|plan| plan.number).collect())
+    }
+
+    /// Number after the highest track number
+    fn next_track_number<R: Read + Seek>(
+        &self,
+        reader: &mut EbmlReader<R>,
+        segment: &ElementHeader,
+    ) -> Result<u64> {
+        let mut highest = 0;
+
+        while let Some(header) = next_child(reader, segment)? {
+            if header.id == CLUSTER {
+                break;
+            } else if header.id != TRACKS {
+                reader.skip(&header)?;
+                continue;
+            }
+
+            while let Some(entry) = next_child(reader, &header)? {
+                if entry.id != TRACK_ENTRY {
+                    reader.skip(&entry)?;
+                    continue;
+                }
+
+                while let Some(child) = next_child(reader, &entry)? {
+                    match child.id {
+                        TRACK_NUMBER => highest = highest.max(reader.read_uint(&child)?),
+                        _ => reader.skip(&child)?,
+                    }
+                }
+            }
+        }
+
+        Ok(highest + 1)
+    }
+}
+
+impl<'a> Planner<'a> {
+    /// Tracks element with a copy of the tonemapped track entries after the original ones
+    fn add_tracks<R: Read + Seek>(
+        &mut self,
+        reader: &mut EbmlReader<R>,
+        tracks: &ElementHeader,
+    ) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+
+        while let Some(entry) = next_child(reader, tracks)? {
+            let raw = read_element(reader, &entry)?;
+            if entry.id == CRC_32 {
+                continue;
+            }
+
+            data.extend_from_slice(&raw);
+
+            if entry.id != TRACK_ENTRY {
+                continue;
+            }
+
+            let mut number = None;
+            for_each_child(&raw, |header, value| {
+                if header.id == TRACK_NUMBER {
+                    number = Some(read_uint(value));
+                }
+                Ok(())
+            })?;
+
+            if let Some(plan) = self.plan(number) {
+                data.extend(plan.tonemapped_entry(&raw)?);
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn plan(&mut self, track: Option<u64>) -> Option<&mut TrackPlan<'a>> {
+        self.tracks
+            .iter_mut()
+            .find(|plan| Some(plan.track.number) == track)
+    }
+
+    /// Builds the new blocks of a cluster, `None` when it has none
+    fn plan_cluster<R: Read + Seek>(
+        &mut self,
+        reader: &mut EbmlReader<R>,
+        cluster: &ElementHeader,
+    ) -> Result<Option<ClusterEdits>> {
+        let mut edits = Vec::new();
+        let mut number = 0;
+
+        while let Some(header) = next_child(reader, cluster)? {
+            if header.id != SIMPLE_BLOCK && header.id != BLOCK_GROUP {
+                reader.skip(&header)?;
+                continue;
+            }
+
+            number += 1;
+
+            let track = if header.id == SIMPLE_BLOCK {
+                reader.read_vint()?.0
+            } else {
+                group_track(&read_element(reader, &header)?)?
+            };
+
+            let Some(plan) = self.plan(Some(track)) else {
+                reader.skip(&header)?;
+                continue;
+            };
+
+            let raw = read_element(reader, &header)?;
+            edits.push(BlockEdit {
+                start: header.start - cluster.data_start,
+                end: reader.position()? - cluster.data_start,
+                number,
+                element: plan.rebuild(header.id, &raw)?,
+            });
+        }
+
+        if edits.is_empty() && cluster.size.is_some() {
+            return Ok(None);
+        }
+
+        let old_size = reader.position()? - cluster.data_start;
+        let data_size = edits
+            .iter()
+            .fold(old_size as i64, |size, edit| size + edit.shift(self.mode));
+
+        Ok(Some(ClusterEdits {
+            data_size: data_size as u64,
+            edits,
+        }))
+    }
+}
+
+impl TrackPlan<'_> {
+    fn tonemapped_entry(&self, raw: &[u8]) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut has_name = false;
+
+        for_each_child(raw, |header, child| {
+            match header.id {
+                TRACK_NUMBER => data.extend(encode_fixed_uint(TRACK_NUMBER, self.number)?),
+                // Any unique value, derived from the original one
+                TRACK_UID => data.extend(encode_fixed_uint(
+                    TRACK_UID,
+                    self.track.uid.rotate_left(32) ^ self.number,
+                )?),
+                NAME => {
+                    has_name = true;
+                    let name = String::from_utf8_lossy(child);
+                    let name = format!("{} (tonemapped)", name.trim_end_matches('\0'));
+                    data.extend(encode_element(NAME, name.as_bytes())?);
+                }
+                // The default flag is added below, as it defaults to 1
+                FLAG_DEFAULT | CRC_32 => {}
+                _ => data.extend(encode_element(header.id, child)?),
+            }
+            Ok(())
+        })?;
+
+        if !has_name {
+            data.extend(encode_element(NAME, b"Tonemapped")?);
+        }
+        data.extend(encode_fixed_uint(FLAG_DEFAULT, 0)?);
+
+        encode_element(TRACK_ENTRY, &data)
+    }
+
+    /// SimpleBlock or BlockGroup with the next display sets of the stream
+    fn rebuild(&mut self, id: u32, raw: &[u8]) -> Result<Vec<u8>> {
+        let header = EbmlReader::new(Cursor::new(raw))
+            .read_header()?
+            .ok_or_else(|| matroska_error("Empty block"))?;
+        let data = &raw[header.data_start as usize..];
+
+        if id == SIMPLE_BLOCK {
+            return encode_element(SIMPLE_BLOCK, &self.rebuild_block(data)?);
+        }
+
+        let mut group = Vec::new();
+        for_each_child(raw, |header, child| {
+            match header.id {
+                BLOCK => group.extend(encode_element(BLOCK, &self.rebuild_block(child)?)?),
+                CRC_32 => {}
+                _ => group.extend(encode_element(header.id, child)?),
+            }
+            Ok(())
+        })?;
+
+        encode_element(BLOCK_GROUP, &group)
+    }
+
+    /// Block data with the same timestamp and flags, holding as many display sets as before
+    fn rebuild_block(&mut self, block: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = EbmlReader::new(Cursor::new(block));
+        let (_, track_len) = reader.read_vint()?;
+
+        let payload_start = track_len + 3;
+        if block.len() < payload_start {
+            return Err(matroska_error("Block is shorter than its header"));
+        }
+        if block[track_len + 2] & 0x06 != 0 {
+            return Err(matroska_error(format!(
+                "Laced blocks are not supported, in track {}",
+                self.track.number
+            )));
+        }
+
+        let count = count_display_sets(&self.track.decode(block[payload_start..].to_vec())?);
+        if count == 0 {
+            return Err(matroska_error(format!(
+                "Display sets split across blocks are not supported, in track {}",
+                self.track.number
+            )));
+        }
+
+        let display_sets: Vec<_> = self.display_sets.by_ref().take(count).collect();
+        if display_sets.len() < count {
+            return Err(matroska_error(format!(
+                "The tonemapped stream has fewer display sets than track {}",
+                self.track.number
+            )));
+        }
+
+        let segments = strip_headers(display_sets.iter().flat_map(|ds| &ds.segments))?;
+
+        Ok([
+            encode_vint(self.number, None)?,
+            block[track_len..payload_start].to_vec(),
+            self.track.encode(segments)?,
+        ]
+        .concat())
+    }
+}
+
+/// Track number of the block of a group
+fn group_track(raw: &[u8]) -> Result<u64> {
+    let mut track = 0;
+    for_each_child(raw, |header, data| {
+        if header.id == BLOCK {
+            track = EbmlReader::new(Cursor::new(data)).read_vint()?.0;
+        }
+        Ok(())
+    })?;
+
+    Ok(track)
+}
+
+/// Size of an element in the output, header included
+fn element_size<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    element: &TopLevel,
+    positions: &HashMap<u64, (u64, &TopLevel)>,
+    mode: RemuxMode,
+) -> Result<u64> {
+    let data_size = match (&element.data, element.header.id) {
+        (Some(Content::Bytes(data)), _) => data.len() as u64,
+        (Some(Content::Cluster(cluster)), _) => cluster.data_size,
+        // The positions are on 8 bytes, so that the size doesn't depend on them
+        (None, SEEK_HEAD | CUES) => {
+            rewrite_positions(reader, element, positions, mode)?.len() as u64
+        }
+        (None, _) => return Ok(element.end - element.header.start),
+    };
+
+    Ok(encode_header(element.header.id, data_size)?.len() as u64 + data_size)
+}
+
+fn write_element<R: Read + Seek, W: Write>(
+    reader: &mut EbmlReader<R>,
+    writer: &mut W,
+    element: &TopLevel,
+    positions: &HashMap<u64, (u64, &TopLevel)>,
+    mode: RemuxMode,
+) -> Result<()> {
+    let header = &element.header;
+
+    match (&element.data, header.id) {
+        (Some(Content::Bytes(data)), _) => writer.write_all(&encode_element(header.id, data)?)?,
+        (Some(Content::Cluster(cluster)), _) => {
+            writer.write_all(&encode_header(header.id, cluster.data_size)?)?;
+            reader.seek(header.data_start)?;
+
+            let mut position = 0;
+            for edit in &cluster.edits {
+                reader.copy_to(writer, edit.start - position)?;
+
+                match mode {
+                    RemuxMode::Replace => reader.seek(header.data_start + edit.end)?,
+                    RemuxMode::Add => reader.copy_to(writer, edit.end - edit.start)?,
+                }
+                writer.write_all(&edit.element)?;
+
+                position = edit.end;
+            }
+
+            reader.copy_to(writer, element.end - header.data_start - position)?;
+        }
+        (None, SEEK_HEAD | CUES) => {
+            let data = rewrite_positions(reader, element, positions, mode)?;
+            writer.write_all(&encode_element(header.id, &data)?)?;
+        }
+        (None, _) => {
+            reader.seek(header.start)?;
+            reader.copy_to(writer, element.end - header.start)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Seek head or cues data with their positions updated, and without CRC-32
+fn rewrite_positions<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    element: &TopLevel,
+    positions: &HashMap<u64, (u64, &TopLevel)>,
+    mode: RemuxMode,
+) -> Result<Vec<u8>> {
+    reader.seek(element.header.start)?;
+    let raw = read_element(reader, &element.header)?;
+
+    // Unknown positions are left as they were
+    let top_level = |old: u64| positions.get(&old).map_or(old, |(new, _)| *new);
+    let cluster_edits = |old: u64| match positions.get(&old) {
+        Some((
+            _,
+            TopLevel {
+                data: Some(Content::Cluster(edits)),
+                ..
+            },
+        )) => Some(edits),
+        _ => None,
+    };
+
+    let mut data = Vec::new();
+    for_each_child(&raw, |header, child| {
+        match header.id {
+            SEEK => {
+                let mut seek = Vec::new();
+                for_each_child_data(child, |header, value| {
+                    match header.id {
+                        SEEK_POSITION => seek.extend(encode_fixed_uint(
+                            SEEK_POSITION,
+                            top_level(read_uint(value)),
+                        )?),
+                        CRC_32 => {}
+                        _ => seek.extend(encode_element(header.id, value)?),
+                    }
+                    Ok(())
+                })?;
+                data.extend(encode_element(SEEK, &seek)?);
+            }
+            CUE_POINT => {
+                let mut point = Vec::new();
+                for_each_child_data(child, |header, value| {
+                    match header.id {
+                        CUE_TRACK_POSITIONS => point.extend(encode_element(
+                            CUE_TRACK_POSITIONS,
+                            &rewrite_cue(value, &top_level, &cluster_edits, mode)?,
+                        )?),
+                        CRC_32 => {}
+                        _ => point.extend(encode_element(header.id, value)?),
+                    }
+                    Ok(())
+                })?;
+                data.extend(encode_element(CUE_POINT, &point)?);
+            }
+            CRC_32 => {}
+            _ => data.extend(encode_element(header.id, child)?),
+        }
+        Ok(())
+    })?;
+
+    Ok(data)
+}
+
+fn rewrite_cue<'a>(
+    positions: &[u8],
+    top_level: &dyn Fn(u64) -> u64,
+    cluster_edits: &dyn Fn(u64) -> Option<&'a ClusterEdits>,
+    mode: RemuxMode,
+) -> Result<Vec<u8>> {
+    let mut cluster = None;
+    for_each_child_data(positions, |header, value| {
+        if header.id == CUE_CLUSTER_POSITION {
+            cluster = Some(read_uint(value));
+        }
+        Ok(())
+    })?;
+    let edits = cluster.and_then(cluster_edits);
+
+    let mut data = Vec::new();
+    for_each_child_data(positions, |header, value| {
+        let value_or = |f: &dyn Fn(&ClusterEdits, u64) -> u64| {
+            let old = read_uint(value);
+            edits.map_or(old, |edits| f(edits, old))
+        };
+
+        match header.id {
+            CUE_CLUSTER_POSITION => data.extend(encode_fixed_uint(
+                CUE_CLUSTER_POSITION,
+                top_level(read_uint(value)),
+            )?),
+            CUE_RELATIVE_POSITION => data.extend(encode_fixed_uint(
+                CUE_RELATIVE_POSITION,
+                value_or(&|edits, old| edits.position(old, mode)),
+            )?),
+            CUE_BLOCK_NUMBER => data.extend(encode_fixed_uint(
+                CUE_BLOCK_NUMBER,
+                value_or(&|edits, old| edits.block_number(old, mode)),
+            )?),
+            CRC_32 => {}
+            _ => data.extend(encode_element(header.id, value)?),
+        }
+        Ok(())
+    })?;
+
+    Ok(data)
+}
+
+fn read_element<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    header: &ElementHeader,
+) -> Result<Vec<u8>> {
+    reader.seek(header.start)?;
+
+    let end = header
+        .end()
+        .ok_or_else(|| matroska_error(format!("Element {:#X} has an unknown size", header.id)))?;
+    let mut raw = vec![0; (end - header.start) as usize];
+    reader.read_exact(&mut raw)?;
+
+    Ok(raw)
+}
+
+/// Calls `f` with the header and data of every child of a complete element
+fn for_each_child<F>(raw: &[u8], f: F) -> Result<()>
+where
+    F: FnMut(&ElementHeader, &[u8]) -> Result<()>,
+{
+    let header = EbmlReader::new(Cursor::new(raw))
+        .read_header()?
+        .ok_or_else(|| matroska_error("Empty element"))?;
+
+    for_each_child_data(&raw[header.data_start as usize..], f)
+}
+
+/// Calls `f` with the header and data of every element of `data`
+fn for_each_child_data<F>(data: &[u8], mut f: F) -> Result<()>
+where
+    F: FnMut(&ElementHeader, &[u8]) -> Result<()>,
+{
+    let mut reader = EbmlReader::new(Cursor::new(data));
+
+    while let Some(header) = reader.read_header()? {
+        let end = header
+            .end()
+            .filter(|&end| end <= data.len() as u64)
+            .ok_or_else(|| matroska_error(format!("Truncated element {:#X}", header.id)))?;
+
+        f(&header, &data[header.data_start as usize..end as usize])?;
+        reader.seek(end)?;
+    }
+
+    Ok(())
+}
+
+fn read_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |value, &b| (value << 8) | b as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::samples::*;
+    use super::*;
+    use crate::pgs::samples::small_stream;
+    use crate::pgs::Object;
+    use crate::workspace::Workspace;
+
+    const MAX_2_BYTES_SIZE: u64 = 16382;
+
+    fn tracks() -> Vec<PgsTrack> {
+        let zlib = PgsTrack {
+            compression: Some((Compression::Zlib, Vec::new())),
+            ..pgs_track(3, "fre")
+        };
+
+        vec![pgs_track(2, "eng"), zlib]
+    }
+
+    fn video(relative: i16, len: usize) -> SampleBlock {
+        SampleBlock {
+            track: 1,
+            relative,
+            payload: vec![0x55; len],
+            group: false,
+        }
+    }
+
+    /// The small stream with larger objects
+    fn tonemapped_stream() -> PgsStream {
+        let mut stream = small_stream();
+        let bitmap: Vec<u8> = (0..60 * 40).map(|i| (i * 13 % 7) as u8).collect();
+        let object = Object::from_bitmap(0, 0, 60, 40, &bitmap);
+
+        for ds in &mut stream.display_sets {
+            if ds.objects().next().is_some() {
+                ds.set_object(&object).unwrap();
+            }
+        }
+
+        stream
+    }
+
+    /// A first cluster just small enough for a 2 bytes size, a cluster of unknown size
+    /// and a cluster without subtitles, with cues on video and subtitle blocks after edited blocks
+    fn sample_file() -> SampleFile {
+        let stream = small_stream();
+        let ds = &stream.display_sets;
+        let [plain, zlib] = &tracks()[..] else {
+            unreachable!()
+        };
+
+        let first = |padding: usize| SampleCluster {
+            timestamp: 1000,
+            blocks: vec![
+                video(0, padding),
+                pgs_block(plain, 0, &ds[0..1], false),
+                pgs_block(zlib, 0, &ds[0..1], true),
+                pgs_block(plain, 1000, &ds[1..2], false),
+                pgs_block(zlib, 1000, &ds[1..2], false),
+                video(1500, 10),
+            ],
+            known_size: true,
+        };
+        let data_size = |cluster: &SampleCluster| {
+            let (element, _) = cluster.element();
+            let mut reader = EbmlReader::new(Cursor::new(element));
+            reader.read_header().unwrap().unwrap().size.unwrap()
+        };
+
+        let mut padding = (MAX_2_BYTES_SIZE - data_size(&first(0))) as usize;
+        while data_size(&first(padding)) > MAX_2_BYTES_SIZE {
+            padding -= 1;
+        }
+
+        let second = SampleCluster {
+            timestamp: 3000,
+            blocks: vec![
+                pgs_block(plain, 0, &ds[2..3], true),
+                video(0, 100),
+                pgs_block(zlib, 0, &ds[2..3], false),
+                pgs_block(plain, 1000, &ds[3..4], false),
+                video(1000, 100),
+                pgs_block(zlib, 1000, &ds[3..4], false),
+            ],
+            known_size: false,
+        };
+        let third = SampleCluster {
+            timestamp: 5000,
+            blocks: vec![video(0, 100)],
+            known_size: true,
+        };
+
+        let mut file_tracks = vec![(pgs_track(1, "und"), VIDEO_CODEC_ID)];
+        file_tracks.extend(tracks().into_iter().map(|t| (t, PGS_CODEC_ID)));
+
+        SampleFile {
+            timestamp_scale: 1_000_000,
+            tracks: file_tracks,
+            clusters: vec![first(padding), second, third],
+            cues: vec![(0, 5), (1, 1), (1, 3), (1, 4), (2, 0)],
+        }
+    }
+
+    /// Size of the size of the first cluster
+    fn first_cluster_size_len(path: &Path) -> u64 {
+        let matroska = Matroska::open(path).unwrap();
+        let mut reader = EbmlReader::new(BufReader::new(File::open(path).unwrap()));
+        reader.seek(matroska.clusters_start.unwrap()).unwrap();
+
+        let header = reader.read_header().unwrap().unwrap();
+        header.data_start - header.start - 4
+    }
+
+    /// Every cue and seek head entry points to its element
+    fn assert_index(path: &Path) {
+        let targets = cue_targets(path);
+        assert_eq!(targets.len(), 5);
+
+        for target in targets {
+            assert_eq!(target.at_position, (target.track, target.time));
+            assert_eq!(target.at_number, (target.track, target.time));
+        }
+
+        for (id, found) in seek_targets(path) {
+            assert_eq!(found, id);
+        }
+    }
+
+    fn remux(mode: RemuxMode) -> (Workspace, PathBuf, Vec<u64>) {
+        let workspace = Workspace::new(None, false).unwrap();
+        let input = workspace.path().join("input.mkv");
+        let output = workspace.path().join("output.mkv");
+
+        sample_file().write(&input);
+        assert_eq!(first_cluster_size_len(&input), 2);
+        assert_index(&input);
+
+        let streams = [(2, tonemapped_stream()), (3, tonemapped_stream())];
+        let numbers = Matroska::open(&input)
+            .unwrap()
+            .remux(&output, &streams, mode)
+            .unwrap();
+
+        (workspace, output, numbers)
+    }
+
+    #[test]
+    fn replace_tracks() {
+        let (_workspace, output, numbers) = remux(RemuxMode::Replace);
+        assert_eq!(numbers, [2, 3]);
+
+        let matroska = Matroska::open(&output).unwrap();
+        assert_eq!(matroska.tracks, tracks());
+        for stream in matroska.demux(&[2, 3]).unwrap() {
+            assert_eq!(stream, without_dts(tonemapped_stream()));
+        }
+
+        assert_eq!(first_cluster_size_len(&output), 3);
+        assert_index(&output);
+    }
+
+    #[test]
+    fn add_tracks() {
+        let (_workspace, output, numbers) = remux(RemuxMode::Add);
+        assert_eq!(numbers, [4, 5]);
+
+        let matroska = Matroska::open(&output).unwrap();
+        let original = tracks();
+        let numbers: Vec<u64> = matroska.tracks.iter().map(|t| t.number).collect();
+        assert_eq!(numbers, [2, 4, 3, 5]);
+
+        for (track, added) in original
+            .iter()
+            .zip([&matroska.tracks[1], &matroska.tracks[3]])
+        {
+            assert_eq!(matroska.track(track.number), Some(track));
+            assert_eq!(added.name.as_deref(), Some("Tonemapped"));
+            assert!(!added.default);
+            assert_ne!(added.uid, track.uid);
+            assert_eq!(added.language, track.language);
+            assert_eq!(added.compression, track.compression);
+        }
+
+        let streams = matroska.demux(&[2, 3, 4, 5]).unwrap();
+        for stream in &streams[..2] {
+            assert_eq!(stream, &without_dts(small_stream()));
+        }
+        for stream in &streams[2..] {
+            assert_eq!(stream, &without_dts(tonemapped_stream()));
+        }
+
+        assert_eq!(first_cluster_size_len(&output), 3);
+        assert_index(&output);
+    }
+
+    #[test]
+    fn display_set_count_must_match() {
+        let workspace = Workspace::new(None, false).unwrap();
+        let input = workspace.path().join("input.mkv");
+        sample_file().write(&input);
+
+        let mut shorter = small_stream();
+        shorter.display_sets.pop();
+        let mut longer = small_stream();
+        longer.display_sets.push(longer.display_sets[1].clone());
+
+        let matroska = Matroska::open(&input).unwrap();
+        for stream in [shorter, longer] {
+            let output = workspace.path().join("output.mkv");
+            assert!(matroska
+                .remux(&output, &[(2, stream)], RemuxMode::Replace)
+                .is_err());
+        }
+    }
+}
@@ -0,0 +1,400 @@
+//! Small Matroska files for the tests of the demuxer and the remuxer.
+
+use std::collections::HashMap;
+use std::fs;
+
+use super::ebml::{encode_element, encode_fixed_uint, encode_id, encode_vint};
+use super::remux::{
+    CUE_BLOCK_NUMBER, CUE_CLUSTER_POSITION, CUE_POINT, CUE_RELATIVE_POSITION, CUE_TRACK_POSITIONS,
+    SEEK, SEEK_POSITION,
+};
+use super::*;
+use crate::pgs::DisplaySet;
+
+const DOC_TYPE: u32 = 0x4282;
+const SEEK_ID: u32 = 0x53AB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK: u32 = 0xF7;
+
+pub const VIDEO_CODEC_ID: &str = "V_MPEG4/ISO/AVC";
+
+pub struct SampleBlock {
+    pub track: u64,
+    pub relative: i16,
+    pub payload: Vec<u8>,
+    /// Written as a BlockGroup instead of a SimpleBlock
+    pub group: bool,
+}
+
+pub struct SampleCluster {
+    pub timestamp: u64,
+    pub blocks: Vec<SampleBlock>,
+    /// Written with an unknown size when false
+    pub known_size: bool,
+}
+
+pub struct SampleFile {
+    pub timestamp_scale: u64,
+    /// Tracks with their codec ID
+    pub tracks: Vec<(PgsTrack, &'static str)>,
+    pub clusters: Vec<SampleCluster>,
+    /// Cluster and block index of the cued blocks
+    pub cues: Vec<(usize, usize)>,
+}
+
+/// Block where a cue points, with its track number and absolute timestamp
+#[derive(Debug, PartialEq, Eq)]
+pub struct CueTarget {
+    pub track: u64,
+    pub time: u64,
+    /// Block at the cluster and relative positions
+    pub at_position: (u64, u64),
+    /// Block with the block number in the cluster
+    pub at_number: (u64, u64),
+}
+
+pub fn pgs_track(number: u64, language: &str) -> PgsTrack {
+    PgsTrack {
+        number,
+        uid: 1000 + number,
+        language: language.to_owned(),
+        name: None,
+        default: true,
+        forced: false,
+        compression: None,
+    }
+}
+
+/// Demuxed streams have a DTS of 0
+pub fn without_dts(mut stream: PgsStream) -> PgsStream {
+    for ds in &mut stream.display_sets {
+        ds.segments.iter_mut().for_each(|s| s.dts = 0);
+    }
+    stream
+}
+
+/// Block holding display sets, without their headers like in a Matroska track
+pub fn pgs_block(
+    track: &PgsTrack,
+    relative: i16,
+    display_sets: &[DisplaySet],
+    group: bool,
+) -> SampleBlock {
+    let data = strip_headers(display_sets.iter().flat_map(|ds| &ds.segments)).unwrap();
+
+    SampleBlock {
+        track: track.number,
+        relative,
+        payload: track.encode(data).unwrap(),
+        group,
+    }
+}
+
+/// Unsigned integer element on the fewest bytes
+fn uint(id: u32, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|&b| b != 0).unwrap_or(7);
+
+    encode_element(id, &bytes[start..]).unwrap()
+}
+
+impl SampleBlock {
+    fn element(&self) -> Vec<u8> {
+        let header = [
+            encode_vint(self.track, None).unwrap(),
+            self.relative.to_be_bytes().to_vec(),
+        ]
+        .concat();
+
+        if self.group {
+            let block = [header, vec![0], self.payload.clone()].concat();
+            encode_element(BLOCK_GROUP, &encode_element(BLOCK, &block).unwrap()).unwrap()
+        } else {
+            let block = [header, vec![0x80], self.payload.clone()].concat();
+            encode_element(SIMPLE_BLOCK, &block).unwrap()
+        }
+    }
+}
+
+impl SampleCluster {
+    /// The cluster and the positions of its blocks relative to its data
+    pub fn element(&self) -> (Vec<u8>, Vec<u64>) {
+        let mut data = uint(CLUSTER_TIMESTAMP, self.timestamp);
+        let mut positions = Vec::new();
+
+        for block in &self.blocks {
+            positions.push(data.len() as u64);
+            data.extend(block.element());
+        }
+
+        let element = if self.known_size {
+            encode_element(CLUSTER, &data).unwrap()
+        } else {
+            [
+                encode_id(CLUSTER),
+                vec![0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+                data,
+            ]
+            .concat()
+        };
+
+        (element, positions)
+    }
+}
+
+impl SampleFile {
+    fn track_entry(track: &PgsTrack, codec: &str) -> Vec<u8> {
+        let mut data = [
+            uint(TRACK_NUMBER, track.number),
+            uint(TRACK_UID, track.uid),
+            encode_element(CODEC_ID, codec.as_bytes()).unwrap(),
+            uint(FLAG_DEFAULT, track.default as u64),
+            uint(FLAG_FORCED, track.forced as u64),
+        ]
+        .concat();
+
+        if track.language.contains('-') {
+            data.extend(encode_element(LANGUAGE_BCP47, track.language.as_bytes()).unwrap());
+        } else {
+            data.extend(encode_element(LANGUAGE, track.language.as_bytes()).unwrap());
+        }
+        if let Some(name) = &track.name {
+            data.extend(encode_element(NAME, name.as_bytes()).unwrap());
+        }
+        if let Some((compression, settings)) = &track.compression {
+            let algo = match compression {
+                Compression::Zlib => 0,
+                Compression::HeaderStripping => 3,
+            };
+            let compression = [
+                uint(CONTENT_COMP_ALGO, algo),
+                encode_element(CONTENT_COMP_SETTINGS, settings).unwrap(),
+            ]
+            .concat();
+            let encoding = encode_element(CONTENT_COMPRESSION, &compression).unwrap();
+            let encodings = encode_element(CONTENT_ENCODING, &encoding).unwrap();
+
+            data.extend(encode_element(CONTENT_ENCODINGS, &encodings).unwrap());
+        }
+
+        encode_element(TRACK_ENTRY, &data).unwrap()
+    }
+
+    /// Seek head and cues, with the positions relative to the segment data
+    fn index(&self, offsets: &[u64], blocks: &[Vec<u64>]) -> (Vec<u8>, Vec<u8>) {
+        let seek = |id: u32, position: u64| {
+            let data = [
+                encode_element(SEEK_ID, &encode_id(id)).unwrap(),
+                encode_fixed_uint(SEEK_POSITION, position).unwrap(),
+            ]
+            .concat();
+            encode_element(SEEK, &data).unwrap()
+        };
+        let cues_offset = offsets[offsets.len() - 1];
+        let seek_head = [
+            seek(INFO, offsets[1]),
+            seek(TRACKS, offsets[2]),
+            seek(CUES, cues_offset),
+        ]
+        .concat();
+
+        let mut cues = Vec::new();
+        for &(cluster, block) in &self.cues {
+            let sample = &self.clusters[cluster];
+            let positions = [
+                uint(CUE_TRACK, sample.blocks[block].track),
+                encode_fixed_uint(CUE_CLUSTER_POSITION, offsets[3 + cluster]).unwrap(),
+                encode_fixed_uint(CUE_RELATIVE_POSITION, blocks[cluster][block]).unwrap(),
+                encode_fixed_uint(CUE_BLOCK_NUMBER, block as u64 + 1).unwrap(),
+            ]
+            .concat();
+            let time = sample.timestamp as i64 + sample.blocks[block].relative as i64;
+            let point = [
+                encode_fixed_uint(CUE_TIME, time as u64).unwrap(),
+                encode_element(CUE_TRACK_POSITIONS, &positions).unwrap(),
+            ]
+            .concat();
+
+            cues.extend(encode_element(CUE_POINT, &point).unwrap());
+        }
+
+        (
+            encode_element(SEEK_HEAD, &seek_head).unwrap(),
+            encode_element(CUES, &cues).unwrap(),
+        )
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let ebml =
+            encode_element(EBML_HEADER, &encode_element(DOC_TYPE, b"matroska").unwrap()).unwrap();
+        let info = encode_element(INFO, &uint(TIMESTAMP_SCALE, self.timestamp_scale)).unwrap();
+        let entries: Vec<u8> = self
+            .tracks
+            .iter()
+            .flat_map(|(track, codec)| Self::track_entry(track, codec))
+            .collect();
+        let tracks = encode_element(TRACKS, &entries).unwrap();
+        let (clusters, blocks): (Vec<_>, Vec<_>) =
+            self.clusters.iter().map(SampleCluster::element).unzip();
+
+        // The positions are on 8 bytes, so the index has the same size with any position
+        let placeholder = vec![0; self.clusters.len() + 4];
+        let (seek_head, cues) = self.index(&placeholder, &blocks);
+
+        let mut elements = vec![seek_head, info, tracks];
+        elements.extend(clusters);
+        elements.push(cues);
+
+        let offsets: Vec<u64> = elements
+            .iter()
+            .scan(0, |offset, element| {
+                let start = *offset;
+                *offset += element.len() as u64;
+                Some(start)
+            })
+            .collect();
+        let (seek_head, cues) = self.index(&offsets, &blocks);
+        let last = elements.len() - 1;
+        elements[0] = seek_head;
+        elements[last] = cues;
+
+        [ebml, encode_element(SEGMENT, &elements.concat()).unwrap()].concat()
+    }
+
+    pub fn write(&self, path: &Path) {
+        fs::write(path, self.to_bytes()).unwrap();
+    }
+}
+
+/// Track number and absolute timestamp of a SimpleBlock or BlockGroup
+fn read_block_at<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    header: &ElementHeader,
+    cluster_timestamp: u64,
+) -> (u64, u64) {
+    let block = if header.id == BLOCK_GROUP {
+        std::iter::from_fn(|| next_child(reader, header).unwrap())
+            .find(|child| child.id == BLOCK)
+            .unwrap()
+    } else {
+        assert_eq!(header.id, SIMPLE_BLOCK);
+        *header
+    };
+
+    reader.seek(block.data_start).unwrap();
+    let (track, _) = reader.read_vint().unwrap();
+    let mut relative = [0; 2];
+    reader.read_exact(&mut relative).unwrap();
+    reader.seek(header.end().unwrap()).unwrap();
+
+    let timestamp = cluster_timestamp as i64 + i16::from_be_bytes(relative) as i64;
+    (track, timestamp as u64)
+}
+
+/// Follows every cue of a file to the blocks it points to
+pub fn cue_targets(path: &Path) -> Vec<CueTarget> {
+    let mut reader = EbmlReader::new(BufReader::new(File::open(path).unwrap()));
+    let segment = read_segment_header(&mut reader).unwrap();
+
+    let mut cues = None;
+    while let Some(header) = next_child(&mut reader, &segment).unwrap() {
+        if header.id == CUES {
+            cues = Some(header);
+        }
+
+        if header.size.is_some() {
+            reader.skip(&header).unwrap();
+        } else {
+            while let Some(child) = next_child(&mut reader, &header).unwrap() {
+                reader.skip(&child).unwrap();
+            }
+        }
+    }
+    let cues = cues.expect("Missing cues");
+
+    let mut points = Vec::new();
+    reader.seek(cues.data_start).unwrap();
+    while let Some(point) = next_child(&mut reader, &cues).unwrap() {
+        let mut fields = HashMap::new();
+        while let Some(child) = next_child(&mut reader, &point).unwrap() {
+            if child.id == CUE_TRACK_POSITIONS {
+                while let Some(field) = next_child(&mut reader, &child).unwrap() {
+                    fields.insert(field.id, reader.read_uint(&field).unwrap());
+                }
+            } else {
+                fields.insert(child.id, reader.read_uint(&child).unwrap());
+            }
+        }
+        points.push(fields);
+    }
+
+    points
+        .into_iter()
+        .map(|fields| {
+            reader
+                .seek(segment.data_start + fields[&CUE_CLUSTER_POSITION])
+                .unwrap();
+            let cluster = reader.read_header().unwrap().unwrap();
+            assert_eq!(cluster.id, CLUSTER);
+
+            let mut timestamp = 0;
+            let mut blocks = Vec::new();
+            while let Some(child) = next_child(&mut reader, &cluster).unwrap() {
+                match child.id {
+                    CLUSTER_TIMESTAMP => timestamp = reader.read_uint(&child).unwrap(),
+                    SIMPLE_BLOCK | BLOCK_GROUP => {
+                        blocks.push(child);
+                        reader.skip(&child).unwrap();
+                    }
+                    _ => reader.skip(&child).unwrap(),
+                }
+            }
+
+            reader
+                .seek(cluster.data_start + fields[&CUE_RELATIVE_POSITION])
+                .unwrap();
+            let header = reader.read_header().unwrap().unwrap();
+            let at_position = read_block_at(&mut reader, &header, timestamp);
+
+            let number = fields[&CUE_BLOCK_NUMBER] as usize;
+            reader.seek(blocks[number - 1].data_start).unwrap();
+            let at_number = read_block_at(&mut reader, &blocks[number - 1], timestamp);
+
+            CueTarget {
+                track: fields[&CUE_TRACK],
+                time: fields[&CUE_TIME],
+                at_position,
+                at_number,
+            }
+        })
+        .collect()
+}
+
+/// IDs of the elements targeted by the seek head, and of the elements found at their positions
+pub fn seek_targets(path: &Path) -> Vec<(u32, u32)> {
+    let mut reader = EbmlReader::new(BufReader::new(File::open(path).unwrap()));
+    let segment = read_segment_header(&mut reader).unwrap();
+
+    let seek_head = next_child(&mut reader, &segment).unwrap().unwrap();
+    assert_eq!(seek_head.id, SEEK_HEAD);
+
+    let mut seeks = Vec::new();
+    while let Some(seek) = next_child(&mut reader, &seek_head).unwrap() {
+        let (mut id, mut position) = (0, 0);
+        while let Some(field) = next_child(&mut reader, &seek).unwrap() {
+            match field.id {
+                SEEK_ID => id = reader.read_uint(&field).unwrap() as u32,
+                _ => position = reader.read_uint(&field).unwrap(),
+            }
+        }
+        seeks.push((id, position));
+    }
+
+    seeks
+        .into_iter()
+        .map(|(id, position)| {
+            reader.seek(segment.data_start + position).unwrap();
+            (id, reader.read_header().unwrap().unwrap().id)
+        })
+        .collect()
+}
@@ -0,0 +1,409 @@
+//! Output paths for the processed subtitles.
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::bdn;
+use crate::input::InputFile;
+use crate::matroska::{is_matroska, PgsTrack};
+use crate::Result;
+
+/// Added to the default output names when there is no output directory, `{stem}.tonemapped.{ext}`
+pub const DEFAULT_SUFFIX: &str = "tonemapped";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Keep the existing file and skip the input
+    Skip,
+    /// Replace the existing file
+    #[default]
+    Overwrite,
+    /// Append a number to the file name until it is unique
+    Rename,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// PGS `.sup` file
+    #[default]
+    Sup,
+    /// BDN `.xml` file, with the PNG images next to it
+    Bdn,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Sup => "sup",
+            Self::Bdn => bdn::EXTENSION,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// Outputs mirror the input directory structure under this directory.
+    /// When not set, outputs are written next to their input.
+    pub dir: Option<PathBuf>,
+    /// File name template, with the `{stem}`, `{ext}` and `{name}` placeholders from the input,
+    /// `{track}`, `{lang}` and `{track_name}` from the first track of Matroska inputs,
+    /// and `{pid}` for transport stream inputs.
+    /// Defaults to the input file name with the extension of the format,
+    /// or a name from the track metadata for Matroska inputs unless remuxed,
+    /// or from the PID for transport stream inputs.
+    /// Without `dir`, the default names get the [`DEFAULT_SUFFIX`].
+    pub template: Option<String>,
+    /// Matroska inputs are remuxed to Matroska files instead of written as `.sup`
+    pub remux: bool,
+    pub format: OutputFormat,
+    pub overwrite: OverwritePolicy,
+    /// Forced subtitles are also written to `{stem}.forced.{ext}` next to each output
+    pub split_forced: bool,
+}
+
+impl OutputOptions {
+    /// Output path for an input, before applying the overwrite policy
+    pub fn output_path(&self, input: &InputFile) -> PathBuf {
+        let name = match &self.template {
+            Some(template) => {
+                let stem = input.path.file_stem().map(OsStr::to_string_lossy);
+                let ext = input.path.extension().map(OsStr::to_string_lossy);
+                let name = input.path.file_name().map(OsStr::to_string_lossy);
+                let track = input.tracks.first();
+
+                template
+                    .replace("{stem}", stem.as_deref().unwrap_or_default())
+                    .replace("{ext}", ext.as_deref().unwrap_or_default())
+                    .replace("{name}", name.as_deref().unwrap_or_default())
+                    .replace(
+                        "{track}",
+                        &track.map(|t| t.number.to_string()).unwrap_or_default(),
+                    )
+                    .replace(
+                        "{lang}",
+                        &track.map(|t| sanitize(&t.language)).unwrap_or_default(),
+                    )
+                    .replace(
+                        "{track_name}",
+                        &track
+                            .and_then(|t| t.name.as_deref())
+                            .map(sanitize)
+                            .unwrap_or_default(),
+                    )
+                    .replace(
+                        "{pid}",
+                        &input
+                            .pid
+                            .map(|pid| format!("{pid:04X}"))
+                            .unwrap_or_default(),
+                    )
+                    .into()
+            }
+            None => {
+                let path = if input.pid.is_some() {
+                    pid_path(&input.path, input.pid).with_extension(self.format.extension())
+                } else if is_matroska(&input.path) && self.remux {
+                    input.path.clone()
+                } else if is_matroska(&input.path) {
+                    track_path(&input.path, input.tracks.first())
+                        .with_extension(self.format.extension())
+                } else {
+                    input.path.with_extension(self.format.extension())
+                };
+
+                // Outputs written next to their input must not replace it
+                let path = if self.dir.is_none() {
+                    with_stem_suffix(&path, DEFAULT_SUFFIX)
+                } else {
+                    path
+                };
+
+                path.file_name().map(PathBuf::from).unwrap_or_default()
+            }
+        };
+
+        match &self.dir {
+            Some(dir) => {
+                let mut path = dir.join(&input.relative);
+                path.set_file_name(name);
+                path
+            }
+            None => input.path.with_file_name(name),
+        }
+    }
+
+    /// Paths of the forced subtitles split from an output, one per written stream
+    pub fn forced_paths(&self, input: &InputFile, output: &Path) -> Vec<PathBuf> {
+        if !self.split_forced {
+            Vec::new()
+        } else if self.remux && is_matroska(&input.path) {
+            input
+                .tracks
+                .iter()
+                .map(|track| forced_path(&track_path(output, Some(track))))
+                .collect()
+        } else {
+            vec![forced_path(output)]
+        }
+    }
+
+    /// Resolves the output path of every input, in order.
+    ///
+    /// `Ok(None)` means the input should be skipped.
+    /// Inputs never overwrite each other's output, or another input,
+    /// including the forced subtitles split from the outputs.
+    pub fn plan(&self, inputs: &[InputFile]) -> Vec<Result<Option<PathBuf>>> {
+        let mut reserved: HashSet<PathBuf> = inputs.iter().map(|i| identity(&i.path)).collect();
+        let is_reserved =
+            |reserved: &HashSet<PathBuf>, path: &Path| reserved.contains(&identity(path));
+
+        inputs
+            .iter()
+            .map(|input| {
+                let written = |path: &Path| {
+                    let mut paths = self.forced_paths(input, path);
+                    paths.insert(0, path.to_path_buf());
+                    paths
+                };
+
+                let path = self.output_path(input);
+                let paths = written(&path);
+                let taken = paths.iter().any(|p| is_reserved(&reserved, p));
+                let exists = paths.iter().any(|p| p.exists());
+
+                let path = match self.overwrite {
+                    OverwritePolicy::Rename if taken || exists => unique_path(&path, |p| {
+                        written(p)
+                            .iter()
+                            .all(|p| !is_reserved(&reserved, p) && !p.exists())
+                    }),
+                    OverwritePolicy::Skip if !taken && exists => return Ok(None),
+                    _ if taken => {
+                        let path = paths.iter().find(|p| is_reserved(&reserved, p)).unwrap();
+
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!(
+                                "Output {} would overwrite an input or another output",
+                                path.display()
+                            ),
+                        )
+                        .into());
+                    }
+                    _ => path,
+                };
+
+                reserved.extend(written(&path).iter().map(|p| identity(p)));
+
+                Ok(Some(path))
+            })
+            .collect()
+    }
+}
+
+/// Absolute path of a file with its directory resolved, so that `a.sup` and `./a.sup` compare equal
+fn identity(path: &Path) -> PathBuf {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    match (parent.canonicalize(), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+    }
+}
+
+/// `{stem}.{suffix}.{ext}`
+fn with_stem_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(OsStr::to_string_lossy)
+        .unwrap_or_default();
+
+    match path.extension().map(OsStr::to_string_lossy) {
+        Some(ext) => path.with_file_name(format!("{stem}.{suffix}.{ext}")),
+        None => path.with_file_name(format!("{stem}.{suffix}")),
+    }
+}
+
+/// Appends `_1`, `_2`... to the file stem until `is_free` returns true
+fn unique_path<F: Fn(&Path) -> bool>(path: &Path, is_free: F) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(OsStr::to_string_lossy)
+        .unwrap_or_default();
+    let ext = path.extension().map(OsStr::to_string_lossy);
+
+    (1..)
+        .map(|i| match &ext {
+            Some(ext) => path.with_file_name(format!("{stem}_{i}.{ext}")),
+            None => path.with_file_name(format!("{stem}_{i}")),
+        })
+        .find(|p| is_free(p))
+        .unwrap()
+}
+
+/// `.sup` path for a track of a Matroska file, `{stem}.{track}.{lang}[.{track_name}][.forced].sup`
+pub fn track_path(path: &Path, track: Option<&PgsTrack>) -> PathBuf {
+    let mut name = path
+        .file_stem()
+        .map(OsStr::to_string_lossy)
+        .unwrap_or_default()
+        .into_owned();
+
+    if let Some(track) = track {
+        name.push_str(&format!(".{}.{}", track.number, sanitize(&track.language)));
+
+        if let Some(track_name) = track.name.as_deref().filter(|n| !n.is_empty()) {
+            name.push_str(&format!(".{}", sanitize(track_name)));
+        }
+        if track.forced {
+            name.push_str(".forced");
+        }
+    }
+
+    path.with_file_name(format!("{name}.sup"))
+}
+
+/// `.sup` path for a PGS stream of a transport stream, `{stem}.{pid}.sup` with the PID in hexadecimal
+pub fn pid_path(path: &Path, pid: Option<u16>) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(OsStr::to_string_lossy)
+        .unwrap_or_default();
+
+    match pid {
+        Some(pid) => path.with_file_name(format!("{stem}.{pid:04X}.sup")),
+        None => path.with_file_name(format!("{stem}.sup")),
+    }
+}
+
+/// Replaces the characters that are not safe in file names
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Path of the forced captions split from an output, `{stem}.forced.{ext}`
+pub fn forced_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(OsStr::to_string_lossy)
+        .unwrap_or_default();
+
+    match path.extension().map(OsStr::to_string_lossy) {
+        Some(ext) => path.with_file_name(format!("{stem}.forced.{ext}")),
+        None => path.with_file_name(format!("{stem}.forced")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(path: &str) -> InputFile {
+        InputFile {
+            path: PathBuf::from(path),
+            relative: PathBuf::from(Path::new(path).file_name().unwrap()),
+            tracks: Vec::new(),
+            pid: None,
+        }
+    }
+
+    #[test]
+    fn default_name_next_to_input() {
+        let opts = OutputOptions::default();
+        let inputs = [input("subs/a.sup")];
+
+        assert_eq!(
+            opts.output_path(&inputs[0]),
+            Path::new("subs/a.tonemapped.sup")
+        );
+        assert_eq!(
+            opts.plan(&inputs).pop().unwrap().unwrap(),
+            Some(PathBuf::from("subs/a.tonemapped.sup"))
+        );
+    }
+
+    #[test]
+    fn default_name_in_output_dir() {
+        let opts = OutputOptions {
+            dir: Some(PathBuf::from("out")),
+            format: OutputFormat::Bdn,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            opts.output_path(&input("subs/a.sup")),
+            Path::new("out/a.xml")
+        );
+    }
+
+    #[test]
+    fn forced_outputs_are_reserved() {
+        let inputs = [input("subs/ep1.forced.sup"), input("subs/ep1.sup")];
+        let mut opts = OutputOptions {
+            dir: Some(PathBuf::from("out")),
+            split_forced: true,
+            ..Default::default()
+        };
+
+        let plan = opts.plan(&inputs);
+        assert_eq!(
+            plan[0].as_ref().unwrap(),
+            &Some(PathBuf::from("out/ep1.forced.sup"))
+        );
+        assert!(plan[1].is_err());
+
+        opts.overwrite = OverwritePolicy::Rename;
+        let plan = opts.plan(&inputs);
+        assert_eq!(
+            plan[1].as_ref().unwrap(),
+            &Some(PathBuf::from("out/ep1_1.sup"))
+        );
+    }
+
+    #[test]
+    fn input_in_output_dir_is_reserved() {
+        let opts = OutputOptions {
+            dir: Some(PathBuf::from(".")),
+            ..Default::default()
+        };
+        let inputs = [input("a.sup")];
+
+        assert_eq!(opts.output_path(&inputs[0]), Path::new("./a.sup"));
+        assert!(opts.plan(&inputs).pop().unwrap().is_err());
+    }
+
+    #[test]
+    fn track_metadata_is_sanitized_in_templates() {
+        let opts = OutputOptions {
+            template: Some("{stem}.{track}.{lang}.{track_name}.sup".to_owned()),
+            ..Default::default()
+        };
+        let mut input = input("movie.mkv");
+        input.tracks.push(PgsTrack {
+            number: 3,
+            uid: 1,
+            language: "../en".to_owned(),
+            name: Some("Signs/Songs".to_owned()),
+            default: true,
+            forced: false,
+            compression: None,
+        });
+
+        assert_eq!(
+            opts.output_path(&input),
+            Path::new("movie.3..._en.Signs_Songs.sup")
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use super::{
+    Object, ObjectDefinition, PaletteDefinition, PresentationComposition, Segment, SegmentData,
+    WindowDefinition,
+};
+use crate::error::{parse_error, Result};
+
+/// A group of segments, from a PCS up to and including the END segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplaySet {
+    pub segments: Vec<Segment>,
+}
+
+impl DisplaySet {
+    pub fn new(segments: Vec<Segment>) -> Result<Self> {
+        if !matches!(segments.first().map(|s| &s.data), Some(SegmentData::Pcs(_))) {
+            return Err(parse_error("Display set does not start with a PCS"));
+        }
+        if !matches!(segments.last().map(|s| &s.data), Some(SegmentData::End)) {
+            return Err(parse_error("Display set does not end with an END segment"));
+        }
+
+        Ok(Self { segments })
+    }
+
+    /// Presentation timestamp of the display set, from its PCS
+    pub fn pts(&self) -> u32 {
+        self.segments[0].pts
+    }
+
+    pub fn composition(&self) -> &PresentationComposition {
+        match &self.segments[0].data {
+            SegmentData::Pcs(pcs) => pcs,
+            _ => unreachable!("Display set must start with a PCS"),
+        }
+    }
+
+    pub fn composition_mut(&mut self) -> &mut PresentationComposition {
+        match &mut self.segments[0].data {
+            SegmentData::Pcs(pcs) => pcs,
+            _ => unreachable!("Display set must start with a PCS"),
+        }
+    }
+
+    /// Whether any object of the composition is forced
+    pub fn is_forced(&self) -> bool {
+        self.composition().objects.iter().any(|obj| obj.forced)
+    }
+
+    pub fn windows(&self) -> impl Iterator<Item = &WindowDefinition> {
+        self.segments.iter().filter_map(|s| match &s.data {
+            SegmentData::Wds(wds) => Some(wds),
+            _ => None,
+        })
+    }
+
+    pub fn palettes(&self) -> impl Iterator<Item = &PaletteDefinition> {
+        self.segments.iter().filter_map(|s| match &s.data {
+            SegmentData::Pds(pds) => Some(pds),
+            _ => None,
+        })
+    }
+
+    pub fn palettes_mut(&mut self) -> impl Iterator<Item = &mut PaletteDefinition> {
+        self.segments.iter_mut().filter_map(|s| match &mut s.data {
+            SegmentData::Pds(pds) => Some(pds),
+            _ => None,
+        })
+    }
+
+    /// ODS fragments, in stream order
+    pub fn objects(&self) -> impl Iterator<Item = &ObjectDefinition> {
+        self.segments.iter().filter_map(|s| match &s.data {
+            SegmentData::Ods(ods) => Some(ods),
+            _ => None,
+        })
+    }
+
+    /// Objects defined in this display set, reassembled from their fragments
+    pub fn complete_objects(&self) -> Result<Vec<Object>> {
+        let mut sequences: Vec<Vec<&ObjectDefinition>> = Vec::new();
+
+        for ods in self.objects() {
+            match sequences.last_mut() {
+                Some(sequence) if !ods.first_in_sequence => sequence.push(ods),
+                _ => sequences.push(vec![ods]),
+            }
+        }
+
+        sequences.into_iter().map(Object::from_fragments).collect()
+    }
+
+    /// Replaces the ODS fragments of an object defined in this display set,
+    /// splitting the new data into as many fragments as required.
+    pub fn set_object(&mut self, object: &Object) -> Result<()> {
+        let is_object =
+            |s: &Segment| matches!(&s.data, SegmentData::Ods(ods) if ods.id == object.id);
+
+        let index = self.segments.iter().position(is_object).ok_or_else(|| {
+            parse_error(format!("Object {} is not in the display set", object.id))
+        })?;
+        let (pts, dts) = (self.segments[index].pts, self.segments[index].dts);
+
+        self.segments.retain(|s| !is_object(s));
+
+        let fragments = object.fragments().into_iter().map(|ods| Segment {
+            pts,
+            dts,
+            data: SegmentData::Ods(ods),
+        });
+        self.segments.splice(index..index, fragments);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+//! HDMV presentation graphics stream (PGS), as found in `.sup` files.
+//!
+//! A stream is a sequence of segments, grouped into display sets
+//! that each start with a presentation composition segment and end with an END segment.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::error::{parse_error, Error, Result};
+
+mod display_set;
+mod object;
+pub mod rle;
+#[cfg(test)]
+pub(crate) mod samples;
+mod segment;
+
+pub use display_set::DisplaySet;
+pub use object::Object;
+pub use segment::*;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PgsStream {
+    pub display_sets: Vec<DisplaySet>,
+}
+
+impl PgsStream {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+
+        Self::from_reader(BufReader::new(file))
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        Self::parse(&bytes)
+    }
+
+    /// Parses a complete stream, every display set must be terminated by an END segment.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut display_sets = Vec::new();
+        let mut current: Vec<Segment> = Vec::new();
+
+        let mut offset = 0;
+        while offset < data.len() {
+            let (segment, size) = Segment::parse(&data[offset..]).map_err(|e| match e {
+                Error::Parse(msg) => parse_error(format!("Segment at offset {offset}: {msg}")),
+                e => e,
+            })?;
+            offset += size;
+
+            match segment.data {
+                SegmentData::Pcs(_) if !current.is_empty() => {
+                    return Err(parse_error(format!(
+                        "Display set at offset {offset} is missing its END segment"
+                    )));
+                }
+                SegmentData::End => {
+                    current.push(segment);
+                    display_sets.push(DisplaySet::new(std::mem::take(&mut current))?);
+                }
+                _ => current.push(segment),
+            }
+        }
+
+        if !current.is_empty() {
+            return Err(parse_error("Stream ends in the middle of a display set"));
+        }
+
+        Ok(Self { display_sets })
+    }
+
+    pub fn write_to_path<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        self.write(&mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Serializes every display set back to back.
+    /// Unmodified segments are written back exactly as they were parsed,
+    /// except for the reserved bits of their flag bytes which are cleared.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.segments()
+            .try_for_each(|segment| segment.write(writer))
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.display_sets.iter().flat_map(|ds| ds.segments.iter())
+    }
+
+    /// Checks that `output` has the same display sets as this stream, at the exact same PTS and DTS.
+    /// The segments are compared one by one when both display sets have the same segment types,
+    /// otherwise only the compositions are compared.
+    pub fn verify_timing(&self, output: &Self) -> Result<()> {
+        if self.display_sets.len() != output.display_sets.len() {
+            return Err(Error::Timing(format!(
+                "{} display sets became {}",
+                self.display_sets.len(),
+                output.display_sets.len()
+            )));
+        }
+
+        for (i, (input, output)) in self
+            .display_sets
+            .iter()
+            .zip(&output.display_sets)
+            .enumerate()
+        {
+            let same_types = input
+                .segments
+                .iter()
+                .map(Segment::segment_type)
+                .eq(output.segments.iter().map(Segment::segment_type));
+            let compared = if same_types { input.segments.len() } else { 1 };
+
+            let mismatch = input
+                .segments
+                .iter()
+                .zip(&output.segments)
+                .take(compared)
+                .find(|(a, b)| a.pts != b.pts || a.dts != b.dts);
+
+            if let Some((a, b)) = mismatch {
+                return Err(Error::Timing(format!(
+                    "display set {} at {}: {:?} PTS/DTS {}/{} became {}/{}",
+                    i + 1,
+                    format_timestamp(input.pts()),
+                    a.segment_type(),
+                    a.pts,
+                    a.dts,
+                    b.pts,
+                    b.dts
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Display sets grouped by epoch, each group starting at an epoch start
+    pub fn epochs_mut(&mut self) -> impl Iterator<Item = &mut [DisplaySet]> {
+        self.display_sets.chunk_by_mut(|_, next| {
+            next.composition().composition_state != CompositionState::EpochStart
+        })
+    }
+
+    /// Copy of the stream with only the forced captions.
+    /// Every display set keeps its timing, but the compositions drop their objects that are not forced,
+    /// and the objects never displayed as forced in their epoch are removed.
+    /// Display sets left without objects are kept, as empty compositions clearing the screen.
+    pub fn forced_only(&self) -> Self {
+        let mut stream = self.clone();
+
+        for epoch in stream.epochs_mut() {
+            let forced: HashSet<u16> = epoch
+                .iter()
+                .flat_map(|ds| ds.composition().objects.iter())
+                .filter(|obj| obj.forced)
+                .map(|obj| obj.object_id)
+                .collect();
+
+            for ds in epoch.iter_mut() {
+                ds.composition_mut().objects.retain(|obj| obj.forced);
+                ds.segments.retain(
+                    |s| !matches!(&s.data, SegmentData::Ods(ods) if !forced.contains(&ods.id)),
+                );
+            }
+        }
+
+        stream
+    }
+}
+
+/// Formats a 90 kHz timestamp as `HH:MM:SS.mmm`
+pub fn format_timestamp(timestamp: u32) -> String {
+    let ms = timestamp as u64 / 90;
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// Adds the `PG` magic, PTS and DTS to segments stored without them,
+/// like in Matroska blocks and PES packets
+pub(crate) fn add_headers(
+    buffer: &mut Vec<u8>,
+    mut data: &[u8],
+    pts: u32,
+    dts: u32,
+) -> std::result::Result<(), String> {
+    while !data.is_empty() {
+        if data.len() < 3 {
+            return Err("truncated segment header".to_owned());
+        }
+
+        let size = u16::from_be_bytes([data[1], data[2]]) as usize;
+        if data.len() < 3 + size {
+            return Err("truncated segment".to_owned());
+        }
+
+        buffer.extend_from_slice(&SEGMENT_MAGIC);
+        buffer.extend_from_slice(&pts.to_be_bytes());
+        buffer.extend_from_slice(&dts.to_be_bytes());
+        buffer.extend_from_slice(&data[..3 + size]);
+
+        data = &data[3 + size..];
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::samples::*;
+    use super::*;
+
+    #[test]
+    fn parse_write_round_trip() {
+        let stream = sample_stream();
+
+        let mut bytes = Vec::new();
+        stream.write(&mut bytes).unwrap();
+
+        let parsed = PgsStream::parse(&bytes).unwrap();
+        assert_eq!(parsed, stream);
+
+        let mut written = Vec::new();
+        parsed.write(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn fragmented_object_is_reassembled() {
+        let stream = sample_stream();
+        let objects = stream.display_sets[0].complete_objects().unwrap();
+
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].decode().unwrap().len(), 1000 * 200);
+    }
+
+    #[test]
+    fn missing_end_segment_is_rejected() {
+        let stream = sample_stream();
+
+        let mut bytes = Vec::new();
+        stream.write(&mut bytes).unwrap();
+        bytes.truncate(bytes.len() - SEGMENT_HEADER_SIZE);
+
+        assert!(PgsStream::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn verify_timing() {
+        let stream = small_stream();
+        let fails =
+            |output: &PgsStream| matches!(stream.verify_timing(output), Err(Error::Timing(_)));
+        assert!(stream.verify_timing(&stream.clone()).is_ok());
+
+        // Other segments in the display sets, only the compositions are compared
+        let mut output = stream.clone();
+        output.display_sets[0].segments.remove(1);
+        assert!(stream.verify_timing(&output).is_ok());
+        output.display_sets[0].segments[0].dts += 1;
+        assert!(fails(&output));
+
+        // A segment at another DTS in the same display set
+        let mut output = stream.clone();
+        output.display_sets[0].segments[2].dts = 0;
+        assert!(fails(&output));
+
+        let mut output = stream.clone();
+        output.display_sets.pop();
+        assert!(fails(&output));
+    }
+
+    #[test]
+    fn out_of_order_timestamps_fail_verification() {
+        let stream = small_stream();
+
+        let mut output = stream.clone();
+        output.display_sets.swap(1, 2);
+        let Err(Error::Timing(message)) = stream.verify_timing(&output) else {
+            panic!("Reordered display sets passed the verification");
+        };
+        assert!(
+            message.starts_with("display set 2 at 00:00:02"),
+            "{message}"
+        );
+
+        let mut output = stream.clone();
+        for segment in &mut output.display_sets[3].segments {
+            segment.pts = 0;
+        }
+        assert!(stream.verify_timing(&output).is_err());
+    }
+
+    #[test]
+    fn forced_only_keeps_empty_display_sets() {
+        let stream = small_stream();
+        let forced = stream.forced_only();
+
+        assert_eq!(forced.display_sets.len(), stream.display_sets.len());
+        assert!(forced.segments().map(|s| (s.pts, s.dts)).eq(stream
+            .segments()
+            .filter(|s| !matches!(&s.data, SegmentData::Ods(_)) || s.pts == 90_000)
+            .map(|s| (s.pts, s.dts))));
+
+        // The forced caption is kept as it was
+        assert_eq!(forced.display_sets[0], stream.display_sets[0]);
+
+        // The other caption only clears the screen
+        let ds = &forced.display_sets[2];
+        assert!(ds.composition().objects.is_empty());
+        assert_eq!(ds.objects().count(), 0);
+        assert_eq!(ds.palettes().count(), 1);
+        assert_eq!(ds.pts(), 270_000);
+    }
+}
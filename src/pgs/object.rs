@@ -0,0 +1,81 @@
+use super::{rle, ObjectDefinition};
+use crate::error::{parse_error, Result};
+
+/// A complete object, reassembled from its ODS fragments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub id: u16,
+    pub version: u8,
+    pub width: u16,
+    pub height: u16,
+    /// Run-length encoded bitmap
+    pub data: Vec<u8>,
+}
+
+impl Object {
+    /// Reassembles an object from the fragments of a single ODS sequence.
+    pub fn from_fragments<'a, I>(fragments: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = &'a ObjectDefinition>,
+    {
+        let mut fragments = fragments.into_iter();
+
+        let first = fragments
+            .next()
+            .ok_or_else(|| parse_error("Object has no ODS fragments"))?;
+        let header = first
+            .header
+            .filter(|_| first.first_in_sequence)
+            .ok_or_else(|| parse_error(format!("Object {} is missing its header", first.id)))?;
+
+        let mut data = first.data.clone();
+        let mut last_in_sequence = first.last_in_sequence;
+
+        for fragment in fragments {
+            if last_in_sequence || fragment.id != first.id {
+                return Err(parse_error(format!(
+                    "Unexpected ODS fragment for object {}",
+                    fragment.id
+                )));
+            }
+
+            data.extend_from_slice(&fragment.data);
+            last_in_sequence = fragment.last_in_sequence;
+        }
+
+        if !last_in_sequence {
+            return Err(parse_error(format!(
+                "Object {} is missing its last fragment",
+                first.id
+            )));
+        }
+
+        Ok(Self {
+            id: first.id,
+            version: first.version,
+            width: header.width,
+            height: header.height,
+            data,
+        })
+    }
+
+    /// Encodes a `width * height` buffer of palette indices.
+    pub fn from_bitmap(id: u16, version: u8, width: u16, height: u16, bitmap: &[u8]) -> Self {
+        Self {
+            id,
+            version,
+            width,
+            height,
+            data: rle::encode(bitmap, width, height),
+        }
+    }
+
+    /// Decodes the object to a `width * height` buffer of palette indices.
+    pub fn decode(&self) -> Result<Vec<u8>> {
+        rle::decode(&self.data, self.width, self.height)
+    }
+
+    pub fn fragments(&self) -> Vec<ObjectDefinition> {
+        ObjectDefinition::fragments(self.id, self.version, self.width, self.height, &self.data)
+    }
+}
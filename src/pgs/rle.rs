@@ -0,0 +1,173 @@
+//! Run-length encoding of the object bitmaps.
+//!
+//! Each line is a sequence of runs, terminated by `00 00`:
+//! - `CC`: one pixel of color `CC`, non zero
+//! - `00 0L`: `L` (1-63) pixels of color 0
+//! - `00 4L LL`: `L` (64-16383) pixels of color 0
+//! - `00 8L CC`: `L` (3-63) pixels of color `CC`
+//! - `00 CL LL CC`: `L` (64-16383) pixels of color `CC`
+
+use crate::error::{parse_error, Result};
+
+const MAX_RUN_LENGTH: usize = 0x3FFF;
+
+/// Decodes run-length encoded data to a `width * height` buffer of palette indices.
+/// Lines that end early are padded with index 0.
+pub fn decode(data: &[u8], width: u16, height: u16) -> Result<Vec<u8>> {
+    let (width, height) = (width as usize, height as usize);
+    let mut bitmap = vec![0; width * height];
+
+    let mut bytes = data.iter().copied();
+    let mut x = 0;
+    let mut y = 0;
+
+    while y < height {
+        let Some(byte) = bytes.next() else {
+            break;
+        };
+
+        let (color, len) = if byte != 0 {
+            (byte, 1)
+        } else {
+            let flags = next_byte(&mut bytes)?;
+
+            if flags == 0 {
+                x = 0;
+                y += 1;
+                continue;
+            }
+
+            let len = if flags & 0x40 != 0 {
+                (((flags & 0x3F) as usize) << 8) | next_byte(&mut bytes)? as usize
+            } else {
+                (flags & 0x3F) as usize
+            };
+
+            let color = if flags & 0x80 != 0 {
+                next_byte(&mut bytes)?
+            } else {
+                0
+            };
+
+            (color, len)
+        };
+
+        if x + len > width {
+            return Err(parse_error(format!(
+                "RLE line {y} is longer than the object width {width}"
+            )));
+        }
+
+        let start = y * width + x;
+        bitmap[start..start + len].fill(color);
+        x += len;
+    }
+
+    Ok(bitmap)
+}
+
+fn next_byte<I: Iterator<Item = u8>>(bytes: &mut I) -> Result<u8> {
+    bytes
+        .next()
+        .ok_or_else(|| parse_error("RLE data ends in the middle of a run"))
+}
+
+/// Encodes a `width * height` buffer of palette indices, using the shortest code for every run.
+pub fn encode(bitmap: &[u8], width: u16, height: u16) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    assert_eq!(bitmap.len(), width * height, "Bitmap size mismatch");
+
+    let mut out = Vec::with_capacity(bitmap.len() / 4);
+
+    for line in (0..height).map(|y| &bitmap[y * width..(y + 1) * width]) {
+        let mut x = 0;
+
+        while x < width {
+            let color = line[x];
+            let run = line[x..].iter().take_while(|&&c| c == color).count();
+            let len = run.min(MAX_RUN_LENGTH);
+
+            encode_run(&mut out, color, len);
+            x += len;
+        }
+
+        out.extend_from_slice(&[0, 0]);
+    }
+
+    out
+}
+
+fn encode_run(out: &mut Vec<u8>, color: u8, len: usize) {
+    match (color, len) {
+        (0, 1..=63) => out.extend_from_slice(&[0, len as u8]),
+        (0, _) => out.extend_from_slice(&[0, 0x40 | (len >> 8) as u8, len as u8]),
+        (_, 1..=2) => (0..len).for_each(|_| out.push(color)),
+        (_, 3..=63) => out.extend_from_slice(&[0, 0x80 | len as u8, color]),
+        (_, _) => out.extend_from_slice(&[0, 0xC0 | (len >> 8) as u8, len as u8, color]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bitmap: &[u8], width: u16, height: u16) -> Vec<u8> {
+        let encoded = encode(bitmap, width, height);
+        assert_eq!(decode(&encoded, width, height).unwrap(), bitmap);
+        encoded
+    }
+
+    #[test]
+    fn runs_of_every_length_class() {
+        for len in [1, 2, 3, 63, 64, 16383, 16384, 40000] {
+            for color in [0, 5] {
+                let mut line = vec![color; len];
+                line.push(9);
+
+                round_trip(&line, line.len() as u16, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn all_zero_lines() {
+        let encoded = round_trip(&[0; 3 * 100], 100, 3);
+        assert_eq!(encoded, [0, 0x40, 100, 0, 0].repeat(3));
+
+        let encoded = round_trip(&[0; 2 * 20], 20, 2);
+        assert_eq!(encoded, [0, 20, 0, 0].repeat(2));
+    }
+
+    #[test]
+    fn shortest_encoding() {
+        assert_eq!(encode(&[7], 1, 1), [7, 0, 0]);
+        assert_eq!(encode(&[7, 7], 2, 1), [7, 7, 0, 0]);
+        assert_eq!(encode(&[7; 3], 3, 1), [0, 0x83, 7, 0, 0]);
+        assert_eq!(encode(&[7; 63], 63, 1), [0, 0xBF, 7, 0, 0]);
+        assert_eq!(encode(&[7; 64], 64, 1), [0, 0xC0, 64, 7, 0, 0]);
+        assert_eq!(encode(&[7; 16383], 16383, 1), [0, 0xFF, 0xFF, 7, 0, 0]);
+        assert_eq!(encode(&[7; 16384], 16384, 1), [0, 0xFF, 0xFF, 7, 7, 0, 0]);
+    }
+
+    #[test]
+    fn mixed_lines() {
+        let (width, height) = (300, 40);
+        let bitmap: Vec<u8> = (0..width * height)
+            .map(|i| match (i % width, i / width) {
+                (x, _) if x < 10 => 0,
+                (x, y) if x < 200 => ((x / 3 + y) % 4) as u8,
+                (x, y) => (x * y % 256) as u8,
+            })
+            .collect();
+
+        round_trip(&bitmap, width as u16, height as u16);
+    }
+
+    #[test]
+    fn short_lines_are_padded() {
+        assert_eq!(
+            decode(&[5, 0, 0, 6, 6, 0, 0], 3, 2).unwrap(),
+            [5, 0, 0, 6, 6, 0]
+        );
+    }
+}
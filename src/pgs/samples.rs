@@ -0,0 +1,205 @@
+//! Small streams for the tests of the PGS readers and writers.
+
+use super::*;
+
+pub fn segment(pts: u32, data: SegmentData) -> Segment {
+    Segment {
+        pts,
+        dts: pts.saturating_sub(900),
+        data,
+    }
+}
+
+pub fn entry(id: u8, y: u8, cr: u8, cb: u8, alpha: u8) -> PaletteEntry {
+    PaletteEntry {
+        id,
+        y,
+        cr,
+        cb,
+        alpha,
+    }
+}
+
+pub fn palette(id: u8, entries: Vec<PaletteEntry>) -> PaletteDefinition {
+    PaletteDefinition {
+        id,
+        version: 0,
+        entries,
+    }
+}
+
+/// Object of window 0 shown at the top left corner
+pub fn placed(object_id: u16, forced: bool) -> CompositionObject {
+    CompositionObject {
+        object_id,
+        window_id: 0,
+        forced,
+        x: 0,
+        y: 0,
+        crop: None,
+    }
+}
+
+/// 1080p composition using palette 0
+pub fn composition(
+    number: u16,
+    state: CompositionState,
+    objects: Vec<CompositionObject>,
+) -> PresentationComposition {
+    PresentationComposition {
+        width: 1920,
+        height: 1080,
+        frame_rate: 0x10,
+        composition_number: number,
+        composition_state: state,
+        palette_update: false,
+        palette_id: 0,
+        objects,
+    }
+}
+
+/// Display set of the composition followed by the segments of `data`, at the same timestamps
+pub fn display_set(pts: u32, pcs: PresentationComposition, data: Vec<SegmentData>) -> DisplaySet {
+    let segments = std::iter::once(SegmentData::Pcs(pcs))
+        .chain(data)
+        .chain([SegmentData::End])
+        .map(|data| segment(pts, data))
+        .collect();
+
+    DisplaySet::new(segments).unwrap()
+}
+
+/// Epoch start showing an object of `bitmap` with its palette, in a window covering it
+pub fn caption(
+    pts: u32,
+    number: u16,
+    forced: bool,
+    entries: Vec<PaletteEntry>,
+    object: &Object,
+) -> DisplaySet {
+    let window = Window {
+        id: 0,
+        x: 0,
+        y: 0,
+        width: object.width,
+        height: object.height,
+    };
+    let mut data = vec![
+        SegmentData::Wds(WindowDefinition {
+            windows: vec![window],
+        }),
+        SegmentData::Pds(palette(0, entries)),
+    ];
+    data.extend(object.fragments().into_iter().map(SegmentData::Ods));
+
+    display_set(
+        pts,
+        composition(
+            number,
+            CompositionState::EpochStart,
+            vec![placed(object.id, forced)],
+        ),
+        data,
+    )
+}
+
+/// Normal display set removing every object
+pub fn clear(pts: u32, number: u16) -> DisplaySet {
+    display_set(
+        pts,
+        composition(number, CompositionState::Normal, Vec::new()),
+        Vec::new(),
+    )
+}
+
+/// A forced caption, its clear, then a caption that is not forced and its clear
+pub fn small_stream() -> PgsStream {
+    let object = Object::from_bitmap(0, 0, 4, 2, &[1, 1, 2, 2, 1, 0, 0, 2]);
+    let entries = vec![
+        entry(0, 16, 128, 128, 0),
+        entry(1, 235, 128, 128, 255),
+        entry(2, 81, 240, 90, 255),
+    ];
+
+    PgsStream {
+        display_sets: vec![
+            caption(90_000, 0, true, entries.clone(), &object),
+            clear(180_000, 1),
+            caption(270_000, 2, false, entries, &object),
+            clear(360_000, 3),
+        ],
+    }
+}
+
+/// Epoch start showing a cropped forced object too large for a single ODS, then a clear
+pub fn sample_stream() -> PgsStream {
+    let (width, height) = (1000, 200);
+    let bitmap: Vec<u8> = (0..width as usize * height as usize)
+        .map(|i| (i * 7 % 251) as u8)
+        .collect();
+    let object = Object::from_bitmap(0, 0, width, height, &bitmap);
+    assert!(object.fragments().len() > 1);
+
+    let mut first = vec![
+        segment(
+            90_000,
+            SegmentData::Pcs(PresentationComposition {
+                objects: vec![CompositionObject {
+                    x: 460,
+                    y: 800,
+                    crop: Some(Crop {
+                        x: 0,
+                        y: 0,
+                        width: 1000,
+                        height: 100,
+                    }),
+                    ..placed(0, true)
+                }],
+                ..composition(0, CompositionState::EpochStart, Vec::new())
+            }),
+        ),
+        segment(
+            90_000,
+            SegmentData::Wds(WindowDefinition {
+                windows: vec![Window {
+                    id: 0,
+                    x: 460,
+                    y: 800,
+                    width,
+                    height,
+                }],
+            }),
+        ),
+        segment(
+            90_000,
+            SegmentData::Pds(palette(
+                0,
+                (0..=255)
+                    .map(|id| entry(id, 16 + id / 2, 128, 255 - id, id))
+                    .collect(),
+            )),
+        ),
+    ];
+    first.extend(
+        object
+            .fragments()
+            .into_iter()
+            .map(|ods| segment(90_000, SegmentData::Ods(ods))),
+    );
+    first.push(segment(90_000, SegmentData::End));
+
+    let second = vec![
+        segment(
+            270_000,
+            SegmentData::Pcs(composition(1, CompositionState::Normal, Vec::new())),
+        ),
+        segment(270_000, SegmentData::End),
+    ];
+
+    PgsStream {
+        display_sets: vec![
+            DisplaySet::new(first).unwrap(),
+            DisplaySet::new(second).unwrap(),
+        ],
+    }
+}
@@ -0,0 +1,688 @@
+use std::io::Write;
+
+use crate::color::Matrix;
+use crate::error::{encode_error, parse_error, Result};
+
+/// "PG"
+pub const SEGMENT_MAGIC: [u8; 2] = [0x50, 0x47];
+pub const SEGMENT_HEADER_SIZE: usize = 13;
+pub const MAX_SEGMENT_SIZE: usize = u16::MAX as usize;
+
+/// Bytes preceding the object data in the first ODS fragment
+const ODS_FIRST_HEADER_SIZE: usize = 11;
+/// Bytes preceding the object data in the following ODS fragments
+const ODS_HEADER_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentType {
+    Pds = 0x14,
+    Ods = 0x15,
+    Pcs = 0x16,
+    Wds = 0x17,
+    End = 0x80,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// Presentation timestamp, 90 kHz
+    pub pts: u32,
+    /// Decoding timestamp, 90 kHz
+    pub dts: u32,
+    pub data: SegmentData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SegmentData {
+    Pcs(PresentationComposition),
+    Wds(WindowDefinition),
+    Pds(PaletteDefinition),
+    Ods(ObjectDefinition),
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompositionState {
+    Normal = 0x00,
+    AcquisitionPoint = 0x40,
+    EpochStart = 0x80,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresentationComposition {
+    pub width: u16,
+    pub height: u16,
+    pub frame_rate: u8,
+    pub composition_number: u16,
+    pub composition_state: CompositionState,
+    /// Top bit of the palette update flag byte, the reserved bits are written as 0
+    pub palette_update: bool,
+    pub palette_id: u8,
+    pub objects: Vec<CompositionObject>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompositionObject {
+    pub object_id: u16,
+    pub window_id: u8,
+    /// From the flags byte, along with the presence of `crop`. The reserved bits are written as 0
+    pub forced: bool,
+    pub x: u16,
+    pub y: u16,
+    pub crop: Option<Crop>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowDefinition {
+    pub windows: Vec<Window>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub id: u8,
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaletteDefinition {
+    pub id: u8,
+    pub version: u8,
+    pub entries: Vec<PaletteEntry>,
+}
+
+/// Palette colors are stored as YCbCr with an alpha channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaletteEntry {
+    pub id: u8,
+    pub y: u8,
+    pub cr: u8,
+    pub cb: u8,
+    pub alpha: u8,
+}
+
+/// A single ODS segment, which may only be a fragment of the object's data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectDefinition {
+    pub id: u16,
+    pub version: u8,
+    /// From the sequence flags byte, the reserved bits are written as 0
+    pub first_in_sequence: bool,
+    pub last_in_sequence: bool,
+    /// Only present in the first fragment of the sequence
+    pub header: Option<ObjectHeader>,
+    /// Run-length encoded bitmap data contained in this fragment
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectHeader {
+    /// Length of the object data, including the 4 bytes for the dimensions
+    pub data_length: u32,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl SegmentType {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        Ok(match value {
+            0x14 => Self::Pds,
+            0x15 => Self::Ods,
+            0x16 => Self::Pcs,
+            0x17 => Self::Wds,
+            0x80 => Self::End,
+            _ => return Err(parse_error(format!("Unknown segment type 0x{value:02X}"))),
+        })
+    }
+}
+
+impl CompositionState {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        Ok(match value {
+            0x00 => Self::Normal,
+            0x40 => Self::AcquisitionPoint,
+            0x80 => Self::EpochStart,
+            _ => {
+                return Err(parse_error(format!(
+                    "Unknown composition state 0x{value:02X}"
+                )))
+            }
+        })
+    }
+}
+
+impl Segment {
+    /// Parses a segment at the start of `data`.
+    /// Returns the segment and the number of bytes it occupied.
+    pub fn parse(data: &[u8]) -> Result<(Self, usize)> {
+        let mut reader = ByteReader::new(data);
+
+        let magic = reader.read_bytes(2)?;
+        if magic != SEGMENT_MAGIC {
+            return Err(parse_error(format!(
+                "Invalid segment magic {:02X}{:02X}",
+                magic[0], magic[1]
+            )));
+        }
+
+        let pts = reader.read_u32()?;
+        let dts = reader.read_u32()?;
+        let segment_type = SegmentType::from_u8(reader.read_u8()?)?;
+        let size = reader.read_u16()? as usize;
+
+        let mut payload = ByteReader::new(reader.read_bytes(size)?);
+
+        let data = match segment_type {
+            SegmentType::Pcs => SegmentData::Pcs(PresentationComposition::parse(&mut payload)?),
+            SegmentType::Wds => SegmentData::Wds(WindowDefinition::parse(&mut payload)?),
+            SegmentType::Pds => SegmentData::Pds(PaletteDefinition::parse(&mut payload)?),
+            SegmentType::Ods => SegmentData::Ods(ObjectDefinition::parse(&mut payload)?),
+            SegmentType::End => SegmentData::End,
+        };
+
+        if payload.remaining() > 0 {
+            return Err(parse_error(format!(
+                "{segment_type:?} segment has {} unexpected trailing bytes",
+                payload.remaining()
+            )));
+        }
+
+        Ok((Self { pts, dts, data }, SEGMENT_HEADER_SIZE + size))
+    }
+
+    /// Serializes the segment, header included.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut payload = Vec::new();
+
+        match &self.data {
+            SegmentData::Pcs(pcs) => pcs.write(&mut payload),
+            SegmentData::Wds(wds) => wds.write(&mut payload),
+            SegmentData::Pds(pds) => pds.write(&mut payload),
+            SegmentData::Ods(ods) => ods.write(&mut payload),
+            SegmentData::End => {}
+        }
+
+        if payload.len() > MAX_SEGMENT_SIZE {
+            return Err(encode_error(format!(
+                "{:?} segment is too large: {} bytes",
+                self.segment_type(),
+                payload.len()
+            )));
+        }
+
+        let mut header = [0; SEGMENT_HEADER_SIZE];
+        header[0..2].copy_from_slice(&SEGMENT_MAGIC);
+        header[2..6].copy_from_slice(&self.pts.to_be_bytes());
+        header[6..10].copy_from_slice(&self.dts.to_be_bytes());
+        header[10] = self.segment_type() as u8;
+        header[11..13].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+
+        writer.write_all(&header)?;
+        writer.write_all(&payload)?;
+
+        Ok(())
+    }
+
+    pub fn segment_type(&self) -> SegmentType {
+        match self.data {
+            SegmentData::Pcs(_) => SegmentType::Pcs,
+            SegmentData::Wds(_) => SegmentType::Wds,
+            SegmentData::Pds(_) => SegmentType::Pds,
+            SegmentData::Ods(_) => SegmentType::Ods,
+            SegmentData::End => SegmentType::End,
+        }
+    }
+}
+
+impl PresentationComposition {
+    fn parse(reader: &mut ByteReader) -> Result<Self> {
+        let width = reader.read_u16()?;
+        let height = reader.read_u16()?;
+        let frame_rate = reader.read_u8()?;
+        let composition_number = reader.read_u16()?;
+        let composition_state = CompositionState::from_u8(reader.read_u8()?)?;
+        let palette_update = reader.read_u8()? & 0x80 != 0;
+        let palette_id = reader.read_u8()?;
+
+        let count = reader.read_u8()?;
+        let objects = (0..count)
+            .map(|_| CompositionObject::parse(reader))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            width,
+            height,
+            frame_rate,
+            composition_number,
+            composition_state,
+            palette_update,
+            palette_id,
+            objects,
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.width.to_be_bytes());
+        out.extend_from_slice(&self.height.to_be_bytes());
+        out.push(self.frame_rate);
+        out.extend_from_slice(&self.composition_number.to_be_bytes());
+        out.push(self.composition_state as u8);
+        out.push(if self.palette_update { 0x80 } else { 0x00 });
+        out.push(self.palette_id);
+
+        out.push(self.objects.len() as u8);
+        self.objects.iter().for_each(|obj| obj.write(out));
+    }
+}
+
+impl CompositionObject {
+    fn parse(reader: &mut ByteReader) -> Result<Self> {
+        let object_id = reader.read_u16()?;
+        let window_id = reader.read_u8()?;
+
+        let flags = reader.read_u8()?;
+        let cropped = flags & 0x80 != 0;
+        let forced = flags & 0x40 != 0;
+
+        let x = reader.read_u16()?;
+        let y = reader.read_u16()?;
+
+        let crop = if cropped {
+            Some(Crop {
+                x: reader.read_u16()?,
+                y: reader.read_u16()?,
+                width: reader.read_u16()?,
+                height: reader.read_u16()?,
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            object_id,
+            window_id,
+            forced,
+            x,
+            y,
+            crop,
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.object_id.to_be_bytes());
+        out.push(self.window_id);
+
+        let mut flags = 0;
+        if self.crop.is_some() {
+            flags |= 0x80;
+        }
+        if self.forced {
+            flags |= 0x40;
+        }
+        out.push(flags);
+
+        out.extend_from_slice(&self.x.to_be_bytes());
+        out.extend_from_slice(&self.y.to_be_bytes());
+
+        if let Some(crop) = &self.crop {
+            out.extend_from_slice(&crop.x.to_be_bytes());
+            out.extend_from_slice(&crop.y.to_be_bytes());
+            out.extend_from_slice(&crop.width.to_be_bytes());
+            out.extend_from_slice(&crop.height.to_be_bytes());
+        }
+    }
+}
+
+impl WindowDefinition {
+    fn parse(reader: &mut ByteReader) -> Result<Self> {
+        let count = reader.read_u8()?;
+        let windows = (0..count)
+            .map(|_| {
+                Ok(Window {
+                    id: reader.read_u8()?,
+                    x: reader.read_u16()?,
+                    y: reader.read_u16()?,
+                    width: reader.read_u16()?,
+                    height: reader.read_u16()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { windows })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.windows.len() as u8);
+
+        for window in &self.windows {
+            out.push(window.id);
+            out.extend_from_slice(&window.x.to_be_bytes());
+            out.extend_from_slice(&window.y.to_be_bytes());
+            out.extend_from_slice(&window.width.to_be_bytes());
+            out.extend_from_slice(&window.height.to_be_bytes());
+        }
+    }
+}
+
+impl PaletteDefinition {
+    fn parse(reader: &mut ByteReader) -> Result<Self> {
+        let id = reader.read_u8()?;
+        let version = reader.read_u8()?;
+
+        if reader.remaining() % 5 != 0 {
+            return Err(parse_error("PDS size is not a multiple of the entry size"));
+        }
+
+        let entries = (0..reader.remaining() / 5)
+            .map(|_| {
+                Ok(PaletteEntry {
+                    id: reader.read_u8()?,
+                    y: reader.read_u8()?,
+                    cr: reader.read_u8()?,
+                    cb: reader.read_u8()?,
+                    alpha: reader.read_u8()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            id,
+            version,
+            entries,
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.id);
+        out.push(self.version);
+
+        for entry in &self.entries {
+            out.extend_from_slice(&[entry.id, entry.y, entry.cr, entry.cb, entry.alpha]);
+        }
+    }
+}
+
+impl PaletteEntry {
+    /// Converts the limited range YCbCr color to full range RGB, in the 0-255 range.
+    pub fn to_rgb(&self, matrix: Matrix) -> [f32; 3] {
+        let (kr, kb) = matrix.coefficients();
+        let kg = 1.0 - kr - kb;
+
+        let y = (self.y as f32 - 16.0) / 219.0;
+        let cb = (self.cb as f32 - 128.0) / 224.0;
+        let cr = (self.cr as f32 - 128.0) / 224.0;
+
+        let r = y + 2.0 * (1.0 - kr) * cr;
+        let b = y + 2.0 * (1.0 - kb) * cb;
+        let g = (y - kr * r - kb * b) / kg;
+
+        [r, g, b].map(|c| (c * 255.0).clamp(0.0, 255.0))
+    }
+
+    /// Sets the color from full range RGB values in the 0-255 range.
+    pub fn set_rgb(&mut self, rgb: [f32; 3], matrix: Matrix) {
+        let (kr, kb) = matrix.coefficients();
+        let kg = 1.0 - kr - kb;
+
+        let [r, g, b] = rgb.map(|c| c.clamp(0.0, 255.0) / 255.0);
+
+        let y = kr * r + kg * g + kb * b;
+        let cb = (b - y) / (2.0 * (1.0 - kb));
+        let cr = (r - y) / (2.0 * (1.0 - kr));
+
+        self.y = (16.0 + 219.0 * y).round().clamp(16.0, 235.0) as u8;
+        self.cb = (128.0 + 224.0 * cb).round().clamp(16.0, 240.0) as u8;
+        self.cr = (128.0 + 224.0 * cr).round().clamp(16.0, 240.0) as u8;
+    }
+}
+
+impl ObjectDefinition {
+    fn parse(reader: &mut ByteReader) -> Result<Self> {
+        let id = reader.read_u16()?;
+        let version = reader.read_u8()?;
+
+        let sequence = reader.read_u8()?;
+        let first_in_sequence = sequence & 0x80 != 0;
+        let last_in_sequence = sequence & 0x40 != 0;
+
+        let header = if first_in_sequence {
+            Some(ObjectHeader {
+                data_length: reader.read_u24()?,
+                width: reader.read_u16()?,
+                height: reader.read_u16()?,
+            })
+        } else {
+            None
+        };
+
+        let data = reader.read_bytes(reader.remaining())?.to_vec();
+
+        Ok(Self {
+            id,
+            version,
+            first_in_sequence,
+            last_in_sequence,
+            header,
+            data,
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.push(self.version);
+
+        let mut sequence = 0;
+        if self.first_in_sequence {
+            sequence |= 0x80;
+        }
+        if self.last_in_sequence {
+            sequence |= 0x40;
+        }
+        out.push(sequence);
+
+        if let Some(header) = &self.header {
+            out.extend_from_slice(&header.data_length.to_be_bytes()[1..]);
+            out.extend_from_slice(&header.width.to_be_bytes());
+            out.extend_from_slice(&header.height.to_be_bytes());
+        }
+
+        out.extend_from_slice(&self.data);
+    }
+
+    /// Splits complete run-length encoded object data into as many ODS fragments as needed
+    /// for every segment to fit within the maximum segment size.
+    pub fn fragments(id: u16, version: u8, width: u16, height: u16, data: &[u8]) -> Vec<Self> {
+        let first_len = data.len().min(MAX_SEGMENT_SIZE - ODS_FIRST_HEADER_SIZE);
+        let (first, rest) = data.split_at(first_len);
+
+        let chunks = std::iter::once(first).chain(rest.chunks(MAX_SEGMENT_SIZE - ODS_HEADER_SIZE));
+        let count = 1 + rest.len().div_ceil(MAX_SEGMENT_SIZE - ODS_HEADER_SIZE);
+
+        chunks
+            .enumerate()
+            .map(|(i, chunk)| Self {
+                id,
+                version,
+                first_in_sequence: i == 0,
+                last_in_sequence: i == count - 1,
+                header: (i == 0).then_some(ObjectHeader {
+                    data_length: (data.len() + 4) as u32,
+                    width,
+                    height,
+                }),
+                data: chunk.to_vec(),
+            })
+            .collect()
+    }
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.remaining() < len {
+            return Err(parse_error("Segment is truncated"));
+        }
+
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16> {
+        let b = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn read_u24(&mut self) -> Result<u32> {
+        let b = self.read_bytes(3)?;
+        Ok(u32::from_be_bytes([0, b[0], b[1], b[2]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let b = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn fragments_fill_segments_exactly() {
+        let first = MAX_SEGMENT_SIZE - ODS_FIRST_HEADER_SIZE;
+        let next = MAX_SEGMENT_SIZE - ODS_HEADER_SIZE;
+
+        let single = ObjectDefinition::fragments(1, 0, 8, 8, &vec![0xAA; first]);
+        assert_eq!(single.len(), 1);
+        assert!(single[0].first_in_sequence && single[0].last_in_sequence);
+
+        let data: Vec<u8> = (0..first + next + 1).map(|i| i as u8).collect();
+        let fragments = ObjectDefinition::fragments(1, 0, 8, 8, &data);
+
+        let sizes: Vec<usize> = fragments.iter().map(|f| f.data.len()).collect();
+        assert_eq!(sizes, [first, next, 1]);
+        assert_eq!(
+            fragments[0].header.unwrap().data_length as usize,
+            data.len() + 4
+        );
+        assert!(fragments[1..].iter().all(|f| f.header.is_none()));
+        assert!(fragments[..2].iter().all(|f| !f.last_in_sequence));
+        assert!(fragments[2].last_in_sequence);
+
+        for fragment in fragments {
+            let mut payload = Vec::new();
+            fragment.write(&mut payload);
+            assert!(payload.len() <= MAX_SEGMENT_SIZE);
+        }
+    }
+
+    #[test]
+    fn palette_colors_round_trip_with_their_matrix() {
+        for matrix in [Matrix::Bt709, Matrix::Bt2020] {
+            for rgb in [[255.0, 0.0, 0.0], [0.0, 200.0, 40.0], [255.0, 255.0, 0.0]] {
+                let mut entry = PaletteEntry {
+                    id: 0,
+                    y: 16,
+                    cr: 128,
+                    cb: 128,
+                    alpha: 255,
+                };
+                entry.set_rgb(rgb, matrix);
+
+                let decoded = entry.to_rgb(matrix);
+                assert!(
+                    decoded.iter().zip(rgb).all(|(a, b)| (a - b).abs() < 2.0),
+                    "{matrix:?} {rgb:?} became {decoded:?}"
+                );
+            }
+        }
+
+        let mut red = PaletteEntry {
+            id: 0,
+            y: 16,
+            cr: 128,
+            cb: 128,
+            alpha: 255,
+        };
+        red.set_rgb([255.0, 0.0, 0.0], Matrix::Bt2020);
+        assert!(red.to_rgb(Matrix::Bt709)[1] > 10.0);
+    }
+
+    #[test]
+    fn oversized_segment_is_rejected() {
+        let segment = Segment {
+            pts: 0,
+            dts: 0,
+            data: SegmentData::Ods(ObjectDefinition {
+                id: 0,
+                version: 0,
+                first_in_sequence: false,
+                last_in_sequence: true,
+                header: None,
+                data: vec![0; MAX_SEGMENT_SIZE],
+            }),
+        };
+
+        assert!(matches!(
+            segment.write(&mut Vec::new()),
+            Err(Error::Encode(_))
+        ));
+    }
+
+    #[test]
+    fn reserved_flag_bits_are_cleared() {
+        // 1920x1080 epoch start, palette update with a reserved bit, palette 0 and 1 object
+        let composition = [0x07, 0x80, 0x04, 0x38, 0x10, 0, 0, 0x80, 0x81, 0, 1];
+        // Object 0 in window 0 at 0,0, forced with a reserved bit
+        let object = [0, 0, 0, 0x41, 0, 0, 0, 0];
+        let payload = [&composition[..], &object].concat();
+        let mut bytes = [
+            &SEGMENT_MAGIC[..],
+            &[0; 8],
+            &[SegmentType::Pcs as u8, 0, payload.len() as u8],
+            &payload,
+        ]
+        .concat();
+
+        let (segment, _) = Segment::parse(&bytes).unwrap();
+        let SegmentData::Pcs(pcs) = &segment.data else {
+            panic!("Not a PCS");
+        };
+        assert!(pcs.palette_update && pcs.objects[0].forced);
+
+        let mut written = Vec::new();
+        segment.write(&mut written).unwrap();
+
+        bytes[SEGMENT_HEADER_SIZE + 8] = 0x80;
+        bytes[SEGMENT_HEADER_SIZE + 14] = 0x40;
+        assert_eq!(written, bytes);
+    }
+}
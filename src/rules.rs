@@ -0,0 +1,215 @@
+//! Rules mapping source colors to a target color and brightness, evaluated for every color.
+//!
+//! A rule is written `SOURCE[:TOLERANCE]=TARGET[@PERCENTAGE]`:
+//! - `SOURCE`: `RRGGBB` hexadecimal color, or `*` for every color
+//! - `TOLERANCE`: maximum CIELAB distance (ΔE) to the source color, defaults to [`DEFAULT_TOLERANCE`]
+//! - `TARGET`: `RRGGBB` base color, or `keep` to keep the subtitle's color
+//! - `PERCENTAGE`: percentage replacing the ratio
+//!
+//! For example `ffff00:30=ffffff@55` followed by `*=keep@70`.
+//! The first matching rule is used.
+//!
+//! The percentage applies to the source color, which ends up at exactly that level of the target,
+//! and the other matched colors keep their lightness relative to it.
+//! `*` rules have no source color, their percentage applies to the reference brightness like the ratio.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::color::{delta_e, parse_hex, rgb_to_lab};
+use crate::{Error, Result};
+
+pub const DEFAULT_TOLERANCE: f32 = 20.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorRule {
+    /// RGB color matched by the rule, `None` matches every color
+    pub source: Option<[f32; 3]>,
+    /// Maximum CIELAB distance to `source`
+    pub tolerance: f32,
+    /// Base color, like `fixed`. `None` keeps the subtitle's color.
+    pub target: Option<[f32; 3]>,
+    /// Replaces the ratio, for the source color instead of the reference brightness.
+    /// With `target_nits`, scales the target luminance instead.
+    pub ratio: Option<f32>,
+}
+
+impl ColorRule {
+    pub fn matches(&self, rgb: [f32; 3]) -> bool {
+        self.source.map_or(true, |source| {
+            delta_e(rgb_to_lab(source), rgb_to_lab(rgb)) <= self.tolerance
+        })
+    }
+}
+
+impl FromStr for ColorRule {
+    type Err = Error;
+
+    fn from_str(rule: &str) -> Result<Self> {
+        let invalid = |message: &str| Error::Rule {
+            rule: rule.to_owned(),
+            message: message.to_owned(),
+        };
+        let parse_color = |value: &str| {
+            parse_hex(value)
+                .map(|c| c.map(f32::from))
+                .ok_or_else(|| invalid("expected a RRGGBB hexadecimal color"))
+        };
+
+        let (source, target) = rule
+            .split_once('=')
+            .ok_or_else(|| invalid("expected SOURCE=TARGET"))?;
+
+        let (source, tolerance) = match source.trim().split_once(':') {
+            Some((source, tolerance)) => (source.trim(), Some(tolerance.trim())),
+            None => (source.trim(), None),
+        };
+        let source = match source {
+            "*" => None,
+            source => Some(parse_color(source)?),
+        };
+        let tolerance = match tolerance {
+            Some(tolerance) => tolerance
+                .parse()
+                .map_err(|_| invalid("invalid tolerance"))?,
+            None => DEFAULT_TOLERANCE,
+        };
+
+        let (target, percentage) = match target.trim().split_once('@') {
+            Some((target, percentage)) => (target.trim(), Some(percentage.trim())),
+            None => (target.trim(), None),
+        };
+        let target = match target {
+            "keep" => None,
+            target => Some(parse_color(target)?),
+        };
+        let ratio = match percentage {
+            Some(percentage) => Some(
+                percentage
+                    .parse::<f32>()
+                    .map_err(|_| invalid("invalid percentage"))?
+                    / 100.0,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            source,
+            tolerance,
+            target,
+            ratio,
+        })
+    }
+}
+
+/// Reads rules from a file, one per line. Lines starting with `#` are ignored.
+pub fn read_rules_file<P: AsRef<Path>>(path: P) -> Result<Vec<ColorRule>> {
+    let content = fs::read_to_string(path)?;
+
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    const YELLOW: [f32; 3] = [255.0, 255.0, 0.0];
+    const WHITE: [f32; 3] = [255.0; 3];
+
+    #[test]
+    fn parse_rules() {
+        let rule: ColorRule = "ffff00:30=ffffff@55".parse().unwrap();
+        assert_eq!(
+            rule,
+            ColorRule {
+                source: Some(YELLOW),
+                tolerance: 30.0,
+                target: Some(WHITE),
+                ratio: Some(0.55),
+            }
+        );
+
+        let rule: ColorRule = " * = keep @ 70 ".parse().unwrap();
+        assert_eq!(
+            rule,
+            ColorRule {
+                source: None,
+                tolerance: DEFAULT_TOLERANCE,
+                target: None,
+                ratio: Some(0.7),
+            }
+        );
+
+        let rule: ColorRule = "FFFF00:2.5=keep".parse().unwrap();
+        assert_eq!((rule.tolerance, rule.target, rule.ratio), (2.5, None, None));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for rule in [
+            "ffff00",
+            "ffff0=ffffff",
+            "yellow=ffffff",
+            "ffff00=white",
+            "ffff00:=ffffff",
+            "ffff00:high=ffffff",
+            "ffff00=ffffff@",
+            "ffff00=ffffff@55%",
+            "*:10=keep@",
+        ] {
+            let error = rule.parse::<ColorRule>().unwrap_err();
+            assert!(
+                matches!(&error, Error::Rule { rule: r, .. } if r == rule),
+                "{rule}"
+            );
+        }
+    }
+
+    #[test]
+    fn matching() {
+        let rule: ColorRule = "ffff00=keep".parse().unwrap();
+        assert!(rule.matches(YELLOW));
+        assert!(rule.matches([250.0, 245.0, 20.0]));
+        assert!(!rule.matches(WHITE));
+        assert!(!rule.matches([255.0, 128.0, 0.0]));
+
+        // The distance is in CIELAB ΔE
+        let near = [230.0, 230.0, 0.0];
+        let distance = delta_e(rgb_to_lab(YELLOW), rgb_to_lab(near));
+        let rule = |tolerance: f32| ColorRule { tolerance, ..rule };
+        assert!(rule(distance + 0.01).matches(near));
+        assert!(!rule(distance - 0.01).matches(near));
+        assert!(!rule(0.0).matches(near) && rule(0.0).matches(YELLOW));
+
+        let any: ColorRule = "*=keep".parse().unwrap();
+        assert!([YELLOW, WHITE, [0.0; 3]]
+            .into_iter()
+            .all(|rgb| any.matches(rgb)));
+    }
+
+    #[test]
+    fn rules_file() {
+        let workspace = Workspace::new(None, false).unwrap();
+        let path = workspace.path().join("rules.txt");
+        fs::write(
+            &path,
+            "# Yellow dialogue\nffff00:30=ffffff@55\n\n  *=keep@70\n",
+        )
+        .unwrap();
+
+        let rules = read_rules_file(&path).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].source, Some(YELLOW));
+        assert_eq!(rules[1].source, None);
+
+        fs::write(&path, "ffff00=ffffff\nffff00\n").unwrap();
+        assert!(matches!(read_rules_file(&path), Err(Error::Rule { .. })));
+    }
+}
@@ -0,0 +1,156 @@
+//! Statistics over the measured colors, used to choose the reference brightness.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Statistic {
+    /// Brightest color
+    #[default]
+    Max,
+    /// Percentile of the colors, from 0 to 100, weighted by pixel count and alpha.
+    /// Ignores a few stray bright pixels, unlike `Max`.
+    Percentile(f32),
+    /// Mean of the colors, weighted by pixel count and alpha
+    Mean,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// Brightness from [`crate::TonemapOptions::measure`]
+    pub value: f32,
+    /// Number of pixels, or 1 when the pixels are not known
+    pub weight: f32,
+    pub alpha: u8,
+}
+
+impl Sample {
+    /// Weight scaled by the opacity, so that faint pixels count less
+    fn coverage(&self) -> f64 {
+        self.weight as f64 * self.alpha as f64 / 255.0
+    }
+}
+
+/// Measured colors of a subtitle, or of a group of subtitles
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Samples {
+    samples: Vec<Sample>,
+}
+
+impl Samples {
+    pub fn push(&mut self, value: f32, weight: f32, alpha: u8) {
+        if weight > 0.0 {
+            self.samples.push(Sample {
+                value,
+                weight,
+                alpha,
+            });
+        }
+    }
+
+    pub fn append(&mut self, other: &mut Self) {
+        self.samples.append(&mut other.samples);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Reference brightness of the samples, 0 when empty
+    pub fn reference(&self, statistic: Statistic) -> f32 {
+        match statistic {
+            Statistic::Max => self.samples.iter().map(|s| s.value).fold(0.0, f32::max),
+            Statistic::Percentile(percentile) => self.percentile(percentile),
+            Statistic::Mean => {
+                let (sum, total) = self.samples.iter().fold((0.0, 0.0), |(sum, total), s| {
+                    let weight = s.coverage();
+                    (sum + s.value as f64 * weight, total + weight)
+                });
+
+                if total > 0.0 {
+                    (sum / total) as f32
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    fn percentile(&self, percentile: f32) -> f32 {
+        let mut sorted = self.samples.clone();
+        sorted.sort_by(|a, b| a.value.total_cmp(&b.value));
+
+        let total: f64 = sorted.iter().map(Sample::coverage).sum();
+        let threshold = total * percentile.clamp(0.0, 100.0) as f64 / 100.0;
+
+        let mut cumulative = 0.0;
+        for sample in &sorted {
+            cumulative += sample.coverage();
+
+            if cumulative >= threshold {
+                return sample.value;
+            }
+        }
+
+        sorted.last().map_or(0.0, |s| s.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(values: &[(f32, f32, u8)]) -> Samples {
+        let mut samples = Samples::default();
+        for &(value, weight, alpha) in values {
+            samples.push(value, weight, alpha);
+        }
+        samples
+    }
+
+    #[test]
+    fn max() {
+        let samples = samples(&[(0.2, 10.0, 255), (0.9, 1.0, 10), (0.5, 100.0, 255)]);
+        assert_eq!(samples.reference(Statistic::Max), 0.9);
+
+        // Samples without pixels are not displayed
+        let samples = self::samples(&[(0.2, 10.0, 255), (0.9, 0.0, 255)]);
+        assert_eq!(samples.reference(Statistic::Max), 0.2);
+
+        assert!(Samples::default().is_empty());
+        for statistic in [Statistic::Max, Statistic::Percentile(50.0), Statistic::Mean] {
+            assert_eq!(Samples::default().reference(statistic), 0.0);
+        }
+    }
+
+    #[test]
+    fn percentile() {
+        // 1000 pixels of fill, 2 stray bright pixels
+        let samples = samples(&[(0.9, 2.0, 255), (0.3, 200.0, 255), (0.6, 798.0, 255)]);
+
+        assert_eq!(samples.reference(Statistic::Percentile(0.0)), 0.3);
+        assert_eq!(samples.reference(Statistic::Percentile(20.0)), 0.3);
+        assert_eq!(samples.reference(Statistic::Percentile(20.1)), 0.6);
+        assert_eq!(samples.reference(Statistic::Percentile(99.7)), 0.6);
+        assert_eq!(samples.reference(Statistic::Percentile(99.9)), 0.9);
+        assert_eq!(samples.reference(Statistic::Percentile(100.0)), 0.9);
+        assert_eq!(samples.reference(Statistic::Percentile(150.0)), 0.9);
+
+        // Faint pixels count less
+        let samples = self::samples(&[(0.3, 100.0, 255), (0.6, 100.0, 51)]);
+        assert_eq!(samples.reference(Statistic::Percentile(80.0)), 0.3);
+        assert_eq!(samples.reference(Statistic::Percentile(90.0)), 0.6);
+    }
+
+    #[test]
+    fn mean() {
+        let samples = samples(&[(0.2, 3.0, 255), (0.6, 1.0, 255)]);
+        assert!((samples.reference(Statistic::Mean) - 0.3).abs() < 1e-6);
+
+        // Weighted by alpha, transparent samples do not count
+        let samples = self::samples(&[(0.2, 1.0, 255), (0.8, 2.0, 0), (0.5, 2.0, 51)]);
+        let mean = (0.2 + 0.5 * 0.4) / 1.4;
+        assert!((samples.reference(Statistic::Mean) - mean).abs() < 1e-6);
+
+        let mut all = self::samples(&[(0.2, 1.0, 255)]);
+        all.append(&mut self::samples(&[(0.4, 1.0, 255)]));
+        assert!((all.reference(Statistic::Mean) - 0.3).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,931 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use crate::classify::Classification;
+use crate::color::{
+    bt1886_eotf, bt1886_inverse_eotf, get_lightness, should_edit, LightnessModel, Matrix, Transfer,
+    HDR_WHITE_NITS, SDR_WHITE_NITS,
+};
+use crate::curve::ToneCurve;
+use crate::error::Result;
+use crate::pgs::{CompositionState, PaletteDefinition, PgsStream};
+use crate::rules::ColorRule;
+use crate::stats::{Samples, Statistic};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Decodes the subtitle bitmaps and measures the pixels that are actually displayed
+    #[default]
+    Image,
+    /// Edits the palette entries directly, leaving the bitmaps untouched
+    Palette,
+}
+
+/// Group of subtitles sharing the same reference brightness
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Scope {
+    /// Every display set separately
+    #[default]
+    Event,
+    /// Display sets of the same epoch, from one epoch start to the next
+    Epoch,
+    /// The whole file
+    File,
+}
+
+/// Display sets to process, according to the forced flags of their objects
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ForcedFilter {
+    #[default]
+    All,
+    /// Only the display sets with a forced object
+    Forced,
+    /// Only the display sets without any forced object
+    NonForced,
+}
+
+impl ForcedFilter {
+    pub fn matches(&self, forced: bool) -> bool {
+        match self {
+            Self::All => true,
+            Self::Forced => forced,
+            Self::NonForced => !forced,
+        }
+    }
+}
+
+/// What to do with colors that end up out of range after scaling
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GamutMapping {
+    /// Clips every channel separately, which can shift the hue
+    #[default]
+    Clip,
+    /// Scales all the channels down by the same factor, reducing the lightness instead
+    Scale,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TonemapOptions {
+    /// Multiplier for the final color of the subtitle
+    pub ratio: f32,
+    /// Use `color` as base color instead of the subtitle's original color
+    pub fixed: bool,
+    /// RGB base color for `fixed`
+    pub color: [f32; 3],
+    /// With `fixed`, keep the hue and saturation of the subtitle's colors
+    /// and only map their lightness to the lightness of `color`
+    pub preserve_hue: bool,
+    pub gamut: GamutMapping,
+    /// Scale the colors in absolute luminance so that the brightest color
+    /// reaches this luminance, in nits. Replaces `ratio` when set.
+    pub target_nits: Option<f32>,
+    /// Transfer function used to decode the colors for `target_nits`
+    pub transfer: Transfer,
+    /// YCbCr matrix of the palette entries.
+    /// Defaults to BT.709, or BT.2020 with `target_nits`.
+    pub matrix: Option<Matrix>,
+    /// Model used to measure and scale the lightness of the colors.
+    /// Defaults to HSL, or BT.2020 luminance with `target_nits`.
+    pub lightness: Option<LightnessModel>,
+    /// Curve mapping the reference lightness to the ratio or `target_nits`
+    pub curve: ToneCurve,
+    /// Subtitles sharing the same reference brightness.
+    /// The BDSup2Sub processing has no epochs and uses the whole file for `Epoch`.
+    pub scope: Scope,
+    /// Statistic of the measured colors used as reference brightness
+    pub statistic: Statistic,
+    /// Colors with an alpha at or below the threshold are transparent:
+    /// ignored by the analysis and left untouched
+    pub alpha_threshold: u8,
+    /// Treat semi-transparent colors as premultiplied by their alpha, like anti-aliased edges
+    /// often look. They are measured and tonemapped as their opaque color.
+    pub premultiplied: bool,
+    /// Multiplier for the alpha of the subtitle, independently from the color
+    pub opacity: f32,
+    /// Separate ratio and base color for the fill, edges and outline
+    pub classification: Option<Classification>,
+    /// Source color to target color rules, the first matching rule overrides the ratio and base color
+    pub rules: Vec<ColorRule>,
+    /// Display sets to process, the others are left untouched.
+    /// Palettes are edited in the display set defining them, so a display set of the same epoch
+    /// reusing the palette of a processed display set is affected too.
+    /// Not supported by the BDSup2Sub processing.
+    pub forced: ForcedFilter,
+    pub mode: Mode,
+}
+
+impl Default for TonemapOptions {
+    fn default() -> Self {
+        Self {
+            ratio: 0.6,
+            fixed: false,
+            color: [255.0, 255.0, 255.0],
+            preserve_hue: false,
+            gamut: GamutMapping::default(),
+            target_nits: None,
+            transfer: Transfer::default(),
+            matrix: None,
+            lightness: None,
+            curve: ToneCurve::default(),
+            scope: Scope::default(),
+            statistic: Statistic::default(),
+            alpha_threshold: 0,
+            premultiplied: false,
+            opacity: 1.0,
+            classification: None,
+            rules: Vec::new(),
+            forced: ForcedFilter::default(),
+            mode: Mode::default(),
+        }
+    }
+}
+
+impl TonemapOptions {
+    pub fn lightness_model(&self) -> LightnessModel {
+        match self.lightness {
+            Some(model) => model,
+            None if self.target_nits.is_some() => LightnessModel::Bt2020,
+            None => LightnessModel::Hsl,
+        }
+    }
+
+    pub fn matrix(&self) -> Matrix {
+        match self.matrix {
+            Some(matrix) => matrix,
+            None if self.target_nits.is_some() => Matrix::Bt2020,
+            None => Matrix::Bt709,
+        }
+    }
+
+    /// Brightness of an RGB color, as used for the reference `old_max`.
+    /// This is the lightness from [`Self::lightness_model`], in nits for the luminance models.
+    #[inline(always)]
+    pub fn measure(&self, rgb: [f32; 3]) -> f32 {
+        match self.lightness_model() {
+            LightnessModel::Hsl => get_lightness(rgb[0], rgb[1], rgb[2]),
+            model => model.lightness(self.decode(rgb), self.white_nits()),
+        }
+    }
+
+    /// Whether a color is visible, according to `alpha_threshold`
+    #[inline(always)]
+    pub fn is_visible(&self, alpha: u8) -> bool {
+        alpha > self.alpha_threshold
+    }
+
+    /// [`Self::measure`] of a color with its alpha, undoing the premultiplication
+    #[inline(always)]
+    pub fn measure_rgba(&self, rgb: [f32; 3], alpha: u8) -> f32 {
+        self.measure(self.unpremultiply(rgb, alpha))
+    }
+
+    /// Skips black and transparent colors
+    #[inline(always)]
+    pub fn should_edit(&self, rgb: [f32; 3], alpha: u8) -> bool {
+        self.is_visible(alpha) && should_edit(rgb[0], rgb[1], rgb[2], alpha)
+    }
+
+    /// [`Self::tonemap_rgb`] of a color with its alpha, undoing the premultiplication
+    pub fn tonemap_rgba(&self, rgb: [f32; 3], alpha: u8, old_max: f32) -> [f32; 3] {
+        let mapped = self.tonemap_rgb(self.unpremultiply(rgb, alpha), old_max);
+
+        // Keep the edge as faint as it was
+        if self.premultiplied {
+            mapped.map(|c| (c * alpha as f32 / 255.0).round())
+        } else {
+            mapped
+        }
+    }
+
+    #[inline(always)]
+    pub fn scale_opacity(&self, alpha: u8) -> u8 {
+        (alpha as f32 * self.opacity).round().clamp(0.0, 255.0) as u8
+    }
+
+    #[inline(always)]
+    fn unpremultiply(&self, rgb: [f32; 3], alpha: u8) -> [f32; 3] {
+        if self.premultiplied && alpha > 0 {
+            rgb.map(|c| (c * 255.0 / alpha as f32).min(255.0))
+        } else {
+            rgb
+        }
+    }
+
+    /// Maps the lightness of an RGB color with the tone curve, keeping its hue or using the fixed color.
+    /// `old_max` is the reference brightness from [`Self::measure`], mapped to the ratio or `target_nits`.
+    /// A matching rule with a source color maps the source color instead.
+    #[inline(always)]
+    pub fn tonemap_rgb(&self, rgb: [f32; 3], old_max: f32) -> [f32; 3] {
+        // Nothing to use as reference, fallback to white
+        let old_max = if old_max > 0.0 {
+            old_max
+        } else {
+            self.measure([255.0; 3])
+        };
+
+        let lightness = self.measure(rgb);
+        let grade = self.grade(rgb, lightness, old_max);
+        let old_max = grade.reference.unwrap_or(old_max);
+
+        let color = &grade.color;
+        let recolor = grade.fixed && !self.preserve_hue;
+        let base = if recolor { *color } else { rgb };
+
+        let peak_out = match self.target_nits {
+            Some(target_nits) => self.measure_linear([target_nits * grade.target_gain; 3]),
+            None if grade.fixed => self.measure(*color) * grade.ratio,
+            None => old_max * grade.ratio,
+        };
+
+        let lightness = self.map_lightness(lightness, old_max, peak_out);
+        let mapped = self.with_lightness(base, lightness);
+
+        // The fixed color is also the brightest allowed
+        let ceiling = if recolor && self.target_nits.is_none() {
+            *color
+        } else {
+            [255.0; 3]
+        };
+
+        let mapped = match self.gamut {
+            GamutMapping::Clip => mapped,
+            GamutMapping::Scale => {
+                let over = (0..3)
+                    .filter(|&i| ceiling[i] > 0.0)
+                    .map(|i| mapped[i] / ceiling[i])
+                    .fold(1.0, f32::max);
+
+                mapped.map(|c| c / over)
+            }
+        };
+
+        [
+            mapped[0].round().clamp(0.0, ceiling[0]),
+            mapped[1].round().clamp(0.0, ceiling[1]),
+            mapped[2].round().clamp(0.0, ceiling[2]),
+        ]
+    }
+
+    /// Ratio and base color for a color, with the overrides of its class and of the matching rule
+    fn grade(&self, rgb: [f32; 3], lightness: f32, old_max: f32) -> Grade {
+        let mut grade = Grade {
+            ratio: self.ratio,
+            target_gain: 1.0,
+            fixed: self.fixed,
+            color: self.color,
+            reference: None,
+        };
+
+        if let Some(classification) = &self.classification {
+            let class = classification.classify(lightness, old_max);
+            let overrides = classification.options(class);
+
+            if let Some(ratio) = overrides.ratio {
+                grade.ratio = ratio;
+                grade.target_gain = ratio;
+            }
+            if let Some(color) = overrides.color {
+                grade.fixed = true;
+                grade.color = color;
+            }
+        }
+
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches(rgb)) {
+            if let Some(ratio) = rule.ratio {
+                grade.ratio = ratio;
+                grade.target_gain = ratio;
+            }
+
+            grade.fixed = rule.target.is_some();
+            if let Some(color) = rule.target {
+                grade.color = color;
+            }
+
+            // The source color itself reaches the stated level
+            grade.reference = rule
+                .source
+                .map(|source| self.measure(source))
+                .filter(|&reference| reference > 0.0);
+        }
+
+        grade
+    }
+
+    /// Applies the tone curve to a lightness from [`Self::measure`].
+    /// The BT.2390 EETF works on luminance, so other models go through the luminance of an equally light grey.
+    fn map_lightness(&self, lightness: f32, peak_in: f32, peak_out: f32) -> f32 {
+        let is_luminance = matches!(
+            self.lightness_model(),
+            LightnessModel::Bt709 | LightnessModel::Bt2020
+        );
+
+        if !matches!(self.curve, ToneCurve::Bt2390 { .. }) || is_luminance {
+            return self.curve.apply(lightness, peak_in, peak_out);
+        }
+
+        let grey_nits =
+            |lightness| solve(|y| self.measure_linear([y; 3]), lightness, self.peak_nits());
+        let nits = self.curve.apply(
+            grey_nits(lightness),
+            grey_nits(peak_in),
+            grey_nits(peak_out),
+        );
+
+        self.measure_linear([nits; 3])
+    }
+
+    /// Scales an RGB color in linear light so that its lightness becomes `lightness`
+    fn with_lightness(&self, rgb: [f32; 3], lightness: f32) -> [f32; 3] {
+        if let LightnessModel::Hsl = self.lightness_model() {
+            let current = get_lightness(rgb[0], rgb[1], rgb[2]);
+
+            return if current > 0.0 {
+                rgb.map(|c| c * lightness / current)
+            } else {
+                rgb
+            };
+        }
+
+        let linear = self.decode(rgb);
+        let max = linear[0].max(linear[1]).max(linear[2]);
+
+        if max <= 0.0 {
+            return rgb;
+        }
+
+        let scale = solve(
+            |k| self.measure_linear(linear.map(|c| c * k)),
+            lightness,
+            self.peak_nits() / max,
+        );
+
+        self.encode(linear.map(|c| c * scale))
+    }
+
+    #[inline(always)]
+    fn measure_linear(&self, linear: [f32; 3]) -> f32 {
+        match self.lightness_model() {
+            LightnessModel::Hsl => self.measure(self.encode(linear)),
+            model => model.lightness(linear, self.white_nits()),
+        }
+    }
+
+    /// Decodes code values to linear light in nits, SDR BT.1886 without `target_nits`
+    fn decode(&self, rgb: [f32; 3]) -> [f32; 3] {
+        if self.target_nits.is_some() {
+            self.transfer.to_linear(rgb)
+        } else {
+            rgb.map(|c| bt1886_eotf(c / 255.0) * SDR_WHITE_NITS)
+        }
+    }
+
+    /// Encodes linear light in nits back to code values
+    fn encode(&self, linear: [f32; 3]) -> [f32; 3] {
+        if self.target_nits.is_some() {
+            self.transfer.from_linear(linear)
+        } else {
+            linear.map(|c| bt1886_inverse_eotf(c / SDR_WHITE_NITS) * 255.0)
+        }
+    }
+
+    fn peak_nits(&self) -> f32 {
+        self.decode([255.0; 3])[0]
+    }
+
+    fn white_nits(&self) -> f32 {
+        if self.target_nits.is_some() {
+            HDR_WHITE_NITS
+        } else {
+            SDR_WHITE_NITS
+        }
+    }
+}
+
+/// Ratio and base color applied to one color
+struct Grade {
+    ratio: f32,
+    /// Multiplier for `target_nits`
+    target_gain: f32,
+    fixed: bool,
+    color: [f32; 3],
+    /// Brightness mapped to the ratio or `target_nits` instead of `old_max`
+    reference: Option<f32>,
+}
+
+/// Finds `x` in `[0, max]` for which the increasing `f(x)` reaches `target`, by bisection
+fn solve<F: Fn(f32) -> f32>(f: F, target: f32, max: f32) -> f32 {
+    let (mut lo, mut hi) = (0.0, max);
+
+    for _ in 0..32 {
+        let mid = (lo + hi) / 2.0;
+
+        if f(mid) < target {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+
+    (lo + hi) / 2.0
+}
+
+/// Loads a `.sup` file, tonemaps it and writes the result to `output`.
+pub fn tonemap_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+    opts: &TonemapOptions,
+) -> Result<()> {
+    let mut stream = PgsStream::from_path(input)?;
+
+    tonemap(&mut stream, opts)?;
+    stream.write_to_path(output)
+}
+
+pub fn tonemap(stream: &mut PgsStream, opts: &TonemapOptions) -> Result<()> {
+    match opts.mode {
+        Mode::Image => process_bitmaps(stream, opts),
+        Mode::Palette => {
+            process_palettes(stream, opts);
+            Ok(())
+        }
+    }
+}
+
+fn process_palettes(stream: &mut PgsStream, opts: &TonemapOptions) {
+    let samples = stream
+        .display_sets
+        .par_iter()
+        .map(|ds| {
+            ds.palettes()
+                .fold(Samples::default(), |mut samples, palette| {
+                    samples.append(&mut palette_samples(palette, opts, None));
+                    samples
+                })
+        })
+        .collect();
+
+    apply_references(stream, samples, opts);
+}
+
+fn process_bitmaps(stream: &mut PgsStream, opts: &TonemapOptions) -> Result<()> {
+    // Objects can be displayed again by later compositions of the same epoch
+    let mut bitmaps: HashMap<u16, Vec<u8>> = HashMap::new();
+    let mut samples = Vec::with_capacity(stream.display_sets.len());
+
+    for ds in stream.display_sets.iter() {
+        if ds.composition().composition_state == CompositionState::EpochStart {
+            bitmaps.clear();
+        }
+
+        for object in ds.complete_objects()? {
+            bitmaps.insert(object.id, object.decode()?);
+        }
+
+        let mut counts = [0; 256];
+        ds.composition()
+            .objects
+            .iter()
+            .filter_map(|obj| bitmaps.get(&obj.object_id))
+            .flatten()
+            .for_each(|&index| counts[index as usize] += 1);
+
+        // Nothing displayed, fallback to the whole palette
+        let counts = counts.iter().any(|&c| c > 0).then_some(&counts);
+
+        samples.push(
+            ds.palettes()
+                .fold(Samples::default(), |mut samples, palette| {
+                    samples.append(&mut palette_samples(palette, opts, counts));
+                    samples
+                }),
+        );
+    }
+
+    apply_references(stream, samples, opts);
+
+    Ok(())
+}
+
+/// Tonemaps the palettes of every display set, using the reference brightness
+/// of the samples grouped by the scope
+fn apply_references(stream: &mut PgsStream, samples: Vec<Samples>, opts: &TonemapOptions) {
+    let mut epoch = 0;
+    let groups: Vec<usize> = stream
+        .display_sets
+        .iter()
+        .enumerate()
+        .map(|(i, ds)| match opts.scope {
+            Scope::Event => i,
+            Scope::Epoch => {
+                if i > 0 && ds.composition().composition_state == CompositionState::EpochStart {
+                    epoch += 1;
+                }
+                epoch
+            }
+            Scope::File => 0,
+        })
+        .collect();
+
+    let selected: Vec<bool> = stream
+        .display_sets
+        .iter()
+        .map(|ds| opts.forced.matches(ds.is_forced()))
+        .collect();
+
+    let mut grouped = vec![Samples::default(); groups.iter().max().map_or(0, |&g| g + 1)];
+    for ((&group, mut samples), _) in groups
+        .iter()
+        .zip(samples)
+        .zip(&selected)
+        .filter(|(_, &selected)| selected)
+    {
+        grouped[group].append(&mut samples);
+    }
+
+    let references: Vec<f32> = grouped
+        .iter()
+        .map(|samples| samples.reference(opts.statistic))
+        .collect();
+
+    stream
+        .display_sets
+        .par_iter_mut()
+        .zip(groups)
+        .zip(selected)
+        .filter(|(_, selected)| *selected)
+        .for_each(|((ds, group), _)| {
+            ds.palettes_mut()
+                .for_each(|palette| tonemap_palette(palette, opts, references[group]))
+        });
+}
+
+/// Measures the visible palette entries.
+/// When `counts` is set, only the displayed entries are measured, weighted by their number of pixels.
+pub fn palette_samples(
+    palette: &PaletteDefinition,
+    opts: &TonemapOptions,
+    counts: Option<&[u32; 256]>,
+) -> Samples {
+    let mut samples = Samples::default();
+
+    for entry in palette.entries.iter().filter(|e| opts.is_visible(e.alpha)) {
+        let weight = counts.map_or(1, |counts| counts[entry.id as usize]);
+
+        samples.push(
+            opts.measure_rgba(entry.to_rgb(opts.matrix()), entry.alpha),
+            weight as f32,
+            entry.alpha,
+        );
+    }
+
+    samples
+}
+
+/// `old_max` is the reference brightness, usually from [`palette_samples`]
+pub fn tonemap_palette(palette: &mut PaletteDefinition, opts: &TonemapOptions, old_max: f32) {
+    palette.entries.iter_mut().for_each(|entry| {
+        let rgb = entry.to_rgb(opts.matrix());
+
+        if opts.should_edit(rgb, entry.alpha) {
+            let mapped = opts.tonemap_rgba(rgb, entry.alpha, old_max);
+
+            // Unchanged colors keep their exact YCbCr, even outside of the RGB gamut
+            if mapped.iter().zip(rgb).any(|(a, b)| (a - b).abs() >= 0.5) {
+                entry.set_rgb(mapped, opts.matrix());
+            }
+        }
+
+        entry.alpha = opts.scale_opacity(entry.alpha);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::{hlg_signal_to_nits, pq_inverse_eotf};
+    use crate::pgs::samples::{caption, composition, display_set, entry, placed, small_stream};
+    use crate::pgs::{Object, PaletteEntry};
+
+    #[test]
+    fn unchanged_entries_keep_their_bytes() {
+        let entries = vec![
+            // Out of the RGB gamut, clipped by the conversion
+            PaletteEntry {
+                id: 1,
+                y: 81,
+                cr: 240,
+                cb: 90,
+                alpha: 255,
+            },
+            PaletteEntry {
+                id: 2,
+                y: 235,
+                cr: 128,
+                cb: 128,
+                alpha: 128,
+            },
+        ];
+        let mut palette = PaletteDefinition {
+            id: 0,
+            version: 0,
+            entries: entries.clone(),
+        };
+
+        let opts = TonemapOptions {
+            ratio: 1.0,
+            mode: Mode::Palette,
+            ..Default::default()
+        };
+        let old_max = palette_samples(&palette, &opts, None).reference(opts.statistic);
+        tonemap_palette(&mut palette, &opts, old_max);
+
+        assert_eq!(palette.entries, entries);
+    }
+
+    #[test]
+    fn lightness_models_round_trip() {
+        for model in [
+            LightnessModel::Cielab,
+            LightnessModel::Ictcp,
+            LightnessModel::Jzazbz,
+        ] {
+            for target_nits in [None, Some(203.0)] {
+                let opts = TonemapOptions {
+                    lightness: Some(model),
+                    target_nits,
+                    ..Default::default()
+                };
+
+                for rgb in [[255.0; 3], [200.0, 120.0, 30.0], [20.0, 90.0, 240.0]] {
+                    let lightness = opts.measure(rgb);
+                    let same = opts.with_lightness(rgb, lightness);
+                    for (c, expected) in same.into_iter().zip(rgb) {
+                        assert!((c - expected).abs() < 0.05, "{model:?} {rgb:?}: {same:?}");
+                    }
+
+                    // Half as light, with the same proportions in linear light
+                    let darker = opts.with_lightness(rgb, lightness / 2.0);
+                    let error = (opts.measure(darker) / lightness - 0.5).abs();
+                    assert!(error < 1e-3, "{model:?} {rgb:?}: {darker:?}");
+
+                    let [r, g, b] = opts.decode(rgb);
+                    let [dr, dg, db] = opts.decode(darker);
+                    assert!((dr / dg - r / g).abs() < 1e-3 * r / g, "{model:?} {rgb:?}");
+                    assert!((db / dg - b / g).abs() < 1e-3 * b / g, "{model:?} {rgb:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scaled_gamut_preserves_hue() {
+        let opts = TonemapOptions {
+            ratio: 1.0,
+            fixed: true,
+            preserve_hue: true,
+            gamut: GamutMapping::Scale,
+            ..Default::default()
+        };
+        let orange = [255.0, 128.0, 0.0];
+        let old_max = opts.measure([128.0, 64.0, 0.0]);
+
+        // As light as white does not fit, the channels are scaled down together
+        let mapped = opts.tonemap_rgb([128.0, 64.0, 0.0], old_max);
+        assert_eq!(mapped, orange);
+
+        // Clipping the red channel shifts the orange to yellow
+        let opts = TonemapOptions {
+            gamut: GamutMapping::Clip,
+            ..opts
+        };
+        assert_eq!(
+            opts.tonemap_rgb([128.0, 64.0, 0.0], old_max),
+            [255.0, 255.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn target_nits() {
+        let opts = TonemapOptions {
+            target_nits: Some(203.0),
+            ..Default::default()
+        };
+        let code = |nits: f32| pq_inverse_eotf(nits) * 255.0;
+        let white = [code(1000.0); 3];
+        let old_max = opts.measure(white);
+        assert!((old_max - 1000.0).abs() < 5.0);
+
+        // The brightest color reaches the target, darker ones keep their share of it
+        assert_eq!(opts.tonemap_rgb(white, old_max), [code(203.0).round(); 3]);
+        assert_eq!(
+            opts.tonemap_rgb([code(500.0); 3], old_max),
+            [code(101.5).round(); 3]
+        );
+
+        // In linear light, the channels keep their proportions
+        let rgb = [code(900.0), code(300.0), code(30.0)];
+        let mapped = Transfer::Pq.to_linear(opts.tonemap_rgb(rgb, old_max));
+        let scale = mapped[1] / 300.0;
+        assert!((mapped[0] / scale - 900.0).abs() < 20.0, "{mapped:?}");
+        assert!((mapped[2] / scale - 30.0).abs() < 2.0, "{mapped:?}");
+    }
+
+    #[test]
+    fn hlg_level() {
+        // What `--hlg-level 75` sets
+        let peak_nits = 1000.0;
+        let opts = TonemapOptions {
+            target_nits: Some(hlg_signal_to_nits(0.75, peak_nits)),
+            transfer: Transfer::Hlg { peak_nits },
+            ..Default::default()
+        };
+
+        let old_max = opts.measure([255.0; 3]);
+        assert!((old_max - peak_nits).abs() < 1.0);
+        assert_eq!(opts.tonemap_rgb([255.0; 3], old_max), [191.0; 3]);
+
+        // Already at the level
+        let old_max = opts.measure([191.0; 3]);
+        assert_eq!(opts.tonemap_rgb([191.0; 3], old_max), [191.0; 3]);
+    }
+
+    /// Tonemaps a palette with the reference of its own entries
+    fn tonemap_entries(entries: Vec<PaletteEntry>, opts: &TonemapOptions) -> Vec<PaletteEntry> {
+        let mut palette = PaletteDefinition {
+            id: 0,
+            version: 0,
+            entries,
+        };
+        let old_max = palette_samples(&palette, opts, None).reference(opts.statistic);
+        tonemap_palette(&mut palette, opts, old_max);
+
+        palette.entries
+    }
+
+    #[test]
+    fn transparent_entries_are_untouched() {
+        let entries = vec![
+            entry(0, 235, 128, 128, 0),
+            entry(1, 235, 128, 128, 255),
+            entry(2, 235, 128, 128, 12),
+        ];
+        let opts = TonemapOptions {
+            alpha_threshold: 16,
+            ..Default::default()
+        };
+
+        let mapped = tonemap_entries(entries.clone(), &opts);
+        assert_eq!(mapped[0], entries[0]);
+        assert_eq!(mapped[2], entries[2]);
+        assert_ne!(mapped[1], entries[1]);
+    }
+
+    #[test]
+    fn premultiplied_entries_are_graded_opaque() {
+        // White at half opacity, premultiplied to grey
+        let entries = vec![entry(0, 16, 128, 128, 0), entry(1, 126, 128, 128, 128)];
+        let opts = TonemapOptions {
+            ratio: 0.5,
+            fixed: true,
+            ..Default::default()
+        };
+
+        // As straight alpha, the grey is the brightest color and ends at 50% white
+        let mapped = tonemap_entries(entries.clone(), &opts);
+        assert!(mapped[1].y.abs_diff(126) <= 1, "{:?}", mapped[1]);
+
+        // Premultiplied, the white ends at 50% white, then at half opacity
+        let opts = TonemapOptions {
+            premultiplied: true,
+            ..opts
+        };
+        let mapped = tonemap_entries(entries, &opts);
+        assert!(mapped[1].y.abs_diff(71) <= 1, "{:?}", mapped[1]);
+        assert_eq!(mapped[1].alpha, 128);
+    }
+
+    #[test]
+    fn unused_entries_are_not_measured() {
+        // Entry 3 is brighter than the displayed ones, but no pixel uses it
+        let object = Object::from_bitmap(0, 0, 4, 2, &[1, 1, 2, 2, 1, 0, 0, 2]);
+        let entries = vec![
+            entry(0, 16, 128, 128, 0),
+            entry(1, 126, 128, 128, 255),
+            entry(2, 60, 128, 128, 255),
+            entry(3, 235, 128, 128, 255),
+        ];
+        let luma = |mode| {
+            let mut stream = PgsStream {
+                display_sets: vec![caption(90_000, 0, false, entries.clone(), &object)],
+            };
+            let opts = TonemapOptions {
+                ratio: 0.5,
+                fixed: true,
+                mode,
+                ..Default::default()
+            };
+            tonemap(&mut stream, &opts).unwrap();
+
+            let palette = stream.display_sets[0].palettes().next().unwrap();
+            palette.entries[1].y
+        };
+
+        // The brightest displayed color reaches 50% white
+        assert!(luma(Mode::Image).abs_diff(126) <= 1);
+        // Measuring the whole palette makes it a quarter
+        assert!(luma(Mode::Palette).abs_diff(71) <= 1);
+    }
+
+    #[test]
+    fn forced_filter_edits_palettes_where_they_are_defined() {
+        // A forced caption, then a caption of the same epoch reusing its object and palette
+        let object = Object::from_bitmap(0, 0, 4, 2, &[1, 1, 1, 1, 1, 0, 0, 1]);
+        let entries = vec![entry(0, 16, 128, 128, 0), entry(1, 235, 128, 128, 255)];
+        let original = PgsStream {
+            display_sets: vec![
+                caption(90_000, 0, true, entries, &object),
+                display_set(
+                    180_000,
+                    composition(1, CompositionState::Normal, vec![placed(0, false)]),
+                    Vec::new(),
+                ),
+            ],
+        };
+        let process = |forced| {
+            let mut stream = original.clone();
+            let opts = TonemapOptions {
+                forced,
+                ..Default::default()
+            };
+            tonemap(&mut stream, &opts).unwrap();
+            stream
+        };
+
+        // The second caption has no palette of its own to edit
+        assert_eq!(process(ForcedFilter::NonForced), original);
+
+        // Its segments are untouched, but it shows the palette edited in the first one
+        let stream = process(ForcedFilter::Forced);
+        assert_eq!(stream.display_sets[1], original.display_sets[1]);
+        let palette = stream.display_sets[0].palettes().next().unwrap();
+        assert!(palette.entries[1].y.abs_diff(147) <= 1);
+    }
+
+    /// Luma of the displayed entry 1 of each caption, after tonemapping with `scope`
+    fn caption_lumas(scope: Scope) -> [u8; 2] {
+        // The second epoch is only half as light as the first one
+        let mut stream = small_stream();
+        let palette = stream.display_sets[2].palettes_mut().next().unwrap();
+        palette.entries[1] = entry(1, 126, 128, 128, 255);
+        palette.entries[2] = entry(2, 60, 128, 128, 255);
+
+        let opts = TonemapOptions {
+            ratio: 0.5,
+            fixed: true,
+            scope,
+            ..Default::default()
+        };
+        tonemap(&mut stream, &opts).unwrap();
+
+        [0, 2].map(|i| stream.display_sets[i].palettes().next().unwrap().entries[1].y)
+    }
+
+    #[test]
+    fn epochs_are_normalized_independently() {
+        // Both brightest colors reach 50% white
+        let [first, second] = caption_lumas(Scope::Epoch);
+        assert!(first.abs_diff(126) <= 1, "{first}");
+        assert!(second.abs_diff(126) <= 1, "{second}");
+
+        // The second epoch stays darker with a reference shared by the file
+        let [first, second] = caption_lumas(Scope::File);
+        assert!(first.abs_diff(126) <= 1, "{first}");
+        assert!(second.abs_diff(71) <= 1, "{second}");
+    }
+
+    #[test]
+    fn rules_map_their_source_to_the_stated_level() {
+        let opts = TonemapOptions {
+            rules: vec![
+                "ffff00:30=ffffff@55".parse().unwrap(),
+                "*=keep@70".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+        let old_max = opts.measure([255.0; 3]);
+
+        // Yellow is darker than white in HSL, and still reaches 55% white
+        assert_eq!(opts.tonemap_rgb([255.0, 255.0, 0.0], old_max), [140.0; 3]);
+        // A darker yellow stays darker by the same ratio
+        assert_eq!(opts.tonemap_rgb([204.0, 204.0, 0.0], old_max), [112.0; 3]);
+
+        // The other colors use the subtitle's reference
+        assert_eq!(opts.tonemap_rgb([255.0; 3], old_max), [179.0; 3]);
+        assert_eq!(
+            opts.tonemap_rgb([0.0, 102.0, 204.0], old_max),
+            [0.0, 71.0, 143.0]
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! Temporary working directories, removed when dropped.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::Result;
+
+/// Workspaces that still exist, for cleaning up on interruption
+static ACTIVE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub struct Workspace {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Workspace {
+    /// Creates a uniquely named directory in `root`, or the system temporary directory.
+    /// When `keep` is set, the directory is left behind for debugging.
+    pub fn new(root: Option<&Path>, keep: bool) -> Result<Self> {
+        let root = root.map_or_else(env::temp_dir, Path::to_path_buf);
+        fs::create_dir_all(&root)?;
+
+        loop {
+            let index = COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = root.join(format!("subtitle_tonemap-{}-{index}", process::id()));
+
+            match fs::create_dir(&path) {
+                Ok(()) => {
+                    if !keep {
+                        ACTIVE.lock().unwrap().push(path.clone());
+                    }
+
+                    return Ok(Self { path, keep });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        if !self.keep {
+            fs::remove_dir_all(&self.path).ok();
+            ACTIVE.lock().unwrap().retain(|p| p != &self.path);
+        }
+    }
+}
+
+/// Removes every workspace that wasn't dropped yet.
+/// Meant to be called before exiting from a signal handler.
+pub fn cleanup_all() {
+    let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+
+    for path in active.drain(..) {
+        fs::remove_dir_all(path).ok();
+    }
+}